@@ -1,28 +1,48 @@
-use crate::blockchain;
-use crate::blockchain::Blockchain;
-use crate::miner::Handle as MinerHandle;
+use crate::block::Block;
+use crate::blockchain::{BlockInsertOutcome, Blockchain, ChainEvent, RejectReason, TipStatus};
+use crate::miner::{Handle as MinerHandle, OUR_MAXIMUM_BLOCK_SIZE};
 use crate::network::message::Message;
 use crate::network::server::Handle as NetworkServerHandle;
-use crate::transaction_generator::TransactionGenerator;
+use crate::network::worker::Context as WorkerContext;
+use crate::transaction_generator::{GenerationMode, GenerationRequest, SenderStrategy, TxGenCommand};
+use crate::crypto::address::H160;
+use crate::crypto::hash::{Hashable, H256};
+use crate::crypto::key_pair::get_deterministic_keypair;
+use crate::state::State;
+use crate::transaction::{RawTransaction, SignedTransaction};
+use ring::signature::KeyPair;
 use serde::Serialize;
+use serde_json::json;
 
 use log::info;
 use std::collections::HashMap;
+use std::io::Write;
 use std::sync::mpsc::Sender;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::thread;
+use std::time::Duration;
 use tiny_http::Header;
+use tiny_http::Method;
+use tiny_http::Request;
 use tiny_http::Response;
 use tiny_http::Server as HTTPServer;
 use url::Url;
 
+/// How long to wait for a peer to answer a ping
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many seconds a client should wait before resubmitting a transaction
+/// that was rejected because the mempool is full
+const MEMPOOL_FULL_RETRY_SECS: u64 = 10;
+
 pub struct Server {
     handle: HTTPServer,
     miner: MinerHandle,
     network: NetworkServerHandle,
-    tx_gen: Sender<()>,
+    tx_gen: Sender<TxGenCommand>,
     blockchain: Arc<Mutex<Blockchain>>,
+    worker: WorkerContext,
 }
 
 #[derive(Serialize)]
@@ -33,43 +53,240 @@ struct ApiResponse {
 
 macro_rules! respond_result {
     ( $req:expr, $success:expr, $message:expr ) => {{
+        respond_result!($req, 200, $success, $message)
+    }};
+    ( $req:expr, $status:expr, $success:expr, $message:expr ) => {{
+        respond_result!($req, $status, $success, $message, [])
+    }};
+    // with extra headers, each given as a "Name: value" string
+    ( $req:expr, $status:expr, $success:expr, $message:expr, [ $( $header:expr ),* ] ) => {{
         let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
         let payload = ApiResponse {
             success: $success,
             message: $message.to_string(),
         };
         let resp = Response::from_string(serde_json::to_string_pretty(&payload).unwrap())
+            .with_header(content_type)
+            .with_status_code($status);
+        $( let resp = resp.with_header($header.parse::<Header>().unwrap()); )*
+        $req.respond(resp).unwrap();
+    }};
+}
+
+macro_rules! respond_json {
+    ( $req:expr, $payload:expr ) => {{
+        let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+        let resp = Response::from_string(serde_json::to_string_pretty(&$payload).unwrap())
             .with_header(content_type);
         $req.respond(resp).unwrap();
     }};
 }
 
+#[derive(Serialize)]
+struct LongestChain {
+    success: bool,
+    /// From the tip back to genesis
+    hashes: Vec<String>,
+}
+
+/// A transaction waiting in the mempool
+#[derive(Serialize)]
+struct PendingTransaction {
+    hash: String,
+    from_addr: String,
+    to_addr: String,
+    value: u64,
+    fee: u64,
+    nonce: u32,
+    valid_since: Option<u128>,
+}
+
+#[derive(Serialize)]
+struct ChainTip {
+    hash: String,
+    height: u64,
+    status: TipStatus,
+}
+
+/// Parse the query parameter with the given name
+fn parse_param<T>(params: &HashMap<String, String>, name: &str) -> Result<T, String>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    let value = params.get(name).ok_or_else(|| format!("missing {}", name))?;
+    value
+        .parse::<T>()
+        .map_err(|e| format!("error parsing {}: {}", name, e))
+}
+
+/// The method required by an endpoint that changes the node's mempool or
+/// orphanage, or `None` if the endpoint takes any method
+fn required_method(path: &str) -> Option<Method> {
+    match path {
+        "/mempool/prune" | "/mempool/import" | "/orphans/connect" | "/orphans/prune" | "/transaction/new"
+        | "/tx/submit" => Some(Method::Post),
+        "/mempool/tx" => Some(Method::Delete),
+        _ => None,
+    }
+}
+
+/// Respond with a stream of JSON values, one per line, as they arrive. Ends
+/// when the client disconnects or the values run out
+fn stream_json_lines(req: Request, values: impl Iterator<Item = serde_json::Value>) {
+    let mut writer = req.into_writer();
+    let header = "HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nConnection: close\r\n\r\n";
+    if writer.write_all(header.as_bytes()).and_then(|_| writer.flush()).is_err() {
+        return;
+    }
+    for value in values {
+        if writeln!(writer, "{}", value).and_then(|_| writer.flush()).is_err() {
+            return;
+        }
+    }
+}
+
+/// Respond with plain text sections as they are produced, so that the whole
+/// response never has to be in memory at once. Ends when the client
+/// disconnects or the sections run out
+fn stream_text_sections(req: Request, sections: impl Iterator<Item = String>) {
+    let mut writer = req.into_writer();
+    let header = "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nConnection: close\r\n\r\n";
+    if writer.write_all(header.as_bytes()).and_then(|_| writer.flush()).is_err() {
+        return;
+    }
+    for section in sections {
+        if writeln!(writer, "{}", section).and_then(|_| writer.flush()).is_err() {
+            return;
+        }
+    }
+}
+
+/// The sections of `/status`: a summary of the chain, the mempool, and the
+/// ledger at the tip. Each one only holds the lock long enough to copy what
+/// it needs, and is formatted after letting go
+fn status_sections(blockchain: &Mutex<Blockchain>) -> impl Iterator<Item = String> + '_ {
+    let sections: Vec<fn(&Mutex<Blockchain>) -> String> = vec![status_summary, status_mempool, status_ledger];
+    sections.into_iter().map(move |section| section(blockchain))
+}
+
+fn status_summary(blockchain: &Mutex<Blockchain>) -> String {
+    let blockchain = blockchain.lock().expect("should work");
+    let (num_blocks, tip_hash, (_, height, _)) = (blockchain.num_blocks(), blockchain.tip_hash(), blockchain.tip_data());
+    drop(blockchain);
+    format!("Blockchain status\nNum Blocks: {}\nTip: height {}, hash {}", num_blocks, height, tip_hash)
+}
+
+fn status_mempool(blockchain: &Mutex<Blockchain>) -> String {
+    let mempool = blockchain.lock().expect("should work").export_mempool();
+    format!("Mempool: {:#?}", mempool)
+}
+
+fn status_ledger(blockchain: &Mutex<Blockchain>) -> String {
+    let blockchain = blockchain.lock().expect("should work");
+    // the tip's state is shared, so this doesn't copy the ledger
+    let state = blockchain.compute_state_at(&blockchain.tip_hash()).expect("the tip keeps its state");
+    drop(blockchain);
+    format!("Ledger: {}", state)
+}
+
+/// The alert to send for the event, if it is a reorg of at least `min_depth`
+fn reorg_alert(event: &ChainEvent, min_depth: u64) -> Option<serde_json::Value> {
+    match event {
+        ChainEvent::Reorg { old_tip, new_tip, common_ancestor, depth } if *depth >= min_depth => {
+            Some(json!({
+                "old_tip": old_tip.to_string(),
+                "new_tip": new_tip.to_string(),
+                "common_ancestor": common_ancestor.to_string(),
+                "depth": depth,
+            }))
+        }
+        _ => None,
+    }
+}
+
+/// Describe a mempool event as a JSON line, or `None` for other events
+fn mempool_event(event: &ChainEvent) -> Option<serde_json::Value> {
+    match event {
+        ChainEvent::TransactionAccepted(hash) => Some(json!({
+            "event": "accepted",
+            "hash": hash.to_string(),
+        })),
+        ChainEvent::TransactionRejected(hash, reason) => Some(json!({
+            "event": "rejected",
+            "hash": hash.to_string(),
+            "reason": reason.to_string(),
+        })),
+        _ => None,
+    }
+}
+
+/// Describe a genesis block and the initial ledger, so that a client can
+/// check it starts from the same place
+fn genesis_json(genesis: &Block, initial_state: &State) -> serde_json::Value {
+    let mut ledger: Vec<_> = initial_state.accounts().collect();
+    ledger.sort_by_key(|(addr, _)| **addr);
+    let ledger: Vec<_> = ledger
+        .into_iter()
+        .map(|(addr, acc_info)| {
+            json!({
+                "address": addr.to_string(),
+                "balance": acc_info.balance,
+                "nonce": acc_info.nonce,
+            })
+        })
+        .collect();
+    let header = &genesis.header;
+    json!({
+        "hash": genesis.hash().to_string(),
+        "header": {
+            "parent": header.parent.to_string(),
+            "nonce": header.nonce,
+            "difficulty": header.difficulty.to_string(),
+            "timestamp": header.timestamp.to_string(),
+            "merkle_root": header.merkle_root.to_string(),
+        },
+        "transactions": genesis.content.transactions.len(),
+        "ledger": ledger,
+    })
+}
+
 impl Server {
-    pub fn start(addr: std::net::SocketAddr, miner: &MinerHandle, network: &NetworkServerHandle, tx_gen: Sender<()>, blockchain: Arc<Mutex<Blockchain>>) {
-        let handle = HTTPServer::http(&addr).unwrap();
+    pub fn start(addr: std::net::SocketAddr, miner: &MinerHandle, network: &NetworkServerHandle, tx_gen: Sender<TxGenCommand>, blockchain: Arc<Mutex<Blockchain>>, worker: WorkerContext) {
+        let handle = HTTPServer::http(addr).unwrap();
         let server = Self {
             handle,
             miner: miner.clone(),
             network: network.clone(),
             tx_gen,
-            blockchain: blockchain.clone(),
+            blockchain,
+            worker,
         };
         thread::spawn(move || {
             for req in server.handle.incoming_requests() {
                 let miner = server.miner.clone();
                 let network = server.network.clone();
                 let tx_gen = server.tx_gen.clone();
-                let blockchain = blockchain.clone();
+                let blockchain = server.blockchain.clone();
+                let worker = server.worker.clone();
                 thread::spawn(move || {
+                    let mut req = req;
                     // a valid url requires a base
                     let base_url = Url::parse(&format!("http://{}/", &addr)).unwrap();
                     let url = match base_url.join(req.url()) {
                         Ok(u) => u,
                         Err(e) => {
-                            respond_result!(req, false, format!("error parsing url: {}", e));
+                            respond_result!(req, 400, false, format!("error parsing url: {}", e));
                             return;
                         }
                     };
+                    if let Some(method) = required_method(url.path()) {
+                        if *req.method() != method {
+                            let allow = format!("Allow: {}", method);
+                            respond_result!(req, 405, false, format!("{} requires {}", url.path(), method), [allow]);
+                            return;
+                        }
+                    }
                     match url.path() {
                         "/miner/start" => {
                             let params = url.query_pairs();
@@ -77,7 +294,7 @@ impl Server {
                             let lambda = match params.get("lambda") {
                                 Some(v) => v,
                                 None => {
-                                    respond_result!(req, false, "missing lambda");
+                                    respond_result!(req, 400, false, "missing lambda");
                                     return;
                                 }
                             };
@@ -86,6 +303,7 @@ impl Server {
                                 Err(e) => {
                                     respond_result!(
                                         req,
+                                        400,
                                         false,
                                         format!("error parsing lambda: {}", e)
                                     );
@@ -95,39 +313,599 @@ impl Server {
                             miner.start(lambda);
                             respond_result!(req, true, "ok");
                         }
+                        "/miner/mine" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let count = match params.get("count") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, 400, false, "missing count");
+                                    return;
+                                }
+                            };
+                            let count = match count.parse::<u64>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(
+                                        req,
+                                        400,
+                                        false,
+                                        format!("error parsing count: {}", e)
+                                    );
+                                    return;
+                                }
+                            };
+                            miner.mine_count(count);
+                            respond_result!(req, true, "ok");
+                        }
+                        "/miner/stats" => {
+                            let stats = miner.stats().lock().unwrap().to_json();
+                            respond_json!(req, stats);
+                        }
+                        "/miner/pause" => {
+                            miner.pause();
+                            respond_result!(req, true, "ok");
+                        }
                         "/miner/exit" => {
                             miner.exit();
                             respond_result!(req, true, "ok");
                         }
                         "/tx_gen" => {
-                            // start the transaction generator
-                            let _ = tx_gen.send(());
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let mode = match params.get("mode") {
+                                Some(mode) => match mode.parse::<GenerationMode>() {
+                                    Ok(v) => v,
+                                    Err(e) => {
+                                        respond_result!(
+                                            req,
+                                            400,
+                                            false,
+                                            format!("error parsing mode: {}", e)
+                                        );
+                                        return;
+                                    }
+                                },
+                                None => GenerationMode::Normal,
+                            };
+                            let sender_strategy = match params.get("sender") {
+                                Some(sender) => match sender.parse::<SenderStrategy>() {
+                                    Ok(v) => Some(v),
+                                    Err(e) => {
+                                        respond_result!(
+                                            req,
+                                            400,
+                                            false,
+                                            format!("error parsing sender: {}", e)
+                                        );
+                                        return;
+                                    }
+                                },
+                                None => None,
+                            };
+                            // with an interval, keep generating until stopped
+                            let theta = match params.get("theta") {
+                                Some(theta) => match theta.parse::<u64>() {
+                                    // a zero interval would spin the generator
+                                    Ok(0) => {
+                                        respond_result!(req, 400, false, "theta must be positive");
+                                        return;
+                                    }
+                                    Ok(v) => Some(v),
+                                    Err(e) => {
+                                        respond_result!(
+                                            req,
+                                            400,
+                                            false,
+                                            format!("error parsing theta: {}", e)
+                                        );
+                                        return;
+                                    }
+                                },
+                                None => None,
+                            };
+                            // run the transaction generator
+                            let request = GenerationRequest { mode, sender_strategy };
+                            let command = match theta {
+                                Some(theta) => TxGenCommand::Start { request, theta },
+                                None => TxGenCommand::Once(request),
+                            };
+                            let _ = tx_gen.send(command);
+                            respond_result!(req, true, "ok");
+                        }
+                        "/tx_gen/stop" => {
+                            let _ = tx_gen.send(TxGenCommand::Stop);
                             respond_result!(req, true, "ok");
                         }
                         "/status" => {
+                            stream_text_sections(req, status_sections(&blockchain));
+                        }
+                        "/mempool" => {
+                            let blockchain = blockchain.lock().expect("should work");
+                            let pending: Vec<PendingTransaction> = blockchain
+                                .mempool_transactions()
+                                .map(|(hash, transaction)| {
+                                    let raw = &transaction.raw_transaction;
+                                    PendingTransaction {
+                                        hash: hash.to_string(),
+                                        from_addr: raw.from_addr.to_string(),
+                                        to_addr: raw.to_addr.to_string(),
+                                        value: raw.value,
+                                        fee: raw.fee,
+                                        nonce: raw.nonce,
+                                        valid_since: raw.valid_since,
+                                    }
+                                })
+                                .collect();
+                            drop(blockchain);
+                            respond_json!(req, pending);
+                        }
+                        "/mempool/prune" => {
+                            let mut blockchain = blockchain.lock().expect("should work");
+                            let num_pruned = blockchain.prune_invalid_transactions();
+                            drop(blockchain);
+                            respond_result!(req, true, format!("pruned {} transactions", num_pruned));
+                        }
+                        "/mempool/export" => {
+                            let blockchain = blockchain.lock().expect("should work");
+                            let transactions = blockchain.export_mempool();
+                            drop(blockchain);
+                            respond_json!(req, transactions);
+                        }
+                        "/mempool/import" => {
+                            // the body is a JSON array of transactions, as
+                            // returned by /mempool/export
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let validate = match params.get("validate") {
+                                Some(v) => match v.parse::<bool>() {
+                                    Ok(v) => v,
+                                    Err(e) => {
+                                        respond_result!(req, 400, false, format!("error parsing validate: {}", e));
+                                        return;
+                                    }
+                                },
+                                None => true,
+                            };
+                            let transactions: Vec<SignedTransaction> =
+                                match serde_json::from_reader(req.as_reader()) {
+                                    Ok(v) => v,
+                                    Err(e) => {
+                                        respond_result!(req, 400, false, format!("error parsing transactions: {}", e));
+                                        return;
+                                    }
+                                };
+                            let mut blockchain = blockchain.lock().expect("should work");
+                            let num_imported = blockchain.import_mempool(transactions, validate);
+                            drop(blockchain);
+                            respond_result!(req, true, format!("imported {} transactions", num_imported));
+                        }
+                        "/mempool/tx" => {
+                            // evicts a transaction from the mempool
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let hash = match parse_param::<H256>(&params, "hash") {
+                                Ok(hash) => hash,
+                                Err(e) => {
+                                    respond_result!(req, 400, false, e);
+                                    return;
+                                }
+                            };
+                            let mut blockchain = blockchain.lock().expect("should work");
+                            let removed = blockchain.remove_transaction(&hash);
+                            drop(blockchain);
+                            match removed {
+                                Some(_) => respond_result!(req, true, "ok"),
+                                None => respond_result!(req, 404, false, "transaction not in mempool"),
+                            }
+                        }
+                        "/orphans/connect" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let hash = match parse_param::<H256>(&params, "hash") {
+                                Ok(hash) => hash,
+                                Err(e) => {
+                                    respond_result!(req, 400, false, e);
+                                    return;
+                                }
+                            };
+                            let mut blockchain = blockchain.lock().expect("should work");
+                            let outcome = blockchain.connect_orphan(&hash);
+                            drop(blockchain);
+                            match outcome {
+                                None => respond_result!(req, 404, false, "no orphan with that hash"),
+                                Some(BlockInsertOutcome { orphaned: Some(parent), .. }) => {
+                                    respond_result!(req, 404, false, format!("parent {} is still missing", parent))
+                                }
+                                Some(BlockInsertOutcome { rejected: Some(reason), .. }) => {
+                                    respond_result!(req, 400, false, format!("rejected: {}", reason))
+                                }
+                                Some(BlockInsertOutcome { added, .. }) => {
+                                    let added: Vec<String> = added.iter().map(H256::to_string).collect();
+                                    respond_json!(req, added);
+                                }
+                            }
+                        }
+                        "/orphans/prune" => {
+                            let pruned = blockchain.lock().expect("should work").prune_unreachable_orphans();
+                            respond_json!(req, json!({ "pruned": pruned }));
+                        }
+                        "/account" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let addr = match parse_param::<H160>(&params, "addr") {
+                                Ok(addr) => addr,
+                                Err(e) => {
+                                    respond_result!(req, 400, false, e);
+                                    return;
+                                }
+                            };
                             let blockchain = blockchain.lock().expect("should work");
-                            let response = format!("{}", blockchain);
-                            let content_type = "Content-Type: text/plain".parse::<Header>().unwrap();
-                            let resp = Response::from_string(response).with_header(content_type);
-                            req.respond(resp).unwrap();
+                            let (_, _, state) = blockchain.tip_data();
+                            // unknown accounts have nothing, like in
+                            // `State::check_transaction_validity`
+                            let acc_info = state.get_acc_info(&addr).cloned().unwrap_or_default();
+                            drop(blockchain);
+                            respond_json!(req, acc_info);
+                        }
+                        "/ledger/tip" => {
+                            let blockchain = blockchain.lock().expect("should work");
+                            let (_, _, state) = blockchain.tip_data();
+                            let ledger = state.to_json();
+                            drop(blockchain);
+                            respond_json!(req, ledger);
+                        }
+                        "/genesis" => {
+                            let blockchain = blockchain.lock().expect("should work");
+                            let (genesis, initial_state) = blockchain.genesis_data();
+                            let genesis = genesis_json(genesis, initial_state);
+                            drop(blockchain);
+                            respond_json!(req, genesis);
+                        }
+                        "/blockchain/longest-chain" => {
+                            let blockchain = blockchain.lock().expect("should work");
+                            let hashes = blockchain.all_blocks_in_longest_chain();
+                            drop(blockchain);
+                            let hashes = hashes.iter().map(H256::to_string).collect();
+                            respond_json!(req, LongestChain { success: true, hashes });
+                        }
+                        "/chain/tips" => {
+                            let blockchain = blockchain.lock().expect("should work");
+                            let tips = blockchain.chain_tips();
+                            drop(blockchain);
+                            let tips: Vec<_> = tips
+                                .into_iter()
+                                .map(|(hash, height, status)| ChainTip {
+                                    hash: hash.to_string(),
+                                    height,
+                                    status,
+                                })
+                                .collect();
+                            respond_json!(req, tips);
+                        }
+                        "/chain/duplicates" => {
+                            let blockchain = blockchain.lock().expect("should work");
+                            let duplicates = blockchain.find_content_duplicates();
+                            drop(blockchain);
+                            let duplicates: Vec<Vec<String>> = duplicates
+                                .iter()
+                                .map(|hashes| hashes.iter().map(H256::to_string).collect())
+                                .collect();
+                            respond_json!(req, duplicates);
+                        }
+                        "/chain/path" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let (from, to) = match (
+                                parse_param::<H256>(&params, "from"),
+                                parse_param::<H256>(&params, "to"),
+                            ) {
+                                (Ok(from), Ok(to)) => (from, to),
+                                (Err(e), _) | (_, Err(e)) => {
+                                    respond_result!(req, 400, false, e);
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.lock().expect("should work");
+                            let path = blockchain.path_between(&from, &to);
+                            drop(blockchain);
+                            let Some((disconnect, ancestor, connect)) = path else {
+                                respond_result!(req, 404, false, "unknown block");
+                                return;
+                            };
+                            let to_strings = |hashes: Vec<H256>| -> Vec<String> {
+                                hashes.iter().map(H256::to_string).collect()
+                            };
+                            respond_json!(req, json!({
+                                "disconnect": to_strings(disconnect),
+                                "common_ancestor": ancestor.to_string(),
+                                "connect": to_strings(connect),
+                            }));
+                        }
+                        "/block/info" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let hash = match parse_param::<H256>(&params, "hash") {
+                                Ok(hash) => hash,
+                                Err(e) => {
+                                    respond_result!(req, 400, false, e);
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.lock().expect("should work");
+                            let info = blockchain.block_info(&hash);
+                            let difficulty = blockchain
+                                .look_up_block(&hash)
+                                .map(|(block, _, _, _)| block.header.difficulty.to_difficulty());
+                            drop(blockchain);
+                            let Some(info) = info else {
+                                respond_result!(req, 404, false, "unknown block");
+                                return;
+                            };
+                            let confirmations = match info.confirmations {
+                                Some(confirmations) => json!(confirmations),
+                                None => json!("not on main chain"),
+                            };
+                            respond_json!(req, json!({
+                                "height": info.height,
+                                "cumulative_work": info.cumulative_work.to_string(),
+                                "confirmations": confirmations,
+                                "children": info.num_children,
+                                "difficulty": difficulty,
+                            }));
+                        }
+                        "/metrics" => {
+                            let blockchain = blockchain.lock().expect("should work");
+                            let metrics = blockchain.metrics().to_json();
+                            drop(blockchain);
+                            respond_json!(req, metrics);
+                        }
+                        "/metrics/latency" => {
+                            // how long blocks from peers took to arrive
+                            let latency = worker.block_latency().lock().unwrap().to_json();
+                            respond_json!(req, latency);
+                        }
+                        "/difficulty" => {
+                            let blockchain = blockchain.lock().expect("should work");
+                            let (tip, height, _) = blockchain.tip_data();
+                            let target = tip.header.difficulty;
+                            drop(blockchain);
+                            respond_json!(req, json!({
+                                "height": height,
+                                "target": target.to_string(),
+                                "difficulty": target.to_difficulty(),
+                            }));
+                        }
+                        "/alerts/reorg" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let min_depth = match params.get("min_depth") {
+                                Some(v) => match v.parse::<u64>() {
+                                    Ok(v) => v,
+                                    Err(e) => {
+                                        respond_result!(req, 400, false, format!("error parsing min_depth: {}", e));
+                                        return;
+                                    }
+                                },
+                                None => 1,
+                            };
+                            let events = blockchain.lock().expect("should work").subscribe();
+                            let alerts = events.into_iter().filter_map(|event| reorg_alert(&event, min_depth));
+                            stream_json_lines(req, alerts);
+                        }
+                        "/mempool/subscribe" => {
+                            // every transaction offered to the mempool from
+                            // now on, and whether it got in
+                            let events = blockchain.lock().expect("should work").subscribe();
+                            stream_json_lines(req, events.into_iter().filter_map(|event| mempool_event(&event)));
+                        }
+                        "/transaction/exists" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let hash = match parse_param::<H256>(&params, "hash") {
+                                Ok(hash) => hash,
+                                Err(e) => {
+                                    respond_result!(req, 400, false, e);
+                                    return;
+                                }
+                            };
+                            let location = blockchain.lock().expect("should work").transaction_location(&hash);
+                            respond_json!(req, json!({
+                                "known": location.is_some(),
+                                "location": location.map_or(json!("none"), |location| json!(location)),
+                            }));
+                        }
+                        "/transaction/eta" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let hash = match parse_param::<H256>(&params, "hash") {
+                                Ok(hash) => hash,
+                                Err(e) => {
+                                    respond_result!(req, 400, false, e);
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.lock().expect("should work");
+                            let estimate = blockchain.estimate_confirmation(&hash, OUR_MAXIMUM_BLOCK_SIZE);
+                            drop(blockchain);
+                            let Some(estimate) = estimate else {
+                                respond_json!(req, json!({ "blocks": "unknown", "seconds": "unknown" }));
+                                return;
+                            };
+                            let seconds = match estimate.time {
+                                Some(time) => json!(time.as_secs_f64()),
+                                None => json!("unknown"),
+                            };
+                            respond_json!(req, json!({ "blocks": estimate.blocks, "seconds": seconds }));
+                        }
+                        "/fee/estimate" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let target_blocks = match parse_param::<u64>(&params, "blocks") {
+                                Ok(target_blocks) => target_blocks,
+                                Err(e) => {
+                                    respond_result!(req, 400, false, e);
+                                    return;
+                                }
+                            };
+                            let fee = blockchain.lock().expect("should work").estimate_fee(target_blocks);
+                            respond_json!(req, json!({ "blocks": target_blocks, "fee": fee }));
+                        }
+                        "/transaction/new" => {
+                            // send coins between ICO accounts, identified by
+                            // their index, optionally with a fee, a memo, and
+                            // the timestamp from which it is valid
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let (from, to, value) = match (
+                                parse_param::<u8>(&params, "from"),
+                                parse_param::<u8>(&params, "to"),
+                                parse_param::<u64>(&params, "value"),
+                            ) {
+                                (Ok(from), Ok(to), Ok(value)) => (from, to, value),
+                                (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => {
+                                    respond_result!(req, 400, false, e);
+                                    return;
+                                }
+                            };
+                            let fee = match params.get("fee") {
+                                Some(v) => match v.parse::<u64>() {
+                                    Ok(v) => v,
+                                    Err(e) => {
+                                        respond_result!(req, 400, false, format!("error parsing fee: {}", e));
+                                        return;
+                                    }
+                                },
+                                None => 0,
+                            };
+                            let memo = params.get("memo").map_or_else(Vec::new, |memo| memo.as_bytes().to_vec());
+                            let valid_since = match params.get("valid_since") {
+                                Some(v) => match v.parse::<u128>() {
+                                    Ok(v) => Some(v),
+                                    Err(e) => {
+                                        respond_result!(req, 400, false, format!("error parsing valid_since: {}", e));
+                                        return;
+                                    }
+                                },
+                                None => None,
+                            };
+                            let sender_key_pair = get_deterministic_keypair(from);
+                            let from_addr = H160::from_pubkey(sender_key_pair.public_key().as_ref());
+                            let to_addr = H160::from_pubkey(get_deterministic_keypair(to).public_key().as_ref());
+                            let mut blockchain = blockchain.lock().expect("should work");
+                            let (_, _, state) = blockchain.tip_data();
+                            let nonce = state.get_acc_info(&from_addr).map_or(0, |acc_info| acc_info.nonce);
+                            let transaction = SignedTransaction::from_raw(
+                                RawTransaction { from_addr, to_addr, value, fee, nonce, memo, valid_since },
+                                &sender_key_pair,
+                            );
+                            let hash = transaction.hash();
+                            let result = blockchain.insert_transaction_with_validation(transaction);
+                            drop(blockchain);
+                            match result {
+                                Ok(()) => {
+                                    network.mark_originated(&[hash]);
+                                    network.broadcast(Message::NewTransactionHashes(vec![hash]));
+                                    respond_result!(req, true, hash);
+                                }
+                                Err(RejectReason::MempoolFull) => {
+                                    // tell the client when to try again
+                                    let retry_after = format!("Retry-After: {}", MEMPOOL_FULL_RETRY_SECS);
+                                    respond_result!(req, 503, false, RejectReason::MempoolFull, [retry_after]);
+                                }
+                                Err(reason) => {
+                                    respond_result!(req, 400, false, format!("rejected: {}", reason));
+                                }
+                            }
+                        }
+                        "/tx/submit" => {
+                            // accepts a transaction signed by anyone, e.g. an
+                            // external wallet
+                            let mut body = Vec::new();
+                            if let Err(e) = req.as_reader().read_to_end(&mut body) {
+                                respond_result!(req, 400, false, format!("error reading body: {}", e));
+                                return;
+                            }
+                            let transaction: SignedTransaction = match serde_json::from_slice(&body) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, 400, false, format!("error parsing transaction: {}", e));
+                                    return;
+                                }
+                            };
+                            let hash = transaction.hash();
+                            let mut blockchain = blockchain.lock().expect("should work");
+                            let result = blockchain.insert_transaction_with_validation(transaction);
+                            drop(blockchain);
+                            match result {
+                                Ok(()) => {
+                                    network.mark_originated(&[hash]);
+                                    network.broadcast(Message::NewTransactionHashes(vec![hash]));
+                                    respond_result!(req, true, hash);
+                                }
+                                Err(RejectReason::MempoolFull) => {
+                                    // tell the client when to try again
+                                    let retry_after = format!("Retry-After: {}", MEMPOOL_FULL_RETRY_SECS);
+                                    respond_result!(req, 503, false, RejectReason::MempoolFull, [retry_after]);
+                                }
+                                Err(reason) => {
+                                    respond_result!(req, 400, false, format!("rejected: {}", reason));
+                                }
+                            }
                         }
                         "/network/ping" => {
-                            network.broadcast(Message::Ping(String::from("Test ping")));
-                            respond_result!(req, true, "ok");
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let Some(peer_id) = params.get("peer") else {
+                                network.broadcast(Message::Ping(String::from("Test ping")));
+                                respond_result!(req, true, "ok");
+                                return;
+                            };
+                            let peer_id = match peer_id.parse::<usize>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, 400, false, format!("error parsing peer: {}", e));
+                                    return;
+                                }
+                            };
+                            if !network.peers().iter().any(|&(id, _)| id == peer_id) {
+                                respond_result!(req, 404, false, format!("no peer with id {}", peer_id));
+                                return;
+                            }
+                            match network.ping(peer_id, PING_TIMEOUT) {
+                                Ok(rtt) => respond_json!(req, json!({
+                                    "success": true,
+                                    "peer": peer_id,
+                                    "rtt_ms": rtt.as_secs_f64() * 1000.0,
+                                })),
+                                // the peer didn't answer in time
+                                Err(e) => respond_result!(req, 504, false, e),
+                            }
+                        }
+                        "/network/pause" => {
+                            worker.pause();
+                            respond_result!(req, true, "paused");
+                        }
+                        "/network/resume" => {
+                            let num_processed = worker.resume();
+                            respond_result!(req, true, format!("processed {} queued messages", num_processed));
+                        }
+                        "/network/banned" => {
+                            let banned: Vec<String> =
+                                worker.banned_peers().iter().map(ToString::to_string).collect();
+                            respond_json!(req, banned);
+                        }
+                        "/network/peers/stats" => {
+                            let peer_stats = worker.peer_stats();
+                            let peer_stats = peer_stats.lock().unwrap();
+                            let peer_stats: HashMap<String, _> = peer_stats
+                                .iter()
+                                .map(|(addr, stats)| (addr.to_string(), stats.clone()))
+                                .collect();
+                            respond_json!(req, peer_stats);
                         }
                         _ => {
-                            let content_type =
-                                "Content-Type: application/json".parse::<Header>().unwrap();
-                            let payload = ApiResponse {
-                                success: false,
-                                message: "endpoint not found".to_string(),
-                            };
-                            let resp = Response::from_string(
-                                serde_json::to_string_pretty(&payload).unwrap(),
-                            )
-                            .with_header(content_type)
-                            .with_status_code(404);
-                            req.respond(resp).unwrap();
+                            respond_result!(req, 404, false, "endpoint not found");
                         }
                     }
                 });
@@ -136,3 +914,121 @@ impl Server {
         info!("API server listening at {}", &addr);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block;
+
+    #[test]
+    fn reorg_alert_threshold() {
+        let mut blockchain = Blockchain::new();
+        let events = blockchain.subscribe();
+        let genesis_hash = blockchain.tip_hash();
+        let main_1 = generate_random_block(&genesis_hash);
+        blockchain.insert_block(main_1.clone());
+        let main_2 = generate_random_block(&main_1.hash());
+        blockchain.insert_block(main_2.clone());
+
+        // a reorg of depth 2 fires an alert for a threshold of 2
+        let fork_1 = generate_random_block(&genesis_hash);
+        let fork_2 = generate_random_block(&fork_1.hash());
+        let fork_3 = generate_random_block(&fork_2.hash());
+        for block in [&fork_1, &fork_2, &fork_3] {
+            blockchain.insert_block(block.clone());
+        }
+        let event = events.try_recv().unwrap();
+        let alert = reorg_alert(&event, 2).unwrap();
+        assert_eq!(alert["depth"], 2);
+        assert_eq!(alert["old_tip"], main_2.hash().to_string());
+        assert_eq!(alert["new_tip"], fork_3.hash().to_string());
+        assert_eq!(alert["common_ancestor"], genesis_hash.to_string());
+
+        // a reorg of depth 1 stays silent
+        let sibling_3 = generate_random_block(&fork_2.hash());
+        blockchain.insert_block(sibling_3.clone());
+        blockchain.insert_block(generate_random_block(&sibling_3.hash()));
+        let event = events.try_recv().unwrap();
+        assert_eq!(reorg_alert(&event, 2), None);
+        assert!(reorg_alert(&event, 1).is_some());
+    }
+
+    #[test]
+    fn mempool_events() {
+        let mut blockchain = Blockchain::new();
+        let events = blockchain.subscribe();
+        let sender = get_deterministic_keypair(0);
+        let from_addr = H160::from_pubkey(sender.public_key().as_ref());
+        let transaction = |value, nonce| {
+            SignedTransaction::from_raw(
+                RawTransaction { from_addr, to_addr: from_addr, value, fee: 0, nonce, memo: vec![], valid_since: None },
+                &sender,
+            )
+        };
+        let valid = transaction(1, 0);
+        let overdrawn = transaction(u64::MAX, 1);
+        blockchain.insert_transaction_with_validation(valid.clone()).unwrap();
+        assert!(blockchain.insert_transaction_with_validation(overdrawn.clone()).is_err());
+
+        let accepted = mempool_event(&events.try_recv().unwrap()).unwrap();
+        assert_eq!(accepted, json!({ "event": "accepted", "hash": valid.hash().to_string() }));
+        let rejected = mempool_event(&events.try_recv().unwrap()).unwrap();
+        assert_eq!(rejected["event"], "rejected");
+        assert_eq!(rejected["hash"], overdrawn.hash().to_string());
+        assert_eq!(rejected["reason"], RejectReason::InvalidState.to_string());
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn genesis() {
+        let blockchain = Blockchain::new();
+        let (genesis, initial_state) = blockchain.genesis_data();
+        let genesis = genesis_json(genesis, initial_state);
+        assert_eq!(genesis["hash"], Block::genesis().hash().to_string());
+        assert_eq!(genesis["transactions"], 0);
+
+        let ico = State::ico();
+        let ledger = genesis["ledger"].as_array().unwrap();
+        assert_eq!(ledger.len(), 10);
+        for i in 0..10 {
+            let addr = H160::from_pubkey(get_deterministic_keypair(i).public_key().as_ref());
+            let entry = ledger.iter().find(|entry| entry["address"] == addr.to_string()).unwrap();
+            assert_eq!(entry["balance"], ico.get_acc_info(&addr).unwrap().balance);
+            assert_eq!(entry["nonce"], 0);
+        }
+    }
+
+    #[test]
+    fn status_in_sections() {
+        let mut blockchain = Blockchain::new();
+        let sender = get_deterministic_keypair(0);
+        let from_addr = H160::from_pubkey(sender.public_key().as_ref());
+        let transaction = SignedTransaction::from_raw(
+            RawTransaction { from_addr, to_addr: from_addr, value: 1, fee: 0, nonce: 0, memo: vec![], valid_since: None },
+            &sender,
+        );
+        blockchain.insert_transaction_with_validation(transaction).unwrap();
+        let blockchain = Mutex::new(blockchain);
+
+        let mut sections = vec![];
+        for section in status_sections(&blockchain) {
+            // nothing holds the lock between sections, e.g. while one is
+            // being sent
+            assert!(blockchain.try_lock().is_ok());
+            sections.push(section);
+        }
+        assert_eq!(sections.len(), 3);
+        assert!(sections[0].starts_with("Blockchain status\nNum Blocks: 1\nTip: height 0"));
+        assert!(sections[1].starts_with("Mempool: ") && sections[1].contains(&format!("{:?}", from_addr)));
+        assert!(sections[2].starts_with("Ledger: ") && sections[2].contains(&from_addr.to_string()));
+    }
+
+    #[test]
+    fn mutating_endpoints_require_a_method() {
+        assert_eq!(required_method("/tx/submit"), Some(Method::Post));
+        assert_eq!(required_method("/orphans/connect"), Some(Method::Post));
+        assert_eq!(required_method("/mempool/tx"), Some(Method::Delete));
+        assert_eq!(required_method("/mempool"), None);
+        assert_eq!(required_method("/miner/start"), None);
+    }
+}
@@ -1,5 +1,8 @@
 use crate::{
-    crypto::hash::{Hashable, H256},
+    crypto::{
+        hash::{Hashable, H256},
+        merkle::MerkleTree,
+    },
     transaction::SignedTransaction as Transaction,
 };
 use serde::{Deserialize, Serialize};
@@ -25,11 +28,10 @@ pub struct Block {
     pub content: Content,
 }
 
-// Returns the default difficulty, which is a big-endian 32-byte integer.
-// For a valid block, block.hash() <= difficulty
-fn default_difficulty() -> H256 {
-    H256::with_leading_zeros(2)
-}
+/// The difficulty of the default genesis block, which is a big-endian 32-byte
+/// integer. For a valid block, block.hash() <= difficulty, so with a leading
+/// zero byte it takes about 256 tries to mine a block at this difficulty
+pub const DEFAULT_DIFFICULTY: H256 = H256::with_leading_zeros(8);
 
 impl Block {
     // deterministically construct the genesis block
@@ -38,7 +40,7 @@ impl Block {
             header: Header {
                 parent: Default::default(),
                 nonce: 0, // TODO is this supposed to be correct?
-                difficulty: default_difficulty(),
+                difficulty: DEFAULT_DIFFICULTY,
                 timestamp: 0,
                 merkle_root: Default::default(),
             },
@@ -49,6 +51,14 @@ impl Block {
     }
 }
 
+impl Content {
+    /// The merkle root of the transactions. Content without transactions,
+    /// like genesis's, has the default root
+    pub fn merkle_root(&self) -> H256 {
+        MerkleTree::new(&self.transactions).root()
+    }
+}
+
 impl Hashable for Header {
     fn hash(&self) -> H256 {
         let bytes = bincode::serialize(&self).expect("shouldn't fail");
@@ -62,12 +72,10 @@ impl Hashable for Block {
     }
 }
 
-#[cfg(any(test, test_utilities))]
+#[cfg(any(test, feature = "test-utilities"))]
 pub mod test {
     use super::*;
-    use crate::{
-        crypto::{hash::H256, merkle::MerkleTree},
-    };
+    use crate::blockchain::Blockchain;
 
     pub fn generate_random_block(parent: &H256) -> Block {
         let transactions: Vec<Transaction> = vec![Transaction::generate_random()];
@@ -76,11 +84,99 @@ pub mod test {
             header: Header {
                 parent: *parent,
                 nonce: rand::random(),
-                difficulty: default_difficulty(),
+                difficulty: DEFAULT_DIFFICULTY,
                 timestamp: rand::random(),
                 merkle_root: root,
             },
             content: Content { transactions },
         }
     }
+
+    /// Like `generate_random_block`, but grinding the nonce until the block
+    /// meets its difficulty target
+    pub fn generate_random_mined_block(parent: &H256) -> Block {
+        let mut block = generate_random_block(parent);
+        while block.hash() > block.header.difficulty {
+            block.header.nonce = block.header.nonce.wrapping_add(1);
+        }
+        block
+    }
+
+    /// Build a valid block on the tip of the blockchain containing the given
+    /// transactions, grinding the nonce until it satisfies the proof of work
+    pub fn mine_valid_block(blockchain: &Blockchain, transactions: Vec<Transaction>) -> Block {
+        let (tip, _, _) = blockchain.tip_data();
+        mine_valid_block_on(tip, transactions)
+    }
+
+    /// Build a block on the specified parent containing the given
+    /// transactions, grinding the nonce until it satisfies the proof of work.
+    /// Unless the transactions start with a coinbase, one that creates nothing
+    /// is put first
+    pub fn mine_valid_block_on(parent: &Block, mut transactions: Vec<Transaction>) -> Block {
+        if !transactions.first().is_some_and(Transaction::is_coinbase) {
+            // number it after the parent's, if the parent has one
+            let height = match parent.content.transactions.first() {
+                Some(coinbase) if coinbase.is_coinbase() => coinbase.raw_transaction.nonce as u64 + 1,
+                _ => 1,
+            };
+            transactions.insert(0, Transaction::coinbase(Default::default(), 0, height));
+        }
+        let content = Content { transactions };
+        let mut block = Block {
+            header: Header {
+                parent: parent.hash(),
+                nonce: 0,
+                difficulty: parent.header.difficulty,
+                timestamp: parent.header.timestamp + 1,
+                merkle_root: content.merkle_root(),
+            },
+            content,
+        };
+        while block.hash() > block.header.difficulty {
+            block.header.nonce += 1;
+        }
+        block
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn mine_at_default_difficulty() {
+            // the odds of needing more nonces than this are (255/256)^10000
+            const NONCE_BUDGET: u32 = 10000;
+            let mut block = generate_random_block(&Block::genesis().hash());
+            assert_eq!(block.header.difficulty, DEFAULT_DIFFICULTY);
+            block.header.nonce = 0;
+            while block.hash() > block.header.difficulty {
+                block.header.nonce += 1;
+                assert!(block.header.nonce < NONCE_BUDGET, "no nonce found within the budget");
+            }
+        }
+
+        #[test]
+        fn insert_mined_block() {
+            use crate::crypto::{address::H160, key_pair::get_deterministic_keypair};
+            use crate::transaction::RawTransaction;
+            use ring::signature::KeyPair;
+
+            let mut blockchain = Blockchain::new();
+            let sender = get_deterministic_keypair(0);
+            let from_addr = H160::from_pubkey(sender.public_key().as_ref());
+            let to_addr = H160::from_pubkey(get_deterministic_keypair(1).public_key().as_ref());
+            let transaction = Transaction::from_raw(
+                RawTransaction { from_addr, to_addr, value: 1, fee: 0, nonce: 0, memo: vec![], valid_since: None },
+                &sender,
+            );
+            let block = mine_valid_block(&blockchain, vec![transaction]);
+            assert_eq!(blockchain.insert_block_with_validation(block.clone()).added, vec![block.hash()]);
+            assert_eq!(blockchain.tip_hash(), block.hash());
+
+            // an empty block is fine too
+            let block = mine_valid_block(&blockchain, vec![]);
+            assert_eq!(blockchain.insert_block_with_validation(block.clone()).added, vec![block.hash()]);
+        }
+    }
 }
@@ -1,125 +1,547 @@
-use log::{info, warn};
+use log::info;
 
-use crate::block::Block;
+use crate::block::{Block, Header};
+use crate::crypto::address::H160;
 use crate::crypto::hash::{Hashable, H256};
-use crate::state::State;
-use crate::transaction::SignedTransaction;
-use std::collections::HashMap;
+use crate::crypto::merkle::MerkleTree;
+use crate::events::EventBus;
+use crate::mempool::{mining_priority, Mempool};
+use crate::metrics::Metrics;
+use crate::state::{State, ValidationRules};
+use crate::transaction::{RawTransaction, SignedTransaction, BLOCK_REWARD};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::mpsc::Receiver;
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How far behind the active tip a fork's tip may be before it is considered
+/// stale rather than a competing fork
+const STALE_FORK_DEPTH: u64 = 6;
+
+/// How many of a block's most recent ancestors are used to compute the median
+/// time past, which the block's timestamp must exceed. Near the start of the
+/// chain, where there are fewer ancestors, all of them (including genesis) are
+/// used.
+const MEDIAN_TIME_SPAN: usize = 11;
+
+/// The most transactions a block may have, including its coinbase. Bounds the
+/// work a peer can make us do with one block
+pub const MAX_BLOCK_TRANSACTIONS: usize = 1000;
+
+/// How far ahead of our clock, in milliseconds, a block's timestamp may be.
+/// Later blocks are rejected for now, but not remembered as invalid, since
+/// they become acceptable once our clock catches up
+const MAX_FUTURE_BLOCK_TIME: u128 = 2 * 60 * 60 * 1000;
+
+/// How often the difficulty is retargeted: blocks whose height is a multiple
+/// of this get a new difficulty target, based on how long the blocks before
+/// them took
+const RETARGET_INTERVAL: u64 = 10;
+
+/// The most the difficulty may change by in one retarget, as a factor
+const MAX_RETARGET_FACTOR: u64 = 4;
+
+/// How many of the most recent blocks a block locator lists one by one, before
+/// it starts skipping
+const LOCATOR_DENSE_LEN: usize = 10;
+
+/// How many blocks found to be invalid to remember, so that orphans descending
+/// from them can be pruned
+const INVALID_BLOCKS_CAPACITY: usize = 10000;
+
+/// How long an orphan may wait for its parent before it is pruned by
+/// `Blockchain::prune_unreachable_orphans`
+const DEFAULT_ORPHAN_MAX_AGE: Duration = Duration::from_secs(600);
+
+/// How many recently confirmed transactions to remember, so that they aren't
+/// accepted into the mempool and relayed again
+const RECENTLY_CONFIRMED_CAPACITY: usize = 10000;
+
+/// The status of a leaf block in the block tree
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TipStatus {
+    /// The tip of the longest chain
+    Active,
+    /// The tip of a fork that is close enough to the active tip to compete
+    /// with it
+    ValidFork,
+    /// The tip of a fork that has fallen far behind the active tip
+    Stale,
+}
+
+/// Where a known transaction is
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TransactionLocation {
+    /// Waiting in the mempool
+    Mempool,
+    /// In a block, on any fork
+    Block,
+}
+
+/// Diagnostic information about a block in the chain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockInfo {
+    pub height: u64,
+    /// The total work of the chain ending at this block
+    pub cumulative_work: u128,
+    /// How many blocks the tip is above this block, or `None` if the block is
+    /// not on the longest chain
+    pub confirmations: Option<u64>,
+    pub num_children: usize,
+}
+
+/// Everything needed to check, without the rest of the chain, that a
+/// transaction was included in a block at a certain height. The proof can be
+/// checked with `merkle::verify`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct InclusionCommitment {
+    pub tx_hash: H256,
+    pub block_hash: H256,
+    pub height: u64,
+    pub merkle_root: H256,
+    /// The merkle proof of the transaction, bottom-up
+    pub merkle_proof: Vec<H256>,
+    /// The position of the transaction in the block
+    pub index: usize,
+    /// The number of transactions in the block
+    pub num_leaves: usize,
+}
+
+/// Proof that an account was created: the transaction that funded it, with a
+/// commitment to its inclusion in the longest chain. Anyone holding the proof
+/// can check it against a block header without the rest of the chain
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AccountProof {
+    pub addr: H160,
+    /// How much the funding transaction gave the account
+    pub value: u64,
+    pub funding: InclusionCommitment,
+}
+
+/// Something that happened to the blockchain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChainEvent {
+    /// The tip moved to a block that doesn't descend from the old tip
+    Reorg {
+        old_tip: H256,
+        new_tip: H256,
+        common_ancestor: H256,
+        /// How many blocks of the old longest chain were abandoned
+        depth: u64,
+    },
+    /// A transaction was added to the mempool
+    TransactionAccepted(H256),
+    /// A transaction was offered to the mempool but not added
+    TransactionRejected(H256, RejectReason),
+}
+
+/// How many of the most recent blocks are used to estimate the time between
+/// blocks
+const BLOCK_INTERVAL_SPAN: u64 = 10;
+
+/// How many of the most recent blocks are used to estimate fees
+const FEE_ESTIMATE_SPAN: usize = 24;
+
+/// An estimate of when a transaction in the mempool will be mined
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfirmationEstimate {
+    /// How many blocks will probably be mined before the transaction is,
+    /// counting the one that includes it
+    pub blocks: u64,
+    /// How long that will take, if the recent block interval is known and
+    /// the time fits in a `Duration`
+    pub time: Option<Duration>,
+}
+
+/// The default maximum number of blocks in the orphanage
+const DEFAULT_ORPHANAGE_CAPACITY: usize = 5000;
+
+/// Why a transaction or block was not accepted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    /// The transaction is already in the mempool, or the block is already in
+    /// the chain
+    AlreadyKnown,
+    /// The transaction was recently included in a block
+    AlreadyConfirmed,
+    /// The transaction is not signed by the owner of the sending account
+    InvalidSignature,
+    /// The sending account's nonce or balance doesn't allow the transaction
+    InvalidState,
+    /// The transaction spends the same nonce as a transaction in the mempool
+    DoubleSpend,
+    /// The transaction may be valid, but the mempool has no room for it. The
+    /// sender should try again later
+    MempoolFull,
+    /// The transaction transfers more than a single transaction may
+    ValueTooLarge,
+    /// The transaction transfers nothing, which isn't allowed
+    ZeroValue,
+    /// The transaction's memo is longer than `transaction::MAX_MEMO_LEN`
+    MemoTooLong,
+    /// The block's parent isn't known, or isn't the previous block of the
+    /// sequence being validated
+    UnknownParent,
+    /// The block's difficulty target isn't the one required of it
+    WrongDifficulty,
+    /// The block's hash doesn't meet its difficulty target
+    InsufficientWork,
+    /// The block's timestamp isn't after the median time past of its parent
+    TimestampTooEarly,
+    /// The block's timestamp is more than `MAX_FUTURE_BLOCK_TIME` ahead of
+    /// our clock
+    TimestampTooLate,
+    /// The block's merkle root doesn't match its transactions
+    BadMerkleRoot,
+    /// Some sender's transactions in the block don't use contiguous nonces
+    NonContiguousNonces,
+    /// The block has more than `MAX_BLOCK_TRANSACTIONS` transactions
+    TooManyTransactions,
+    /// The block's first transaction isn't a coinbase
+    MissingCoinbase,
+    /// The block has a coinbase other than as its first transaction
+    MisplacedCoinbase,
+    /// The block's coinbase creates more than `transaction::BLOCK_REWARD` and
+    /// the block's fees
+    ExcessiveCoinbase,
+}
+
+impl std::fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let description = match self {
+            RejectReason::AlreadyKnown => "transaction is already in the mempool",
+            RejectReason::AlreadyConfirmed => "transaction is already confirmed",
+            RejectReason::InvalidSignature => "invalid signature",
+            RejectReason::InvalidState => "invalid nonce or insufficient balance",
+            RejectReason::DoubleSpend => "conflicts with a transaction in the mempool",
+            RejectReason::MempoolFull => "mempool is full",
+            RejectReason::ValueTooLarge => "value exceeds the maximum per transaction",
+            RejectReason::ZeroValue => "zero-value transactions aren't allowed",
+            RejectReason::MemoTooLong => "memo too long",
+            RejectReason::UnknownParent => "unknown parent",
+            RejectReason::WrongDifficulty => "wrong difficulty target",
+            RejectReason::InsufficientWork => "hash doesn't meet the difficulty target",
+            RejectReason::TimestampTooEarly => "timestamp too early",
+            RejectReason::TimestampTooLate => "timestamp too far in the future",
+            RejectReason::BadMerkleRoot => "merkle root doesn't match the transactions",
+            RejectReason::NonContiguousNonces => "non-contiguous nonces",
+            RejectReason::TooManyTransactions => "too many transactions",
+            RejectReason::MissingCoinbase => "missing coinbase",
+            RejectReason::MisplacedCoinbase => "misplaced coinbase",
+            RejectReason::ExcessiveCoinbase => "coinbase exceeds the block reward and fees",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+/// What happened to a block given to `insert_block_with_validation`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockInsertOutcome {
+    /// The blocks added to the chain: the block itself, then any orphans it
+    /// connected
+    pub added: Vec<H256>,
+    /// If the block's parent is unknown, the parent's hash. The block waits
+    /// in the orphanage until the parent arrives
+    pub orphaned: Option<H256>,
+    /// Why the block was rejected, if it was
+    pub rejected: Option<RejectReason>,
+}
+
+impl BlockInsertOutcome {
+    fn rejected(reason: RejectReason) -> Self {
+        BlockInsertOutcome { rejected: Some(reason), ..Default::default() }
+    }
+}
+
+/// Configures the genesis block of a blockchain
+#[derive(Debug, Clone, Default)]
+pub struct GenesisConfig {
+    /// The timestamp of the genesis block, in milliseconds since the Unix
+    /// epoch. It is the baseline for validating the timestamps of the first
+    /// blocks
+    pub timestamp: u128,
+    /// The rules all transactions must follow, in the mempool and in blocks
+    pub rules: ValidationRules,
+    /// The time between blocks, in milliseconds, that retargeting aims for,
+    /// or `None` to keep the genesis difficulty forever
+    pub target_block_interval: Option<u64>,
+    /// The difficulty of the genesis block, which the first blocks are mined
+    /// at, or `None` for the default one of `Block::genesis`
+    pub difficulty: Option<H256>,
+    /// The balance of each account before any block, or `None` for the
+    /// default distribution of `State::ico`
+    pub initial_balances: Option<Vec<(H160, u64)>>,
+}
+
+/// What the chain stores for a block: the block, its height, the total work
+/// of the chain ending at it, and the state after it, unless that state was
+/// dropped (see `Blockchain::set_state_retention`)
+pub type BlockEntry = (Block, u64, u128, Option<Arc<State>>);
 
 pub struct Blockchain {
     /// Stores all the blocks in the chain. Maps the block's hash to its data.
-    hash_to_block: HashMap<H256, (Block, u64, Arc<State>)>,
+    hash_to_block: HashMap<H256, BlockEntry>,
+    /// How many blocks at the end of the longest chain keep their state, or
+    /// `None` to keep the state of every block. Genesis always keeps its state
+    state_retention: Option<u64>,
     /// Stores the hash of the block at the tip.
     tip: H256,
+    /// Maps a block's hash to the number of children it has in the chain.
+    /// Blocks without an entry have no children
+    num_children: HashMap<H256, usize>,
     /// Stores all the blocks whose parents we don't know about yet Maps the
     /// block's parent's hash to all the orphans depending on that parent
     orphanage: HashMap<H256, Vec<Block>>,
-    /// Store all the received valid transactions which have not been included
-    /// in the blockchain yet. Maps a transaction's hash to its data
-    mempool: HashMap<H256, SignedTransaction>,
+    /// The maximum number of blocks in the orphanage. The oldest orphans are
+    /// evicted to make room
+    orphanage_capacity: usize,
+    /// The (parent, hash, arrival time) of each orphan, oldest first. May
+    /// still include orphans that were since connected
+    orphan_order: VecDeque<(H256, H256, Instant)>,
+    /// How long an orphan may wait for its parent before it is considered
+    /// unreachable
+    orphan_max_age: Duration,
+    /// The hashes of blocks recently found to be invalid. Bounded by
+    /// `INVALID_BLOCKS_CAPACITY`
+    invalid_blocks: HashSet<H256>,
+    /// The hashes in `invalid_blocks`, oldest first
+    invalid_blocks_order: VecDeque<H256>,
+    /// Whether orphans are connected by `process_orphans`, a few at a time,
+    /// instead of all at once when their parent arrives
+    lazy_orphans: bool,
+    /// Blocks in the chain whose children are still in the orphanage, waiting
+    /// for `process_orphans`. Only used with `lazy_orphans`
+    ready_orphan_parents: VecDeque<H256>,
+    mempool: Mempool,
+    /// The hashes of transactions recently included in blocks. Bounded by
+    /// `RECENTLY_CONFIRMED_CAPACITY`
+    recently_confirmed: HashSet<H256>,
+    /// The hashes in `recently_confirmed`, oldest first
+    recently_confirmed_order: VecDeque<H256>,
     /// Whether the mempool might have some invalid transactions due to state
     /// changes
     dirty_mempool: bool,
+    /// Maps each transaction's hash to the hashes of all the blocks (on any
+    /// fork) that contain it
+    tx_index: HashMap<H256, Vec<H256>>,
+    events: EventBus<ChainEvent>,
+    /// How many blocks and transactions were accepted in recent minutes
+    metrics: Metrics,
+    /// Test-only: accept blocks without checking their proof of work
+    instant_finality: bool,
+    /// See `GenesisConfig::target_block_interval`
+    target_block_interval: Option<u64>,
 }
 
 impl Blockchain {
-    /// Create a new blockchain, only containing the genesis block
+    /// Create a new blockchain, only containing the default genesis block
     pub fn new() -> Self {
-        let genesis = Block::genesis();
+        Self::with_genesis(&GenesisConfig::default())
+    }
+
+    /// Create a new blockchain, only containing the configured genesis block
+    pub fn with_genesis(config: &GenesisConfig) -> Self {
+        let mut genesis = Block::genesis();
+        genesis.header.timestamp = config.timestamp;
+        if let Some(difficulty) = config.difficulty {
+            genesis.header.difficulty = difficulty;
+        }
         let genesis_hash = genesis.hash();
-        let initial_state = Arc::new(State::ico());
+        let genesis_work = genesis.header.difficulty.work();
+        let mut initial_state = match &config.initial_balances {
+            Some(balances) => State::with_balances(balances),
+            None => State::ico(),
+        };
+        initial_state.set_rules(config.rules.clone());
+        initial_state.set_block_timestamp(config.timestamp);
+        let initial_state = Arc::new(initial_state);
         Blockchain {
-            hash_to_block: HashMap::from([(genesis_hash, (genesis, 0, initial_state))]),
+            hash_to_block: HashMap::from([(genesis_hash, (genesis, 0, genesis_work, Some(initial_state)))]),
+            state_retention: None,
             tip: genesis_hash,
+            num_children: HashMap::new(),
             orphanage: HashMap::new(),
-            mempool: HashMap::new(),
+            orphanage_capacity: DEFAULT_ORPHANAGE_CAPACITY,
+            orphan_order: VecDeque::new(),
+            orphan_max_age: DEFAULT_ORPHAN_MAX_AGE,
+            invalid_blocks: HashSet::new(),
+            invalid_blocks_order: VecDeque::new(),
+            lazy_orphans: false,
+            ready_orphan_parents: VecDeque::new(),
+            mempool: Mempool::new(),
+            recently_confirmed: HashSet::new(),
+            recently_confirmed_order: VecDeque::new(),
             dirty_mempool: false,
+            tx_index: HashMap::new(),
+            events: EventBus::new(),
+            metrics: Metrics::new(),
+            instant_finality: false,
+            target_block_interval: config.target_block_interval,
+        }
+    }
+
+    /// Save every block in the chain, with its height, to the specified file.
+    /// Orphans, the mempool, and states are not saved
+    pub fn save_to_file(&self, path: &Path) -> io::Result<()> {
+        let mut blocks: Vec<(&Block, u64)> =
+            self.hash_to_block.values().map(|(block, height, _, _)| (block, *height)).collect();
+        // parents before children, so that loading can replay them in order
+        blocks.sort_by_key(|&(_, height)| height);
+        let bytes = bincode::serialize(&blocks).map_err(io::Error::other)?;
+        // write to a temporary file first, so a crash never leaves a partial
+        // chain behind
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, bytes)?;
+        fs::rename(&tmp_path, path)
+    }
+
+    /// Load a chain saved by `save_to_file`, recomputing each block's state by
+    /// replaying transactions from genesis. Its genesis block must be the one
+    /// configured. The tip is the block with the most cumulative work
+    pub fn load_from_file(path: &Path, config: &GenesisConfig) -> io::Result<Blockchain> {
+        let invalid_data = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let bytes = fs::read(path)?;
+        let blocks: Vec<(Block, u64)> = bincode::deserialize(&bytes).map_err(|e| invalid_data(e.to_string()))?;
+        let mut blockchain = Self::with_genesis(config);
+        let genesis_hash = blockchain.tip;
+        let mut tip_work = blockchain.hash_to_block[&genesis_hash].2;
+        for (block, height) in blocks {
+            let hash = block.hash();
+            if height == 0 {
+                if hash != genesis_hash {
+                    return Err(invalid_data(format!("saved genesis {} is not the configured one", hash)));
+                }
+                continue;
+            }
+            let parent_hash = block.header.parent;
+            let (parent_work, parent_state) = match blockchain.hash_to_block.get(&parent_hash) {
+                Some((_, parent_height, parent_work, Some(parent_state))) if parent_height + 1 == height => {
+                    (*parent_work, parent_state)
+                }
+                _ => return Err(invalid_data(format!("block {} does not extend a saved block", hash))),
+            };
+            let Some(state) = state_after(&block, parent_state) else {
+                return Err(invalid_data(format!("block {} has invalid transactions", hash)));
+            };
+            *blockchain.num_children.entry(parent_hash).or_default() += 1;
+            blockchain.index_transactions(hash, &block);
+            let work = parent_work.saturating_add(block.header.difficulty.work());
+            blockchain.hash_to_block.insert(hash, (block, height, work, Some(Arc::new(state))));
+            if work > tip_work {
+                blockchain.tip = hash;
+                tip_work = work;
+            }
         }
+        Ok(blockchain)
     }
 
     /// Insert a block into blockchain
     /// should only be used for debugging
     pub fn insert_block(&mut self, block: Block) {
         let hash = block.hash();
-        let (_, parent_height, parent_state) = self
+        let (_, parent_height, parent_work, _) = self
             .hash_to_block
             .get(&block.header.parent)
             .expect("no orphan blocks");
         let block_height = *parent_height + 1;
-        let new_state = parent_state.clone();
-        self.hash_to_block.insert(hash, (block, block_height, new_state));
+        let block_work = parent_work.saturating_add(block.header.difficulty.work());
+        let new_state = self.compute_state_at(&block.header.parent);
+        let parent_hash = block.header.parent;
+        if !self.hash_to_block.contains_key(&hash) {
+            *self.num_children.entry(parent_hash).or_default() += 1;
+            self.index_transactions(hash, &block);
+        }
+        self.hash_to_block.insert(hash, (block, block_height, block_work, new_state));
 
-        // if the block's height is the new tallest, it becomes the new tip
-        let &(_, current_tallest_height, _) = self
-            .hash_to_block
-            .get(&self.tip)
-            .expect("tip exists in the blockchain");
-        if block_height > current_tallest_height {
-            self.tip = hash;
+        // if the block's chain has the most work, it becomes the new tip
+        if block_work > self.tip_work() {
+            self.set_tip(hash);
         }
+        self.drop_old_states();
     }
 
     /// Insert a block into the blockchain with validation. May assign orphan
-    /// blocks to their parents. Returns all blocks that were added
-    pub fn insert_block_with_validation(&mut self, block: Block) -> Vec<H256> {
-        let mut added_blocks = vec![];
-
+    /// blocks to their parents. Returns all blocks that were added, or why
+    /// the block wasn't
+    pub fn insert_block_with_validation(&mut self, block: Block) -> BlockInsertOutcome {
         // check if the block is already in the blockchain
         if self.hash_to_block.contains_key(&block.hash()) {
-            return added_blocks;
+            return BlockInsertOutcome::rejected(RejectReason::AlreadyKnown);
+        }
+        // an oversized block isn't worth keeping even as an orphan
+        if block.content.transactions.len() > MAX_BLOCK_TRANSACTIONS {
+            info!("rejected block {}: {}", block.hash(), RejectReason::TooManyTransactions);
+            self.remember_invalid(block.hash());
+            return BlockInsertOutcome::rejected(RejectReason::TooManyTransactions);
         }
 
         // find the the parent
         let hash = block.hash();
         let parent_hash = &block.header.parent;
-        if let Some((parent_block, parent_height, parent_state)) = self.hash_to_block.get(parent_hash) {
-            // calculate the difficulty
-            let required_difficulty = parent_block.header.difficulty;
-
-            // validate the block
-            // check its nonce
-            if hash > required_difficulty {
-                // reject the block
-                return added_blocks;
-            }
-            // check all transactions inside it
-            let Some(new_state) = parent_state.update_with_transactions(
-                block.content.transactions.iter().map(|signed| &signed.raw_transaction)
-            ) else {
-                return added_blocks;
+        if let Some((_, parent_height, parent_work, _)) = self.hash_to_block.get(parent_hash) {
+            let required_difficulty = self.required_difficulty(parent_hash).expect("the parent is in the chain");
+            let median_time_past = self.median_time_past(parent_hash);
+            // check the header, and that the body is the one it commits to,
+            // before doing any work on the transactions, which might include
+            // recomputing the parent's state
+            let new_state = validate_header(&block.header, required_difficulty, median_time_past, !self.instant_finality)
+                .and_then(|()| validate_merkle_root(&block))
+                .and_then(|()| {
+                    let parent_state = self.compute_state_at(parent_hash).expect("the parent is in the chain");
+                    validate_block_transactions(&block, &parent_state)
+                });
+            let new_state = match new_state {
+                Ok(new_state) => new_state,
+                Err(reason) => {
+                    info!("rejected block {}: {}", hash, reason);
+                    if reason != RejectReason::TimestampTooLate {
+                        self.remember_invalid(hash);
+                    }
+                    return BlockInsertOutcome::rejected(reason);
+                }
             };
 
-            // block seems valid. assume that if the blocks are valid, then we
-            // care about them even if they're unsolicited.
+            // block seems valid. whether unsolicited blocks are wanted at all
+            // is decided by the caller (see `worker::BlockPolicy`)
 
-            // update the mempool
-            // remove transactions that are in this block
-            for transaction in &block.content.transactions {
-                self.mempool.remove(&transaction.hash());
-            }
+            let block_height = parent_height + 1;
+            let block_work = parent_work.saturating_add(block.header.difficulty.work());
 
             // add the block to the blockchain
-            let block_height = parent_height + 1;
             info!("inserted block {}", hash);
-            self.hash_to_block.insert(hash, (block, block_height, Arc::new(new_state)));
-
-            // if the block's height is the new tallest, it becomes the new tip
-            let &(_, current_tallest_height, _) = self
-                .hash_to_block
-                .get(&self.tip)
-                .expect("tip exists in the blockchain");
-            if block_height > current_tallest_height {
-                self.tip = hash;
+            self.metrics.record_block();
+            *self.num_children.entry(block.header.parent).or_default() += 1;
+            self.index_transactions(hash, &block);
+            self.hash_to_block.insert(hash, (block, block_height, block_work, Some(Arc::new(new_state))));
+
+            // if the block's chain has the most work, it becomes the new tip,
+            // and its transactions leave the mempool (see `set_tip`). Those of
+            // a block on a side fork stay, since it may never be on the
+            // longest chain
+            if block_work > self.tip_work() {
+                self.set_tip(hash);
                 self.dirty_mempool = true;
             }
+            self.drop_old_states();
 
-            added_blocks.push(hash);
+            let mut added_blocks = vec![hash];
 
             // insert all blocks for which this block is a parent
-            if let Some(orphan_children) = self.orphanage.remove(&hash) {
+            if self.lazy_orphans {
+                if self.orphanage.contains_key(&hash) {
+                    self.ready_orphan_parents.push_back(hash);
+                }
+            } else if let Some(orphan_children) = self.orphanage.remove(&hash) {
                 for orphan in orphan_children {
-                    let mut added_children = self.insert_block_with_validation(orphan);
+                    let mut added_children = self.insert_block_with_validation(orphan).added;
                     added_blocks.append(&mut added_children);
                 }
             }
@@ -127,100 +549,1148 @@ impl Blockchain {
             if self.dirty_mempool {
                 self.prune_invalid_transactions();
             }
+            BlockInsertOutcome { added: added_blocks, ..Default::default() }
         } else {
-            // put it into the orphanage
-            self.orphanage.entry(*parent_hash).or_default().push(block);
+            // put it into the orphanage, once. The target it must meet isn't
+            // known without its parent, but it must at least meet the one it
+            // claims, so that orphans aren't free to make up
+            let parent_hash = *parent_hash;
+            if !self.instant_finality && hash > block.header.difficulty {
+                info!("rejected orphan {}: {}", hash, RejectReason::InsufficientWork);
+                self.remember_invalid(hash);
+                return BlockInsertOutcome::rejected(RejectReason::InsufficientWork);
+            }
+            let siblings = self.orphanage.entry(parent_hash).or_default();
+            if siblings.iter().any(|orphan| orphan.hash() == hash) {
+                return BlockInsertOutcome { orphaned: Some(parent_hash), ..Default::default() };
+            }
+            siblings.push(block);
+            self.orphan_order.push_back((parent_hash, hash, Instant::now()));
+            self.evict_orphans();
+            BlockInsertOutcome { orphaned: Some(parent_hash), ..Default::default() }
+        }
+    }
+
+    /// Keep the state of only the last `retention` blocks of the longest
+    /// chain (and genesis), or of every block if `None`. Other states are
+    /// recomputed when needed by `compute_state_at`, trading CPU for memory
+    pub fn set_state_retention(&mut self, retention: Option<u64>) {
+        assert_ne!(retention, Some(0), "the tip's state is always kept");
+        self.state_retention = retention;
+        self.drop_old_states();
+    }
+
+    /// Drop the states that `state_retention` says not to keep
+    fn drop_old_states(&mut self) {
+        let Some(retention) = self.state_retention else {
+            return;
+        };
+        let mut kept = HashSet::new();
+        let mut current_hash = self.tip;
+        for _ in 0..retention {
+            kept.insert(current_hash);
+            let (block, height, _, _) = &self.hash_to_block[&current_hash];
+            if *height == 0 {
+                break;
+            }
+            current_hash = block.header.parent;
+        }
+        for (hash, (_, height, _, state)) in self.hash_to_block.iter_mut() {
+            if *height > 0 && !kept.contains(hash) {
+                *state = None;
+            }
+        }
+    }
+
+    /// Get the state after the specified block, replaying blocks from the
+    /// closest ancestor that still has its state if it was dropped. Returns
+    /// `None` if the block is not in the chain
+    pub fn compute_state_at(&self, hash: &H256) -> Option<Arc<State>> {
+        let mut to_replay = vec![];
+        let mut current_hash = *hash;
+        let base_state = loop {
+            let (block, _, _, state) = self.hash_to_block.get(&current_hash)?;
+            if let Some(state) = state {
+                break state;
+            }
+            to_replay.push(block);
+            current_hash = block.header.parent;
+        };
+        if to_replay.is_empty() {
+            return Some(Arc::clone(base_state));
+        }
+        let mut state = State::clone(base_state);
+        for block in to_replay.into_iter().rev() {
+            state = state_after(block, &state).expect("blocks in the chain are valid");
+        }
+        Some(Arc::new(state))
+    }
+
+    /// For tests that don't care about mining: accept blocks whose hash
+    /// doesn't meet the difficulty target, so that they can be produced
+    /// without grinding nonces. Everything else is still validated
+    #[cfg(any(test, feature = "test-utilities"))]
+    pub fn set_instant_finality(&mut self, enabled: bool) {
+        self.instant_finality = enabled;
+    }
+
+    /// Whether blocks are accepted without proof of work (see
+    /// `set_instant_finality`)
+    pub fn instant_finality(&self) -> bool {
+        self.instant_finality
+    }
+
+    /// Set the maximum number of blocks the orphanage may hold
+    pub fn set_orphanage_capacity(&mut self, capacity: usize) {
+        self.orphanage_capacity = capacity;
+        self.evict_orphans();
+    }
+
+    /// The number of blocks in the orphanage
+    pub fn num_orphans(&self) -> usize {
+        self.orphanage.values().map(Vec::len).sum()
+    }
+
+    /// Evict the oldest orphans until the orphanage is within its capacity
+    fn evict_orphans(&mut self) {
+        let mut num_orphans = self.num_orphans();
+        while num_orphans > self.orphanage_capacity {
+            let Some((parent_hash, hash, _)) = self.orphan_order.pop_front() else {
+                break;
+            };
+            // skip orphans that were connected since
+            let Some(siblings) = self.orphanage.get_mut(&parent_hash) else {
+                continue;
+            };
+            let Some(index) = siblings.iter().position(|orphan| orphan.hash() == hash) else {
+                continue;
+            };
+            siblings.remove(index);
+            if siblings.is_empty() {
+                self.orphanage.remove(&parent_hash);
+            }
+            info!("evicted orphan {}", hash);
+            num_orphans -= 1;
+        }
+
+        // forget the orphans that were connected, so that the order doesn't
+        // grow forever
+        if self.orphan_order.len() > 2 * self.orphanage_capacity.max(num_orphans) {
+            self.forget_connected_orphans();
+        }
+    }
+
+    /// Drop the orphans that are no longer in the orphanage from
+    /// `orphan_order`
+    fn forget_connected_orphans(&mut self) {
+        let orphanage = &self.orphanage;
+        self.orphan_order.retain(|(parent_hash, hash, _)| {
+            orphanage
+                .get(parent_hash)
+                .is_some_and(|siblings| siblings.iter().any(|orphan| orphan.hash() == *hash))
+        });
+    }
+
+    /// Set how long an orphan may wait for its parent before
+    /// `prune_unreachable_orphans` gives up on it
+    pub fn set_orphan_max_age(&mut self, max_age: Duration) {
+        self.orphan_max_age = max_age;
+    }
+
+    /// Remove the orphans that will never connect: those descending from a
+    /// block known to be invalid, and those that have waited longer than the
+    /// maximum age for their parent, along with their descendants in the
+    /// orphanage. Returns the number of orphans removed
+    pub fn prune_unreachable_orphans(&mut self) -> usize {
+        let mut num_pruned = 0;
+        let mut dead_parents: Vec<H256> = self
+            .orphanage
+            .keys()
+            .filter(|parent_hash| self.invalid_blocks.contains(parent_hash))
+            .copied()
+            .collect();
+
+        // the expired orphans go first, and their children with them below
+        let expired: HashSet<H256> = self
+            .orphan_order
+            .iter()
+            .filter(|(_, _, arrival)| arrival.elapsed() > self.orphan_max_age)
+            .map(|(_, hash, _)| *hash)
+            .collect();
+        for siblings in self.orphanage.values_mut() {
+            siblings.retain(|orphan| {
+                let hash = orphan.hash();
+                if !expired.contains(&hash) {
+                    return true;
+                }
+                dead_parents.push(hash);
+                num_pruned += 1;
+                false
+            });
+        }
+        self.orphanage.retain(|_, siblings| !siblings.is_empty());
+
+        while let Some(parent_hash) = dead_parents.pop() {
+            let Some(orphans) = self.orphanage.remove(&parent_hash) else {
+                continue;
+            };
+            for orphan in orphans {
+                info!("pruned unreachable orphan {}", orphan.hash());
+                dead_parents.push(orphan.hash());
+                num_pruned += 1;
+            }
+        }
+        self.forget_connected_orphans();
+        num_pruned
+    }
+
+    /// Remember that a block is invalid, forgetting the oldest such block if
+    /// there are too many
+    fn remember_invalid(&mut self, hash: H256) {
+        if !self.invalid_blocks.insert(hash) {
+            return;
+        }
+        self.invalid_blocks_order.push_back(hash);
+        if self.invalid_blocks_order.len() > INVALID_BLOCKS_CAPACITY {
+            let oldest = self.invalid_blocks_order.pop_front().expect("not empty");
+            self.invalid_blocks.remove(&oldest);
+        }
+    }
+
+    /// Choose whether orphans are connected lazily, by `process_orphans`,
+    /// which spreads the work of connecting a large set of orphans over many
+    /// calls. By default they are all connected as soon as their parent is
+    /// inserted
+    pub fn set_lazy_orphans(&mut self, lazy: bool) {
+        self.lazy_orphans = lazy;
+    }
+
+    /// Try to connect up to `budget` orphans whose parents are now in the
+    /// chain. Returns all blocks that were added
+    pub fn process_orphans(&mut self, budget: usize) -> Vec<H256> {
+        let mut added_blocks = vec![];
+        for _ in 0..budget {
+            let Some(&parent_hash) = self.ready_orphan_parents.front() else {
+                break;
+            };
+            let orphan = match self.orphanage.get_mut(&parent_hash).and_then(Vec::pop) {
+                Some(orphan) => orphan,
+                None => {
+                    self.orphanage.remove(&parent_hash);
+                    self.ready_orphan_parents.pop_front();
+                    continue;
+                }
+            };
+            if self.orphanage.get(&parent_hash).is_some_and(Vec::is_empty) {
+                self.orphanage.remove(&parent_hash);
+                self.ready_orphan_parents.pop_front();
+            }
+            let mut added_children = self.insert_block_with_validation(orphan).added;
+            added_blocks.append(&mut added_children);
         }
         added_blocks
     }
 
+    /// Connect the orphan with the given hash right away, along with all its
+    /// descendants in the orphanage, if its parent is in the chain. Returns
+    /// `None` if there is no such orphan
+    pub fn connect_orphan(&mut self, hash: &H256) -> Option<BlockInsertOutcome> {
+        let (&parent_hash, siblings) = self
+            .orphanage
+            .iter()
+            .find(|(_, orphans)| orphans.iter().any(|orphan| orphan.hash() == *hash))?;
+        if !self.hash_to_block.contains_key(&parent_hash) {
+            return Some(BlockInsertOutcome { orphaned: Some(parent_hash), ..Default::default() });
+        }
+        let index = siblings.iter().position(|orphan| orphan.hash() == *hash).expect("just found it");
+        let siblings = self.orphanage.get_mut(&parent_hash).expect("just found it");
+        let orphan = siblings.remove(index);
+        if siblings.is_empty() {
+            self.orphanage.remove(&parent_hash);
+        }
+        // connect the descendants now, even if orphans are normally connected
+        // lazily
+        let lazy_orphans = std::mem::replace(&mut self.lazy_orphans, false);
+        let outcome = self.insert_block_with_validation(orphan);
+        self.lazy_orphans = lazy_orphans;
+        Some(outcome)
+    }
+
+    /// Move the tip to the specified block, announcing a reorg if the old tip
+    /// is abandoned. The transactions of the blocks that join the longest
+    /// chain leave the mempool and count as confirmed, and those of the
+    /// blocks that leave it no longer do
+    fn set_tip(&mut self, new_tip: H256) {
+        let old_tip = std::mem::replace(&mut self.tip, new_tip);
+        self.mempool.reset_projection();
+        let (disconnect, common_ancestor, connect) = self
+            .path_between(&old_tip, &new_tip)
+            .expect("all blocks descend from genesis");
+        let depth = disconnect.len() as u64;
+        if depth > 0 {
+            info!("reorg of depth {} from {} to {}", depth, old_tip, new_tip);
+            self.events.publish(ChainEvent::Reorg { old_tip, new_tip, common_ancestor, depth });
+        }
+        for hash in disconnect {
+            for transaction_hash in self.transaction_hashes(&hash) {
+                self.recently_confirmed.remove(&transaction_hash);
+            }
+        }
+        for hash in connect {
+            for transaction_hash in self.transaction_hashes(&hash) {
+                self.mempool.remove(&transaction_hash);
+                self.remember_confirmed(transaction_hash);
+            }
+        }
+    }
+
+    /// Get the hashes of the transactions in the specified block
+    fn transaction_hashes(&self, hash: &H256) -> Vec<H256> {
+        let (block, _, _, _) = &self.hash_to_block[hash];
+        block.content.transactions.iter().map(SignedTransaction::hash).collect()
+    }
+
+    /// Receive every `ChainEvent` from now on
+    pub fn subscribe(&mut self) -> Receiver<ChainEvent> {
+        self.events.subscribe()
+    }
+
+    /// Record which transactions are in a newly added block
+    fn index_transactions(&mut self, hash: H256, block: &Block) {
+        for transaction in &block.content.transactions {
+            self.tx_index.entry(transaction.hash()).or_default().push(hash);
+        }
+    }
+
     /// Get the last block's hash of the longest chain
     pub fn tip_hash(&self) -> H256 {
         self.tip
     }
 
+    /// The number of blocks in the chain, on every fork, including genesis
+    pub fn num_blocks(&self) -> usize {
+        self.hash_to_block.len()
+    }
+
     /// Get the data of the tip
     pub fn tip_data(&self) -> (&Block, u64, &State) {
-        let (block, height, state) = self.hash_to_block.get(&self.tip).expect("tip should exist");
-        (block, *height, state)
+        let (block, height, _, state) = self.hash_to_block.get(&self.tip).expect("tip should exist");
+        (block, *height, state.as_ref().expect("the tip keeps its state"))
+    }
+
+    /// Get the genesis block and the initial state, i.e. the state after it
+    pub fn genesis_data(&self) -> (&Block, &State) {
+        let genesis_hash = self.ancestor_at_height(&self.tip, 0).expect("all blocks descend from genesis");
+        let (block, _, _, state) = &self.hash_to_block[&genesis_hash];
+        (block, state.as_ref().expect("genesis keeps its state"))
     }
 
-    /// Look up a block and its height and state using the specified hash
-    pub fn look_up_block(&self, hash: &H256) -> Option<&(Block, u64, Arc<State>)> {
+    /// Look up a block and its height, cumulative work, and state using the
+    /// specified hash. The state is `None` if it was dropped; see
+    /// `compute_state_at`
+    pub fn look_up_block(&self, hash: &H256) -> Option<&BlockEntry> {
         self.hash_to_block.get(hash)
     }
 
-    /// Get all the blocks' hashes along the longest chain
-    #[cfg(any(test, test_utilities))]
+    /// Get the hash of the ancestor of the specified block (or the block
+    /// itself) at the specified height
+    pub fn ancestor_at_height(&self, hash: &H256, height: u64) -> Option<H256> {
+        let mut current_hash = *hash;
+        loop {
+            let (block, current_height, _, _) = self.hash_to_block.get(&current_hash)?;
+            if *current_height == height {
+                return Some(current_hash);
+            }
+            if *current_height < height {
+                return None;
+            }
+            current_hash = block.header.parent;
+        }
+    }
+
+    /// Get the hash of the most recent block that both specified blocks
+    /// descend from (or are)
+    pub fn common_ancestor(&self, a: &H256, b: &H256) -> Option<H256> {
+        let (_, a_height, _, _) = self.hash_to_block.get(a)?;
+        let (_, b_height, _, _) = self.hash_to_block.get(b)?;
+        let height = std::cmp::min(*a_height, *b_height);
+        let mut a = self.ancestor_at_height(a, height)?;
+        let mut b = self.ancestor_at_height(b, height)?;
+        while a != b {
+            a = self.hash_to_block.get(&a)?.0.header.parent;
+            b = self.hash_to_block.get(&b)?.0.header.parent;
+        }
+        Some(a)
+    }
+
+    /// Get the way from one block to another: the blocks to disconnect,
+    /// starting from `from`, their common ancestor, and the blocks to
+    /// connect, ending with `to`
+    pub fn path_between(&self, from: &H256, to: &H256) -> Option<(Vec<H256>, H256, Vec<H256>)> {
+        let ancestor = self.common_ancestor(from, to)?;
+        let blocks_above_ancestor = |tip: &H256| {
+            let mut hashes = Vec::new();
+            let mut current_hash = *tip;
+            while current_hash != ancestor {
+                hashes.push(current_hash);
+                current_hash = self.hash_to_block[&current_hash].0.header.parent;
+            }
+            hashes
+        };
+        let disconnect = blocks_above_ancestor(from);
+        let mut connect = blocks_above_ancestor(to);
+        connect.reverse();
+        Some((disconnect, ancestor, connect))
+    }
+
+    /// Get the median timestamp of the specified block and up to
+    /// `MEDIAN_TIME_SPAN - 1` of its ancestors
+    pub fn median_time_past(&self, hash: &H256) -> u128 {
+        median(&self.recent_timestamps(hash))
+    }
+
+    /// Get the timestamps of the specified block and up to
+    /// `MEDIAN_TIME_SPAN - 1` of its ancestors, most recent first
+    fn recent_timestamps(&self, hash: &H256) -> Vec<u128> {
+        let mut timestamps = Vec::with_capacity(MEDIAN_TIME_SPAN);
+        let mut current_hash = *hash;
+        while let Some((block, _, _, _)) = self.hash_to_block.get(&current_hash) {
+            timestamps.push(block.header.timestamp);
+            if timestamps.len() == MEDIAN_TIME_SPAN {
+                break;
+            }
+            current_hash = block.header.parent;
+        }
+        timestamps
+    }
+
+    /// Fully validate a contiguous sequence of blocks, the first of which
+    /// extends a block already in the chain, without inserting any of them.
+    /// Returns the index of the first invalid block and why it is invalid
+    pub fn validate_chain(&self, blocks: &[Block]) -> Result<(), (usize, RejectReason)> {
+        let Some(first) = blocks.first() else {
+            return Ok(());
+        };
+        let base_hash = first.header.parent;
+        let Some((base_block, base_height, _, _)) = self.hash_to_block.get(&base_hash) else {
+            return Err((0, RejectReason::UnknownParent));
+        };
+        let parent_state = self.compute_state_at(&base_hash).expect("the parent is in the chain");
+        let segment_timestamps: Vec<u128> = blocks.iter().map(|block| block.header.timestamp).collect();
+        let timestamp_at = |height| self.segment_timestamp(&base_hash, *base_height, &segment_timestamps, height);
+        let mut parent_hash = base_hash;
+        let mut parent_difficulty = base_block.header.difficulty;
+        let mut recent_timestamps = self.recent_timestamps(&parent_hash);
+        let mut state = State::clone(&parent_state);
+        for (i, block) in blocks.iter().enumerate() {
+            if block.header.parent != parent_hash {
+                return Err((i, RejectReason::UnknownParent));
+            }
+            let required_difficulty = self.retarget(base_height + i as u64, parent_difficulty, timestamp_at);
+            state = validate_block(block, required_difficulty, median(&recent_timestamps), &state, !self.instant_finality)
+                .map_err(|reason| (i, reason))?;
+
+            parent_hash = block.hash();
+            parent_difficulty = block.header.difficulty;
+            recent_timestamps.insert(0, block.header.timestamp);
+            recent_timestamps.truncate(MEDIAN_TIME_SPAN);
+        }
+        Ok(())
+    }
+
+    /// Check the headers a peer sent before downloading their blocks: they
+    /// must extend `expected_parent`, which we must have, link to each other,
+    /// each pass `validate_header`, and give the chain exactly the total work
+    /// the peer claimed for its best chain
+    pub fn verify_header_segment(&self, headers: &[Header], expected_parent: &H256, claimed_work: u128) -> bool {
+        self.header_segment_work(headers, expected_parent) == Some(claimed_work)
+    }
+
+    /// Get the cumulative work of the chain ending with the given headers, if
+    /// they extend `expected_parent`, which we must have, link to each other,
+    /// and each pass `validate_header`
+    pub fn header_segment_work(&self, headers: &[Header], expected_parent: &H256) -> Option<u128> {
+        let (parent_block, base_height, parent_work, _) = self.hash_to_block.get(expected_parent)?;
+        let segment_timestamps: Vec<u128> = headers.iter().map(|header| header.timestamp).collect();
+        let timestamp_at = |height| self.segment_timestamp(expected_parent, *base_height, &segment_timestamps, height);
+        let mut work = *parent_work;
+        let mut parent_hash = *expected_parent;
+        let mut parent_difficulty = parent_block.header.difficulty;
+        let mut recent_timestamps = self.recent_timestamps(expected_parent);
+        for (i, header) in headers.iter().enumerate() {
+            let required_difficulty = self.retarget(base_height + i as u64, parent_difficulty, timestamp_at);
+            if header.parent != parent_hash
+                || validate_header(header, required_difficulty, median(&recent_timestamps), !self.instant_finality).is_err()
+            {
+                return None;
+            }
+            work = work.saturating_add(header.difficulty.work());
+            parent_hash = header.hash();
+            parent_difficulty = header.difficulty;
+            recent_timestamps.insert(0, header.timestamp);
+            recent_timestamps.truncate(MEDIAN_TIME_SPAN);
+        }
+        Some(work)
+    }
+
+    /// Get hashes of blocks on the longest chain, from the tip back to
+    /// genesis, ever further apart: the most recent ones one by one, then
+    /// doubling the distance each time. A peer can find the last block it
+    /// has in common with us from them, with `headers_after`
+    pub fn block_locator(&self) -> Vec<H256> {
+        let mut locator = vec![];
+        let mut step = 1;
+        let mut current_hash = self.tip;
+        loop {
+            locator.push(current_hash);
+            if locator.len() >= LOCATOR_DENSE_LEN {
+                step *= 2;
+            }
+            let (block, height, _, _) = &self.hash_to_block[&current_hash];
+            if *height == 0 {
+                return locator;
+            }
+            // never step past genesis, so that it is always included
+            current_hash = self
+                .ancestor_at_height(&block.header.parent, height.saturating_sub(step))
+                .expect("all blocks descend from genesis");
+        }
+    }
+
+    /// Get the headers of the blocks on the longest chain after the first
+    /// block in `locator` that is on it, or after genesis if none is, up to
+    /// `max_headers` of them in order
+    pub fn headers_after(&self, locator: &[H256], max_headers: usize) -> Vec<Header> {
+        let start_height = locator
+            .iter()
+            .find_map(|hash| {
+                let (_, height, _, _) = self.hash_to_block.get(hash)?;
+                (self.ancestor_at_height(&self.tip, *height) == Some(*hash)).then_some(*height)
+            })
+            .unwrap_or(0);
+        let mut headers = vec![];
+        let mut current_hash = self.tip;
+        while let Some((block, height, _, _)) = self.hash_to_block.get(&current_hash) {
+            if *height <= start_height {
+                break;
+            }
+            headers.push(block.header.clone());
+            current_hash = block.header.parent;
+        }
+        headers.reverse();
+        headers.truncate(max_headers);
+        headers
+    }
+
+    /// Get the difficulty target that a child of the specified block must
+    /// have, or `None` if the block isn't in the chain
+    pub fn required_difficulty(&self, parent_hash: &H256) -> Option<H256> {
+        let (parent_block, parent_height, _, _) = self.hash_to_block.get(parent_hash)?;
+        let timestamp_at = |height| self.segment_timestamp(parent_hash, *parent_height, &[], height);
+        Some(self.retarget(*parent_height, parent_block.header.difficulty, timestamp_at))
+    }
+
+    /// Get the difficulty target of a block whose parent has the given height
+    /// and target. Every `RETARGET_INTERVAL` blocks, the target is scaled by
+    /// how long the blocks since the last retarget took compared to the
+    /// target block interval. `timestamp_at` gives the timestamps of the
+    /// parent and its ancestors by height
+    fn retarget(&self, parent_height: u64, parent_difficulty: H256, timestamp_at: impl Fn(u64) -> u128) -> H256 {
+        let Some(target_block_interval) = self.target_block_interval else {
+            return parent_difficulty;
+        };
+        if !(parent_height + 1).is_multiple_of(RETARGET_INTERVAL) {
+            return parent_difficulty;
+        }
+        let first_height = parent_height.saturating_sub(RETARGET_INTERVAL);
+        let expected = (parent_height - first_height) * target_block_interval;
+        let actual = timestamp_at(parent_height).saturating_sub(timestamp_at(first_height));
+        let actual = (actual.min(u64::MAX as u128) as u64)
+            .clamp(expected / MAX_RETARGET_FACTOR, expected.saturating_mul(MAX_RETARGET_FACTOR));
+        // slow blocks make the target bigger, i.e. easier to meet
+        parent_difficulty.scale(actual, expected.max(1))
+    }
+
+    /// Get the timestamp of the block at the given height in a chain made of
+    /// `base` (at `base_height`) and its ancestors, followed by blocks with
+    /// the timestamps in `segment_timestamps`
+    fn segment_timestamp(&self, base: &H256, base_height: u64, segment_timestamps: &[u128], height: u64) -> u128 {
+        if height > base_height {
+            return segment_timestamps[(height - base_height - 1) as usize];
+        }
+        let hash = self.ancestor_at_height(base, height).expect("all blocks descend from genesis");
+        self.hash_to_block[&hash].0.header.timestamp
+    }
+
+    /// Get the total work of the chain ending at the specified block
+    pub fn cumulative_work(&self, hash: &H256) -> Option<u128> {
+        self.hash_to_block.get(hash).map(|(_, _, work, _)| *work)
+    }
+
+    /// Get the total work of the longest chain
+    fn tip_work(&self) -> u128 {
+        self.hash_to_block[&self.tip].2
+    }
+
+    /// Get diagnostic information about the specified block
+    pub fn block_info(&self, hash: &H256) -> Option<BlockInfo> {
+        let (_, height, _, _) = self.hash_to_block.get(hash)?;
+        let (_, tip_height, _) = self.tip_data();
+        let on_longest_chain = self.ancestor_at_height(&self.tip, *height) == Some(*hash);
+        Some(BlockInfo {
+            height: *height,
+            cumulative_work: self.cumulative_work(hash)?,
+            confirmations: on_longest_chain.then(|| tip_height - height),
+            num_children: self.num_children.get(hash).copied().unwrap_or(0),
+        })
+    }
+
+    /// Whether the transaction is in the specified block or one of its
+    /// ancestors
+    pub fn is_confirmed_in_chain(&self, tx_hash: &H256, chain_tip: &H256) -> bool {
+        let Some(containing_blocks) = self.tx_index.get(tx_hash) else {
+            return false;
+        };
+        containing_blocks.iter().any(|block_hash| {
+            let Some((_, height, _, _)) = self.hash_to_block.get(block_hash) else {
+                return false;
+            };
+            self.ancestor_at_height(chain_tip, *height) == Some(*block_hash)
+        })
+    }
+
+    /// Get a commitment to the inclusion of the specified transaction in the
+    /// longest chain, or `None` if it isn't in the longest chain
+    pub fn inclusion_commitment(&self, tx_hash: &H256) -> Option<InclusionCommitment> {
+        let mut current_hash = self.tip;
+        while let Some((block, height, _, _)) = self.hash_to_block.get(&current_hash) {
+            let transactions = &block.content.transactions;
+            if let Some(index) = transactions.iter().position(|tx| tx.hash() == *tx_hash) {
+                return Some(InclusionCommitment {
+                    tx_hash: *tx_hash,
+                    block_hash: current_hash,
+                    height: *height,
+                    merkle_root: block.header.merkle_root,
+                    merkle_proof: MerkleTree::new(transactions).proof(index),
+                    index,
+                    num_leaves: transactions.len(),
+                });
+            }
+            current_hash = block.header.parent;
+        }
+        None
+    }
+
+    /// Create a new account by paying it `value` from the funder's account.
+    /// The funding transaction is put in the mempool; once it is mined, its
+    /// hash can be exchanged for an `AccountProof` with `account_proof`
+    pub fn fund_account(&mut self, funder: &Ed25519KeyPair, new_addr: H160, value: u64) -> Result<H256, RejectReason> {
+        let from_addr = H160::from_pubkey(funder.public_key().as_ref());
+        let (_, _, state) = self.tip_data();
+        let nonce = state.get_acc_info(&from_addr).map_or(0, |acc_info| acc_info.nonce);
+        let raw_transaction = RawTransaction { from_addr, to_addr: new_addr, value, fee: 0, nonce, memo: vec![], valid_since: None };
+        let transaction = SignedTransaction::from_raw(raw_transaction, funder);
+        let hash = transaction.hash();
+        self.insert_verified_transaction(transaction)?;
+        Ok(hash)
+    }
+
+    /// Get the proof of the account funded by the specified transaction, or
+    /// `None` if the transaction isn't in the longest chain
+    pub fn account_proof(&self, funding_tx: &H256) -> Option<AccountProof> {
+        let funding = self.inclusion_commitment(funding_tx)?;
+        let (block, _, _, _) = &self.hash_to_block[&funding.block_hash];
+        let RawTransaction { to_addr, value, .. } = block.content.transactions[funding.index].raw_transaction;
+        Some(AccountProof { addr: to_addr, value, funding })
+    }
+
+    /// Get every block that has no children, along with its height and status
+    pub fn chain_tips(&self) -> Vec<(H256, u64, TipStatus)> {
+        let (_, tip_height, _) = self.tip_data();
+        self.hash_to_block
+            .iter()
+            .filter(|(hash, _)| !self.num_children.contains_key(hash))
+            .map(|(hash, (_, height, _, _))| {
+                let status = if *hash == self.tip {
+                    TipStatus::Active
+                } else if tip_height.saturating_sub(*height) <= STALE_FORK_DEPTH {
+                    // a fork with less work may still be taller than the tip
+                    TipStatus::ValidFork
+                } else {
+                    TipStatus::Stale
+                };
+                (*hash, *height, status)
+            })
+            .collect()
+    }
+
+    /// Group the stored blocks that have the same transactions, i.e. the
+    /// same merkle root, but different hashes, such as when two miners build
+    /// the same block with different nonces. Blocks without transactions are
+    /// left out, since they all trivially share a root. Only groups of at
+    /// least two blocks are returned
+    pub fn find_content_duplicates(&self) -> Vec<Vec<H256>> {
+        let mut by_merkle_root: HashMap<H256, Vec<H256>> = HashMap::new();
+        for (hash, (block, _, _, _)) in &self.hash_to_block {
+            if block.content.transactions.is_empty() {
+                continue;
+            }
+            by_merkle_root.entry(block.header.merkle_root).or_default().push(*hash);
+        }
+        let mut duplicates: Vec<Vec<H256>> = by_merkle_root
+            .into_values()
+            .filter(|hashes| hashes.len() > 1)
+            .map(|mut hashes| {
+                hashes.sort();
+                hashes
+            })
+            .collect();
+        duplicates.sort();
+        duplicates
+    }
+
+    /// Get all the blocks' hashes along the longest chain, from the tip back to
+    /// genesis
     pub fn all_blocks_in_longest_chain(&self) -> Vec<H256> {
         let mut results = Vec::new();
         let mut current_hash = self.tip;
 
-        let &(_, expected_height, _) = self
+        let &(_, expected_height, _, _) = self
             .hash_to_block
             .get(&self.tip)
             .expect("tip exists in the blockchain");
 
-        while let Some((block, _, _)) = self.hash_to_block.get(&current_hash) {
+        while let Some((block, _, _, _)) = self.hash_to_block.get(&current_hash) {
             results.push(current_hash);
             current_hash = block.header.parent;
         }
 
-        assert_eq!(results.len() as u64, expected_height + 1);
+        debug_assert_eq!(results.len() as u64, expected_height + 1);
 
         results
     }
 
-    /// Get a transaction from the mempool by hash (or `None` if it does not exist)
+    /// Get a transaction from the mempool by hash (or `None` if it does not
+    /// exist). See `find_transaction` to also look in blocks
     pub fn get_transaction(&self, hash: &H256) -> Option<&SignedTransaction> {
-        // TODO shouldn't this also check the entire blockchain ughh
-        self.mempool.get(hash)
+        self.mempool.get_transaction(hash)
+    }
+
+    /// Get a transaction by hash from the mempool or, if it was mined, from a
+    /// block containing it, preferring one on the longest chain
+    pub fn find_transaction(&self, hash: &H256) -> Option<&SignedTransaction> {
+        if let Some(transaction) = self.mempool.get_transaction(hash) {
+            return Some(transaction);
+        }
+        let containing_blocks = self.tx_index.get(hash)?;
+        let on_longest_chain = |block_hash: &H256| {
+            self.hash_to_block
+                .get(block_hash)
+                .is_some_and(|(_, height, _, _)| self.ancestor_at_height(&self.tip, *height) == Some(*block_hash))
+        };
+        let block_hash = containing_blocks
+            .iter()
+            .find(|block_hash| on_longest_chain(block_hash))
+            .or_else(|| containing_blocks.first())?;
+        let (block, _, _, _) = self.hash_to_block.get(block_hash)?;
+        block.content.transactions.iter().find(|transaction| transaction.hash() == *hash)
+    }
+
+    /// Where the transaction is, or `None` if it is neither in the mempool
+    /// nor in any block
+    pub fn transaction_location(&self, hash: &H256) -> Option<TransactionLocation> {
+        if self.mempool.contains(hash) {
+            Some(TransactionLocation::Mempool)
+        } else if self.tx_index.contains_key(hash) {
+            Some(TransactionLocation::Block)
+        } else {
+            None
+        }
     }
 
     pub fn mempool_transactions(&self) -> impl Iterator<Item = (&H256, &SignedTransaction)> {
         self.mempool.iter()
     }
 
-    #[must_use]
-    pub fn insert_transaction_with_validation(&mut self, transaction: SignedTransaction) -> bool {
-        let hash = transaction.hash();
-        if self.get_transaction(&hash).is_some() {
-            // the transaction is already in the mempool
-            return false;
+    /// Estimate when a transaction in the mempool will be mined, given how
+    /// many transactions fit in a block. Transactions are assumed to be mined
+    /// in the order the miner considers them (see `mempool::mining_priority`)
+    pub fn estimate_confirmation(&self, hash: &H256, block_capacity: usize) -> Option<ConfirmationEstimate> {
+        let transaction = self.mempool.get_transaction(hash)?;
+        let priority = mining_priority(transaction);
+        let num_ahead = self
+            .mempool
+            .iter()
+            .filter(|(_, other)| mining_priority(other) < priority)
+            .count();
+        let blocks = (num_ahead / block_capacity.max(1) + 1) as u64;
+        let time = self
+            .recent_block_interval()
+            .and_then(|interval| interval.checked_mul(u32::try_from(blocks).ok()?));
+        Some(ConfirmationEstimate { blocks, time })
+    }
+
+    /// Get the average time between the most recent blocks on the longest
+    /// chain, or `None` if there is only genesis or the interval is too long
+    /// to represent
+    pub fn recent_block_interval(&self) -> Option<Duration> {
+        let (tip, tip_height, _) = self.tip_data();
+        let span = std::cmp::min(tip_height, BLOCK_INTERVAL_SPAN);
+        if span == 0 {
+            return None;
         }
+        let earlier_hash = self.ancestor_at_height(&self.tip, tip_height - span)?;
+        let (earlier, _, _, _) = &self.hash_to_block[&earlier_hash];
+        let elapsed_millis = tip.header.timestamp.saturating_sub(earlier.header.timestamp);
+        let interval_millis = u64::try_from(elapsed_millis / span as u128).ok()?;
+        Some(Duration::from_millis(interval_millis))
+    }
 
-        // validate the transaction
-        // check its signature
-        if !transaction.verify_signature() {
-            info!("rejected transaction {:?}", transaction);
-            return false;
+    /// Suggest a fee that would have gotten a transaction confirmed within
+    /// `target_blocks` blocks, judging by the last `FEE_ESTIMATE_SPAN` blocks
+    /// on the longest chain. Each block is taken to have accepted any fee at
+    /// least as high as the lowest one it included (or any fee at all, if it
+    /// included no transfers), and the suggestion is the lowest fee that at
+    /// least one in `target_blocks` of them accepted. With only genesis, any
+    /// fee will do, so it is 0
+    pub fn estimate_fee(&self, target_blocks: u64) -> u64 {
+        let mut lowest_fees = Vec::with_capacity(FEE_ESTIMATE_SPAN);
+        let mut current_hash = self.tip;
+        while let Some((block, height, _, _)) = self.hash_to_block.get(&current_hash) {
+            if *height == 0 || lowest_fees.len() == FEE_ESTIMATE_SPAN {
+                break;
+            }
+            let lowest_fee = block
+                .content
+                .transactions
+                .iter()
+                .filter(|transaction| !transaction.is_coinbase())
+                .map(|transaction| transaction.raw_transaction.fee)
+                .min()
+                .unwrap_or(0);
+            lowest_fees.push(lowest_fee);
+            current_hash = block.header.parent;
         }
-        let (_block, _height, state) = self.tip_data();
-        if !state.check_transaction_validity(&transaction.raw_transaction) {
-            return false;
+        if lowest_fees.is_empty() {
+            return 0;
         }
+        lowest_fees.sort_unstable();
+        let num_accepting = (lowest_fees.len() as u64).div_ceil(target_blocks.max(1));
+        lowest_fees[num_accepting as usize - 1]
+    }
 
-        // insert the transaction
-        info!("inserted transaction {:?}", transaction);
-        self.mempool.insert(hash, transaction);
-        true
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
     }
 
-    /// Removes all transactions from the mempool that might be invalid due
-    /// to state changes
-    fn prune_invalid_transactions(&mut self) {
-        let (_, _, latest_state) = self.tip_data();
-        let latest_state = latest_state.clone(); // TODO this is just to avoid memory issues, actually fix later
-        self.mempool.retain(|_, transaction| {
-            latest_state.check_transaction_validity(&transaction.raw_transaction)
-        });
-        self.dirty_mempool = false;
+    /// Set the maximum number of transactions the mempool may hold
+    pub fn set_mempool_capacity(&mut self, capacity: usize) {
+        self.mempool.set_capacity(capacity);
     }
-}
 
-impl std::fmt::Display for Blockchain {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let (_, height, _) = self.tip_data();
+    /// Validate a transaction and insert it into the mempool, or return why
+    /// it was rejected
+    pub fn insert_transaction_with_validation(
+        &mut self,
+        transaction: SignedTransaction,
+    ) -> Result<(), RejectReason> {
+        let hash = transaction.hash();
+        let result = if self.get_transaction(&hash).is_some() {
+            // the transaction is already in the mempool
+            Err(RejectReason::AlreadyKnown)
+        } else if !transaction.verify_signature() {
+            // check its signature
+            info!("rejected transaction {:?}", transaction);
+            Err(RejectReason::InvalidSignature)
+        } else {
+            return self.insert_verified_transaction(transaction);
+        };
+        self.publish_mempool_outcome(hash, result)
+    }
+
+    /// Like `insert_transaction_with_validation`, but for a transaction whose
+    /// signature the caller has already verified (e.g. with
+    /// `transaction::verify_batch`)
+    pub fn insert_verified_transaction(
+        &mut self,
+        transaction: SignedTransaction,
+    ) -> Result<(), RejectReason> {
+        let hash = transaction.hash();
+        let result = self.insert_into_mempool(transaction);
+        self.publish_mempool_outcome(hash, result)
+    }
+
+    /// Tell subscribers whether a transaction made it into the mempool, and
+    /// pass on the result
+    fn publish_mempool_outcome(&mut self, hash: H256, result: Result<(), RejectReason>) -> Result<(), RejectReason> {
+        let event = match result {
+            Ok(()) => ChainEvent::TransactionAccepted(hash),
+            Err(reason) => ChainEvent::TransactionRejected(hash, reason),
+        };
+        self.events.publish(event);
+        result
+    }
+
+    /// Check a transaction whose signature is verified against the mempool
+    /// and the state at the tip, and add it to the mempool
+    fn insert_into_mempool(&mut self, transaction: SignedTransaction) -> Result<(), RejectReason> {
+        let hash = transaction.hash();
+        if !self.mempool.contains(&hash) && self.is_recently_confirmed(&hash) {
+            // the transaction was already mined
+            return Err(RejectReason::AlreadyConfirmed);
+        }
+        let state = self.compute_state_at(&self.tip).expect("the tip is in the chain");
+        match self.mempool.insert_with_validation(transaction, &state) {
+            Ok(()) => {
+                info!("inserted transaction {}", hash);
+                self.metrics.record_transaction();
+                Ok(())
+            }
+            Err(reason) => {
+                if reason == RejectReason::DoubleSpend {
+                    info!("rejected double-spending transaction {}", hash);
+                }
+                Err(reason)
+            }
+        }
+    }
+
+    /// Get a copy of every transaction in the mempool, ordered by sender and
+    /// nonce
+    pub fn export_mempool(&self) -> Vec<SignedTransaction> {
+        let mut transactions: Vec<_> = self.mempool.iter().map(|(_, transaction)| transaction.clone()).collect();
+        transactions.sort_by_key(|transaction| {
+            (transaction.raw_transaction.from_addr, transaction.raw_transaction.nonce)
+        });
+        transactions
+    }
+
+    /// Insert the given transactions into the mempool, e.g. from another
+    /// node's `export_mempool`. If `validate` is false, the transactions are
+    /// inserted as is, even if they are invalid or the mempool is full.
+    /// Returns the number of transactions inserted
+    pub fn import_mempool(&mut self, transactions: Vec<SignedTransaction>, validate: bool) -> usize {
+        let mut num_inserted = 0;
+        for transaction in transactions {
+            if validate {
+                if self.insert_transaction_with_validation(transaction).is_ok() {
+                    num_inserted += 1;
+                }
+                continue;
+            }
+            if self.mempool.insert(transaction) {
+                self.dirty_mempool = true;
+                num_inserted += 1;
+            }
+        }
+        num_inserted
+    }
+
+    /// Whether the transaction was recently included in a block
+    pub fn is_recently_confirmed(&self, hash: &H256) -> bool {
+        self.recently_confirmed.contains(hash)
+    }
+
+    /// Remember that a transaction was included in a block, forgetting the
+    /// oldest such transaction if there are too many
+    fn remember_confirmed(&mut self, hash: H256) {
+        if !self.recently_confirmed.insert(hash) {
+            return;
+        }
+        self.recently_confirmed_order.push_back(hash);
+        if self.recently_confirmed_order.len() > RECENTLY_CONFIRMED_CAPACITY {
+            let oldest = self.recently_confirmed_order.pop_front().expect("not empty");
+            self.recently_confirmed.remove(&oldest);
+        }
+    }
+
+    /// Removes a transaction from the mempool, keeping the mempool's indices
+    /// up to date, so that e.g. its nonce can be spent by another transaction
+    pub fn remove_transaction(&mut self, hash: &H256) -> Option<SignedTransaction> {
+        self.mempool.remove(hash)
+    }
+
+    /// Removes all transactions from the mempool that can't be applied to the
+    /// state at the tip along with the rest of the mempool, other than those
+    /// that are only timelocked. Returns the number of transactions removed
+    pub fn prune_invalid_transactions(&mut self) -> usize {
+        let latest_state = self.compute_state_at(&self.tip).expect("the tip is in the chain");
+        let num_removed = self.mempool.prune_invalid(&latest_state);
+        self.dirty_mempool = false;
+        num_removed
+    }
+}
+
+/// Check a block whose parent is known, given the difficulty target it must
+/// meet (unless `check_pow` is false), the median time past of its parent,
+/// and the state after its parent: its header, its merkle root, then its
+/// transactions. Returns the state after the block
+fn validate_block(
+    block: &Block,
+    required_difficulty: H256,
+    median_time_past: u128,
+    parent_state: &State,
+    check_pow: bool,
+) -> Result<State, RejectReason> {
+    validate_header(&block.header, required_difficulty, median_time_past, check_pow)?;
+    validate_merkle_root(block)?;
+    validate_block_transactions(block, parent_state)
+}
+
+/// Check that the block's transactions are the ones its header commits to. A
+/// block without transactions commits to the all-zero root
+fn validate_merkle_root(block: &Block) -> Result<(), RejectReason> {
+    if block.header.merkle_root != block.content.merkle_root() {
+        return Err(RejectReason::BadMerkleRoot);
+    }
+    Ok(())
+}
+
+/// Check the transactions of a block against the state after its parent, as
+/// `validate_transactions` does. Returns the state after the block
+fn validate_block_transactions(block: &Block, parent_state: &State) -> Result<State, RejectReason> {
+    let mut state = validate_transactions(&block.content.transactions, parent_state)?;
+    state.set_block_timestamp(block.header.timestamp);
+    Ok(state)
+}
+
+/// Check the header of a block whose parent is known, given the difficulty
+/// target it must meet (unless `check_pow` is false) and the median time past
+/// of its parent, and that it isn't too far in the future. This is cheap
+/// compared to checking the transactions, so it is done first, and it is all
+/// that can be checked of a header without its block
+fn validate_header(
+    header: &Header,
+    required_difficulty: H256,
+    median_time_past: u128,
+    check_pow: bool,
+) -> Result<(), RejectReason> {
+    // check its difficulty, since it decides how much work the block adds
+    // to its chain
+    if header.difficulty != required_difficulty {
+        return Err(RejectReason::WrongDifficulty);
+    }
+    // check its nonce
+    if check_pow && header.hash() > required_difficulty {
+        return Err(RejectReason::InsufficientWork);
+    }
+    // check its timestamp
+    if header.timestamp <= median_time_past {
+        return Err(RejectReason::TimestampTooEarly);
+    }
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("system time should always be after Unix epoch")
+        .as_millis();
+    if header.timestamp > now.saturating_add(MAX_FUTURE_BLOCK_TIME) {
+        return Err(RejectReason::TimestampTooLate);
+    }
+    Ok(())
+}
+
+/// Check the transactions of a block against the state after its parent: the
+/// first must be a coinbase, and no other may be; it may create at most
+/// `BLOCK_REWARD` plus the fees of the other transactions; every other one
+/// must be signed by its sender, each sender's must use contiguous nonces, and
+/// all must be valid in order. Returns the state after them
+pub fn validate_transactions(transactions: &[SignedTransaction], parent_state: &State) -> Result<State, RejectReason> {
+    let mut state = parent_state.clone();
+    validate_transactions_in_place(transactions, &mut state)?;
+    Ok(state)
+}
+
+/// Like `validate_transactions`, but applies the transactions to `state`
+/// itself. If they are rejected, `state` may be left partly updated, so a
+/// caller that wants it back should `State::begin` first and roll back
+pub fn validate_transactions_in_place(transactions: &[SignedTransaction], state: &mut State) -> Result<(), RejectReason> {
+    if transactions.len() > MAX_BLOCK_TRANSACTIONS {
+        return Err(RejectReason::TooManyTransactions);
+    }
+    let (coinbase, transfers) = split_coinbase(transactions);
+    let Some(coinbase) = coinbase else {
+        return Err(RejectReason::MissingCoinbase);
+    };
+    if transfers.iter().any(SignedTransaction::is_coinbase) {
+        return Err(RejectReason::MisplacedCoinbase);
+    }
+    let max_coinbase = transfers
+        .iter()
+        .map(|transaction| transaction.raw_transaction.fee)
+        .fold(BLOCK_REWARD, u64::saturating_add);
+    if coinbase.raw_transaction.value > max_coinbase {
+        return Err(RejectReason::ExcessiveCoinbase);
+    }
+    // check that every transaction is signed by its sender; the state checks
+    // below only look at the raw transactions
+    if crate::transaction::verify_batch(transfers).contains(&false) {
+        return Err(RejectReason::InvalidSignature);
+    }
+    // check that each sender's transactions use contiguous nonces
+    let raw_transactions = transfers.iter().map(|signed| &signed.raw_transaction);
+    if !state.check_nonce_contiguity(raw_transactions) {
+        return Err(RejectReason::NonContiguousNonces);
+    }
+    // check all transactions inside it
+    if !apply_transactions_in_place(transactions, state) {
+        return Err(RejectReason::InvalidState);
+    }
+    Ok(())
+}
+
+/// Split off the block's coinbase, if its first transaction is one
+fn split_coinbase(transactions: &[SignedTransaction]) -> (Option<&SignedTransaction>, &[SignedTransaction]) {
+    match transactions.split_first() {
+        Some((first, rest)) if first.is_coinbase() => (Some(first), rest),
+        _ => (None, transactions),
+    }
+}
+
+/// The state after applying the transactions to the given state, or `None` if
+/// they are invalid. The coinbase, if any, is credited after the transfers, so
+/// a miner can't spend its reward in the block that creates it. Signatures
+/// aren't checked
+fn apply_transactions(transactions: &[SignedTransaction], parent_state: &State) -> Option<State> {
+    let mut state = parent_state.clone();
+    apply_transactions_in_place(transactions, &mut state).then_some(state)
+}
+
+/// Like `apply_transactions`, but applies the transactions to `state` itself,
+/// returning whether they were all valid
+fn apply_transactions_in_place(transactions: &[SignedTransaction], state: &mut State) -> bool {
+    let (coinbase, transfers) = split_coinbase(transactions);
+    if !transfers.iter().all(|signed| state.update_in_place(&signed.raw_transaction)) {
+        return false;
+    }
+    if let Some(coinbase) = coinbase {
+        state.mint(&coinbase.raw_transaction.to_addr, coinbase.raw_transaction.value);
+    }
+    true
+}
+
+/// The state after applying the block's transactions to its parent's state,
+/// or `None` if they are invalid
+fn state_after(block: &Block, parent_state: &State) -> Option<State> {
+    let mut state = apply_transactions(&block.content.transactions, parent_state)?;
+    state.set_block_timestamp(block.header.timestamp);
+    Some(state)
+}
+
+/// The median of some timestamps, or 0 if there are none
+fn median(timestamps: &[u128]) -> u128 {
+    let mut timestamps = timestamps.to_vec();
+    timestamps.sort_unstable();
+    timestamps.get(timestamps.len() / 2).copied().unwrap_or(0)
+}
+
+impl Default for Blockchain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Display for Blockchain {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (_, height, _) = self.tip_data();
         write!(
             f,
             "Blockchain status\nNum Blocks: {}\nTip: height {}, hash {}\nMempool: {:#?}\nLedger: {}",
@@ -233,11 +1703,20 @@ impl std::fmt::Display for Blockchain {
     }
 }
 
-#[cfg(any(test, test_utilities))]
+#[cfg(test)]
 mod tests {
     use super::*;
-    use crate::block::test::generate_random_block;
-    use crate::crypto::hash::Hashable;
+    use crate::block::test::{generate_random_block, generate_random_mined_block, mine_valid_block, mine_valid_block_on};
+    use crate::crypto::hash::{Hashable, H256};
+    use crate::crypto::key_pair::get_deterministic_keypair;
+    use crate::transaction::{RawTransaction, MAX_MEMO_LEN};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    /// Sign a transfer of `value` coins, paying `fee`, from `key`'s account
+    fn signed_transfer(key: &Ed25519KeyPair, to_addr: H160, value: u64, fee: u64, nonce: u32) -> SignedTransaction {
+        let from_addr = H160::from_pubkey(key.public_key().as_ref());
+        SignedTransaction::from_raw(RawTransaction { from_addr, to_addr, value, fee, nonce, memo: vec![], valid_since: None }, key)
+    }
 
     #[test]
     fn insert_one() {
@@ -290,6 +1769,1293 @@ mod tests {
         assert_eq!(blockchain.tip_hash(), block_5.hash());
     }
 
+    #[test]
+    fn prune_invalid_transactions() {
+        let mut blockchain = Blockchain::new();
+        let sender = get_deterministic_keypair(0);
+        let receiver = get_deterministic_keypair(1);
+        let to_addr = H160::from_pubkey(receiver.public_key().as_ref());
+        let transaction = signed_transfer(&sender, to_addr, 1, 0, 0);
+        assert_eq!(blockchain.insert_transaction_with_validation(transaction.clone()), Ok(()));
+
+        // spend the sender's nonce behind the mempool's back, without marking
+        // the mempool as dirty
+        let tip = blockchain.tip_hash();
+        let (_, _, _, state) = blockchain.hash_to_block.get_mut(&tip).unwrap();
+        let state = state.as_mut().unwrap();
+        let mut new_state = State::clone(state);
+        assert!(new_state.update_in_place(&transaction.raw_transaction));
+        *state = Arc::new(new_state);
+        assert!(blockchain.get_transaction(&transaction.hash()).is_some());
+
+        assert_eq!(blockchain.prune_invalid_transactions(), 1);
+        assert!(blockchain.get_transaction(&transaction.hash()).is_none());
+        assert_eq!(blockchain.prune_invalid_transactions(), 0);
+    }
+
+    #[test]
+    fn reject_non_contiguous_nonces() {
+        let mut blockchain = Blockchain::new();
+        let sender = get_deterministic_keypair(0);
+        let receiver = get_deterministic_keypair(1);
+        let to_addr = H160::from_pubkey(receiver.public_key().as_ref());
+        let transactions: Vec<_> = [0, 2]
+            .iter()
+            .map(|&nonce| signed_transfer(&sender, to_addr, 1, 0, nonce))
+            .collect();
+
+        let block = mine_valid_block(&blockchain, transactions.clone());
+        assert!(blockchain.insert_block_with_validation(block).added.is_empty());
+
+        let block = mine_valid_block(&blockchain, transactions[..1].to_vec());
+        assert_eq!(blockchain.insert_block_with_validation(block.clone()).added, vec![block.hash()]);
+    }
+
+    #[test]
+    fn block_insert_outcomes() {
+        let mut blockchain = Blockchain::new();
+        let block_1 = mine_valid_block(&blockchain, vec![]);
+        let block_2 = mine_valid_block_on(&block_1, vec![]);
+
+        // its parent is missing
+        assert_eq!(
+            blockchain.insert_block_with_validation(block_2.clone()),
+            BlockInsertOutcome { orphaned: Some(block_1.hash()), ..Default::default() },
+        );
+        // the parent connects it
+        assert_eq!(
+            blockchain.insert_block_with_validation(block_1.clone()),
+            BlockInsertOutcome { added: vec![block_1.hash(), block_2.hash()], ..Default::default() },
+        );
+        // it's a duplicate
+        assert_eq!(
+            blockchain.insert_block_with_validation(block_2.clone()),
+            BlockInsertOutcome { rejected: Some(RejectReason::AlreadyKnown), ..Default::default() },
+        );
+        // it's invalid
+        let mut block_3 = mine_valid_block(&blockchain, vec![]);
+        block_3.header.timestamp = 0;
+        let outcome = blockchain.insert_block_with_validation(block_3);
+        assert!(outcome.added.is_empty());
+        assert_eq!(outcome.orphaned, None);
+        assert!(outcome.rejected.is_some_and(|reason| reason != RejectReason::AlreadyKnown));
+    }
+
+    #[test]
+    fn reject_invalid_signature_in_block() {
+        let mut blockchain = Blockchain::new();
+        let sender = get_deterministic_keypair(0);
+        let to_addr = H160::from_pubkey(get_deterministic_keypair(1).public_key().as_ref());
+        let transaction = signed_transfer(&sender, to_addr, 1, 0, 0);
+
+        // the state transition is fine, but the signature isn't
+        let mut tampered = transaction.clone();
+        tampered.signature[0] ^= 0xff;
+        let block = mine_valid_block(&blockchain, vec![tampered]);
+        assert!(blockchain.insert_block_with_validation(block).added.is_empty());
+
+        // nor is a signature of the wrong length
+        let mut truncated = transaction.clone();
+        truncated.signature.truncate(10);
+        let block = mine_valid_block(&blockchain, vec![truncated]);
+        assert!(blockchain.insert_block_with_validation(block).added.is_empty());
+
+        let block = mine_valid_block(&blockchain, vec![transaction]);
+        assert_eq!(blockchain.insert_block_with_validation(block.clone()).added, vec![block.hash()]);
+    }
+
+    #[test]
+    fn validate_chain() {
+        let mut blockchain = Blockchain::new();
+        let sender = get_deterministic_keypair(0);
+        let to_addr = H160::from_pubkey(get_deterministic_keypair(1).public_key().as_ref());
+        let transaction = |nonce| signed_transfer(&sender, to_addr, 1, 0, nonce);
+        let mut blocks: Vec<Block> = Vec::new();
+        for nonce in 0..10 {
+            let parent = blocks.last().cloned().unwrap_or_else(|| blockchain.tip_data().0.clone());
+            blocks.push(mine_valid_block_on(&parent, vec![transaction(nonce)]));
+        }
+        assert_eq!(blockchain.validate_chain(&blocks), Ok(()));
+
+        // a block whose merkle root doesn't match, with valid blocks after it
+        let mut bad_blocks = blocks[..5].to_vec();
+        let mut bad_block = mine_valid_block_on(&bad_blocks[4], vec![transaction(5)]);
+        bad_block.header.merkle_root = H256::default();
+        while bad_block.hash() > bad_block.header.difficulty {
+            bad_block.header.nonce += 1;
+        }
+        bad_blocks.push(bad_block);
+        for nonce in 6..10 {
+            let parent = bad_blocks.last().unwrap().clone();
+            bad_blocks.push(mine_valid_block_on(&parent, vec![transaction(nonce)]));
+        }
+        assert_eq!(blockchain.validate_chain(&bad_blocks), Err((5, RejectReason::BadMerkleRoot)));
+
+        // nothing was inserted
+        assert_eq!(blockchain.tip_hash(), Block::genesis().hash());
+        for block in blocks {
+            assert_eq!(blockchain.insert_block_with_validation(block.clone()).added, vec![block.hash()]);
+        }
+    }
+
+    #[test]
+    fn verify_header_segment() {
+        let blockchain = Blockchain::new();
+        let genesis = blockchain.tip_data().0.clone();
+        let mut blocks = vec![mine_valid_block_on(&genesis, vec![])];
+        for _ in 0..4 {
+            let parent = blocks.last().unwrap().clone();
+            blocks.push(mine_valid_block_on(&parent, vec![]));
+        }
+        let headers: Vec<Header> = blocks.iter().map(|block| block.header.clone()).collect();
+        let genesis_hash = genesis.hash();
+        let work = blockchain.cumulative_work(&genesis_hash).unwrap()
+            + headers.iter().map(|header| header.difficulty.work()).sum::<u128>();
+
+        assert!(blockchain.verify_header_segment(&headers, &genesis_hash, work));
+        // the peer claims more work than the headers have
+        assert!(!blockchain.verify_header_segment(&headers, &genesis_hash, work + 1));
+        // the headers don't build on the block they should
+        assert!(!blockchain.verify_header_segment(&headers[1..], &genesis_hash, work));
+        assert!(!blockchain.verify_header_segment(&headers, &headers[0].hash(), work));
+    }
+
+    #[test]
+    fn reject_recently_confirmed_transaction() {
+        let mut blockchain = Blockchain::new();
+        let sender = get_deterministic_keypair(0);
+        let to_addr = H160::from_pubkey(get_deterministic_keypair(1).public_key().as_ref());
+        let transaction = signed_transfer(&sender, to_addr, 1, 0, 0);
+        assert_eq!(blockchain.insert_transaction_with_validation(transaction.clone()), Ok(()));
+
+        let block = mine_valid_block(&blockchain, vec![transaction.clone()]);
+        assert_eq!(blockchain.insert_block_with_validation(block.clone()).added, vec![block.hash()]);
+        assert!(blockchain.get_transaction(&transaction.hash()).is_none());
+        assert!(blockchain.is_recently_confirmed(&transaction.hash()));
+
+        // a peer relaying it back doesn't get it re-added
+        assert_eq!(
+            blockchain.insert_transaction_with_validation(transaction.clone()),
+            Err(RejectReason::AlreadyConfirmed),
+        );
+        assert!(blockchain.get_transaction(&transaction.hash()).is_none());
+    }
+
+    #[test]
+    fn side_fork_transactions_stay_unconfirmed() {
+        let mut blockchain = Blockchain::new();
+        let sender = get_deterministic_keypair(0);
+        let to_addr = H160::from_pubkey(get_deterministic_keypair(1).public_key().as_ref());
+        let transaction = signed_transfer(&sender, to_addr, 1, 0, 0);
+        let genesis = blockchain.tip_data().0.clone();
+        let main_block = mine_valid_block_on(&genesis, vec![]);
+        assert_eq!(blockchain.insert_block_with_validation(main_block.clone()).added, vec![main_block.hash()]);
+
+        // a block with the same work as the tip doesn't confirm anything
+        let fork_block = mine_valid_block_on(&genesis, vec![transaction.clone()]);
+        assert_eq!(blockchain.insert_block_with_validation(fork_block.clone()).added, vec![fork_block.hash()]);
+        assert_eq!(blockchain.tip_hash(), main_block.hash());
+        assert!(!blockchain.is_recently_confirmed(&transaction.hash()));
+        assert_eq!(blockchain.insert_transaction_with_validation(transaction.clone()), Ok(()));
+
+        // until the fork becomes the longest chain
+        let fork_tip = mine_valid_block_on(&fork_block, vec![]);
+        blockchain.insert_block_with_validation(fork_tip.clone());
+        assert_eq!(blockchain.tip_hash(), fork_tip.hash());
+        assert!(blockchain.get_transaction(&transaction.hash()).is_none());
+        assert!(blockchain.is_recently_confirmed(&transaction.hash()));
+
+        // and stops confirming it once it is abandoned again
+        let mut main_tip = main_block;
+        for _ in 0..2 {
+            main_tip = mine_valid_block_on(&main_tip, vec![]);
+            blockchain.insert_block_with_validation(main_tip.clone());
+        }
+        assert_eq!(blockchain.tip_hash(), main_tip.hash());
+        assert!(!blockchain.is_recently_confirmed(&transaction.hash()));
+    }
+
+    #[test]
+    fn timestamps_from_configured_genesis() {
+        let genesis_timestamp = 1_700_000_000_000;
+        let mut blockchain = Blockchain::with_genesis(&GenesisConfig {
+            timestamp: genesis_timestamp,
+            ..Default::default()
+        });
+        assert_eq!(blockchain.tip_data().0.header.timestamp, genesis_timestamp);
+        let sender = get_deterministic_keypair(0);
+        let to_addr = H160::from_pubkey(get_deterministic_keypair(1).public_key().as_ref());
+        let transaction = |nonce| signed_transfer(&sender, to_addr, 1, 0, nonce);
+
+        // a block from before genesis is rejected
+        let mut block = mine_valid_block(&blockchain, vec![transaction(0)]);
+        block.header.timestamp = genesis_timestamp - 1;
+        while block.hash() > block.header.difficulty {
+            block.header.nonce += 1;
+        }
+        assert!(blockchain.insert_block_with_validation(block).added.is_empty());
+
+        // a short chain with fewer ancestors than the median window is fine
+        for nonce in 0..5 {
+            let block = mine_valid_block(&blockchain, vec![transaction(nonce)]);
+            assert_eq!(blockchain.insert_block_with_validation(block.clone()).added, vec![block.hash()]);
+        }
+        assert_eq!(blockchain.median_time_past(&blockchain.tip_hash()), genesis_timestamp + 3);
+    }
+
+    #[test]
+    fn configured_genesis() {
+        let sender = get_deterministic_keypair(20);
+        let from_addr = H160::from_pubkey(sender.public_key().as_ref());
+        let to_addr = H160::from_pubkey(get_deterministic_keypair(0).public_key().as_ref());
+        let difficulty = H256::with_leading_zeros(1);
+        let mut blockchain = Blockchain::with_genesis(&GenesisConfig {
+            difficulty: Some(difficulty),
+            initial_balances: Some(vec![(from_addr, 5)]),
+            ..Default::default()
+        });
+        assert_eq!(blockchain.tip_data().0.header.difficulty, difficulty);
+        assert_ne!(blockchain.tip_hash(), Blockchain::new().tip_hash());
+        let genesis_state = blockchain.tip_data().2;
+        assert_eq!(genesis_state.get_acc_info(&from_addr).unwrap().balance, 5);
+        // the default accounts get nothing
+        assert!(genesis_state.get_acc_info(&to_addr).is_none());
+
+        let transaction = signed_transfer(&sender, to_addr, 5, 0, 0);
+        let block = mine_valid_block(&blockchain, vec![transaction]);
+        assert_eq!(block.header.difficulty, difficulty);
+        assert_eq!(blockchain.insert_block_with_validation(block.clone()).added, vec![block.hash()]);
+        assert_eq!(blockchain.tip_data().2.get_acc_info(&to_addr).unwrap().balance, 5);
+    }
+
+    #[test]
+    fn timestamp_bounds() {
+        let mut blockchain = Blockchain::new();
+        let parent = mine_valid_block(&blockchain, vec![]);
+        blockchain.insert_block_with_validation(parent.clone());
+        let with_timestamp = |timestamp| {
+            let mut block = mine_valid_block_on(&parent, vec![]);
+            block.header.timestamp = timestamp;
+            while block.hash() > block.header.difficulty {
+                block.header.nonce += 1;
+            }
+            block
+        };
+
+        // a block no later than its parent is rejected
+        let stuck = with_timestamp(parent.header.timestamp);
+        assert_eq!(blockchain.insert_block_with_validation(stuck).rejected, Some(RejectReason::TimestampTooEarly));
+
+        // and so is one from hours in the future, though only for now
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis();
+        let future = with_timestamp(now + MAX_FUTURE_BLOCK_TIME + 60_000);
+        assert_eq!(blockchain.insert_block_with_validation(future.clone()).rejected, Some(RejectReason::TimestampTooLate));
+        assert!(!blockchain.invalid_blocks.contains(&future.hash()));
+
+        // a little ahead of our clock is fine
+        let ahead = with_timestamp(now + 60_000);
+        assert_eq!(blockchain.insert_block_with_validation(ahead.clone()).added, vec![ahead.hash()]);
+    }
+
+    #[test]
+    fn tampered_body() {
+        let mut blockchain = Blockchain::new();
+        blockchain.set_instant_finality(true);
+        let sender = get_deterministic_keypair(0);
+        let to_addr = H160::from_pubkey(get_deterministic_keypair(1).public_key().as_ref());
+        let pay = |value| signed_transfer(&sender, to_addr, value, 0, 0);
+
+        // the header commits to paying 1, but the body pays 100
+        let mut tampered = mine_valid_block(&blockchain, vec![pay(1)]);
+        tampered.content.transactions = vec![pay(100)];
+        assert_eq!(blockchain.insert_block_with_validation(tampered).rejected, Some(RejectReason::BadMerkleRoot));
+
+        // an empty body must commit to the empty root
+        let mut emptied = mine_valid_block(&blockchain, vec![pay(1)]);
+        emptied.content.transactions.clear();
+        assert_eq!(blockchain.insert_block_with_validation(emptied).rejected, Some(RejectReason::BadMerkleRoot));
+        let empty = mine_valid_block(&blockchain, vec![]);
+        assert_eq!(blockchain.insert_block_with_validation(empty.clone()).added, vec![empty.hash()]);
+    }
+
+    #[test]
+    fn too_many_transactions() {
+        let mut blockchain = Blockchain::new();
+        let transactions = vec![SignedTransaction::generate_random(); MAX_BLOCK_TRANSACTIONS + 1];
+        let oversized = mine_valid_block(&blockchain, transactions);
+        let outcome = blockchain.insert_block_with_validation(oversized.clone());
+        assert_eq!(outcome.rejected, Some(RejectReason::TooManyTransactions));
+        assert!(outcome.added.is_empty());
+        assert!(blockchain.look_up_block(&oversized.hash()).is_none());
+
+        // it isn't kept as an orphan either
+        let orphan = mine_valid_block_on(&mine_valid_block(&blockchain, vec![]), oversized.content.transactions.clone());
+        assert_eq!(blockchain.insert_block_with_validation(orphan).rejected, Some(RejectReason::TooManyTransactions));
+        assert_eq!(blockchain.num_orphans(), 0);
+    }
+
+    #[test]
+    fn max_tx_value() {
+        let mut blockchain = Blockchain::with_genesis(&GenesisConfig {
+            rules: ValidationRules { max_tx_value: Some(100), ..Default::default() },
+            ..Default::default()
+        });
+        let sender = get_deterministic_keypair(0);
+        let to_addr = H160::from_pubkey(get_deterministic_keypair(1).public_key().as_ref());
+        let transaction = |value| signed_transfer(&sender, to_addr, value, 0, 0);
+
+        assert_eq!(
+            blockchain.insert_transaction_with_validation(transaction(101)),
+            Err(RejectReason::ValueTooLarge),
+        );
+        let block = mine_valid_block(&blockchain, vec![transaction(101)]);
+        assert!(blockchain.insert_block_with_validation(block).added.is_empty());
+
+        assert_eq!(blockchain.insert_transaction_with_validation(transaction(100)), Ok(()));
+        let block = mine_valid_block(&blockchain, vec![transaction(100)]);
+        assert_eq!(blockchain.insert_block_with_validation(block.clone()).added, vec![block.hash()]);
+    }
+
+    #[test]
+    fn zero_value_policy() {
+        let sender = get_deterministic_keypair(0);
+        let to_addr = H160::from_pubkey(get_deterministic_keypair(1).public_key().as_ref());
+        let zero_value = signed_transfer(&sender, to_addr, 0, 0, 0);
+
+        // allowed by default
+        let mut blockchain = Blockchain::new();
+        assert_eq!(blockchain.insert_transaction_with_validation(zero_value.clone()), Ok(()));
+
+        let mut blockchain = Blockchain::with_genesis(&GenesisConfig {
+            rules: ValidationRules { reject_zero_value: true, ..Default::default() },
+            ..Default::default()
+        });
+        assert_eq!(
+            blockchain.insert_transaction_with_validation(zero_value.clone()),
+            Err(RejectReason::ZeroValue),
+        );
+        let block = mine_valid_block(&blockchain, vec![zero_value]);
+        assert!(blockchain.insert_block_with_validation(block).added.is_empty());
+    }
+
+    #[test]
+    fn transaction_memo() {
+        let mut blockchain = Blockchain::new();
+        let sender = get_deterministic_keypair(0);
+        let from_addr = H160::from_pubkey(sender.public_key().as_ref());
+        let to_addr = H160::from_pubkey(get_deterministic_keypair(1).public_key().as_ref());
+        let transaction = |nonce, memo: &[u8]| {
+            SignedTransaction::from_raw(
+                RawTransaction { from_addr, to_addr, value: 1, fee: 0, nonce, memo: memo.to_vec(), valid_since: None },
+                &sender,
+            )
+        };
+
+        // a memo survives serialization and is covered by the signature
+        let tagged = transaction(0, b"order #42");
+        let decoded: SignedTransaction = serde_json::from_str(&serde_json::to_string(&tagged).unwrap()).unwrap();
+        assert_eq!(decoded.raw_transaction.memo, b"order #42");
+        assert_eq!(decoded.hash(), tagged.hash());
+        let mut tampered = tagged.clone();
+        tampered.raw_transaction.memo = b"order #43".to_vec();
+        assert!(!tampered.verify_signature());
+        assert_eq!(blockchain.insert_transaction_with_validation(tagged.clone()), Ok(()));
+        assert_eq!(blockchain.get_transaction(&tagged.hash()).unwrap().raw_transaction.memo, b"order #42");
+
+        // one byte too many
+        let oversized = transaction(1, &[0; MAX_MEMO_LEN + 1]);
+        assert_eq!(
+            blockchain.insert_transaction_with_validation(oversized.clone()),
+            Err(RejectReason::MemoTooLong),
+        );
+        let block = mine_valid_block(&blockchain, vec![tagged, oversized]);
+        assert!(blockchain.insert_block_with_validation(block).added.is_empty());
+    }
+
+    #[test]
+    fn estimate_confirmation() {
+        let mut blockchain = Blockchain::new();
+        let to_addr = H160::from_pubkey(get_deterministic_keypair(9).public_key().as_ref());
+        let mut transactions: Vec<_> = (0..5)
+            .map(|i| {
+                let sender = get_deterministic_keypair(i);
+                signed_transfer(&sender, to_addr, 1, 0, 0)
+            })
+            .collect();
+        for transaction in &transactions {
+            assert_eq!(blockchain.insert_transaction_with_validation(transaction.clone()), Ok(()));
+        }
+        transactions.sort_by_key(|transaction| transaction.raw_transaction.from_addr);
+
+        // with two transactions per block, they go out in blocks of two
+        let blocks: Vec<u64> = transactions
+            .iter()
+            .map(|transaction| blockchain.estimate_confirmation(&transaction.hash(), 2).unwrap().blocks)
+            .collect();
+        assert_eq!(blocks, vec![1, 1, 2, 2, 3]);
+        // without any blocks after genesis, there's no telling how long it takes
+        assert_eq!(blockchain.estimate_confirmation(&transactions[0].hash(), 2).unwrap().time, None);
+        assert_eq!(blockchain.estimate_confirmation(&H256::default(), 2), None);
+
+        // after blocks 1ms apart, the time follows
+        let block = mine_valid_block(&blockchain, vec![]);
+        blockchain.insert_block_with_validation(block);
+        let estimate = blockchain.estimate_confirmation(&transactions[4].hash(), 2).unwrap();
+        assert_eq!(estimate.time, Some(Duration::from_millis(3)));
+
+        // a transaction paying a fee goes ahead of all of them
+        let sender = get_deterministic_keypair(5);
+        let generous = signed_transfer(&sender, to_addr, 1, 10, 0);
+        assert_eq!(blockchain.insert_transaction_with_validation(generous.clone()), Ok(()));
+        assert_eq!(blockchain.estimate_confirmation(&generous.hash(), 2).unwrap().blocks, 1);
+        let blocks: Vec<u64> = transactions
+            .iter()
+            .map(|transaction| blockchain.estimate_confirmation(&transaction.hash(), 2).unwrap().blocks)
+            .collect();
+        assert_eq!(blocks, vec![1, 2, 2, 3, 3]);
+
+        // a time too long to represent is unknown rather than wrong
+        let mut block = mine_valid_block(&blockchain, vec![]);
+        block.header.timestamp = 2 * u64::MAX as u128;
+        blockchain.insert_block(block);
+        let sender = get_deterministic_keypair(0);
+        let mut last = None;
+        for nonce in 1..=1100 {
+            let transaction = signed_transfer(&sender, to_addr, 1, 0, nonce);
+            assert_eq!(blockchain.insert_transaction_with_validation(transaction.clone()), Ok(()));
+            last = Some(transaction.hash());
+        }
+        let estimate = blockchain.estimate_confirmation(&last.unwrap(), 1).unwrap();
+        assert!(estimate.blocks > 1100);
+        assert_eq!(estimate.time, None);
+    }
+
+    #[test]
+    fn estimate_fee() {
+        let mut blockchain = Blockchain::new();
+        assert_eq!(blockchain.estimate_fee(1), 0);
+
+        // six blocks, each with a cheap and a dear transaction
+        let to_addr = H160::from_pubkey(get_deterministic_keypair(9).public_key().as_ref());
+        for (i, &lowest_fee) in [12, 10, 8, 6, 4, 2].iter().enumerate() {
+            let sender = get_deterministic_keypair(i as u8);
+            let pay = |nonce, fee| {
+                signed_transfer(&sender, to_addr, 1, fee, nonce)
+            };
+            let block = mine_valid_block(&blockchain, vec![pay(0, lowest_fee), pay(1, lowest_fee + 5)]);
+            assert_eq!(blockchain.insert_block_with_validation(block.clone()).added, vec![block.hash()]);
+        }
+
+        // getting into the next block takes what every block took, but
+        // within six blocks, what any of them took will do
+        assert_eq!(blockchain.estimate_fee(1), 12);
+        assert_eq!(blockchain.estimate_fee(2), 6);
+        assert_eq!(blockchain.estimate_fee(6), 2);
+        assert_eq!(blockchain.estimate_fee(100), 2);
+        assert_eq!(blockchain.estimate_fee(0), blockchain.estimate_fee(1));
+
+        // a block without transfers would have taken any fee
+        let block = mine_valid_block(&blockchain, vec![]);
+        blockchain.insert_block_with_validation(block);
+        assert_eq!(blockchain.estimate_fee(7), 0);
+        assert_eq!(blockchain.estimate_fee(1), 12);
+    }
+
+    #[test]
+    fn remove_transaction() {
+        let mut blockchain = Blockchain::new();
+        let sender = get_deterministic_keypair(0);
+        let transaction = |receiver| {
+            let to_addr = H160::from_pubkey(get_deterministic_keypair(receiver).public_key().as_ref());
+            signed_transfer(&sender, to_addr, 1, 0, 0)
+        };
+        let stuck = transaction(1);
+        let replacement = transaction(2);
+        assert_eq!(blockchain.insert_transaction_with_validation(stuck.clone()), Ok(()));
+        assert_eq!(
+            blockchain.insert_transaction_with_validation(replacement.clone()),
+            Err(RejectReason::DoubleSpend),
+        );
+
+        assert_eq!(blockchain.remove_transaction(&stuck.hash()).map(|tx| tx.hash()), Some(stuck.hash()));
+        assert!(blockchain.get_transaction(&stuck.hash()).is_none());
+        assert!(blockchain.remove_transaction(&stuck.hash()).is_none());
+        assert_eq!(blockchain.insert_transaction_with_validation(replacement), Ok(()));
+    }
+
+    #[test]
+    fn mempool_full() {
+        let mut blockchain = Blockchain::new();
+        blockchain.set_mempool_capacity(2);
+        let to_addr = H160::from_pubkey(get_deterministic_keypair(9).public_key().as_ref());
+        for i in 0..3 {
+            let sender = get_deterministic_keypair(i);
+            let transaction = signed_transfer(&sender, to_addr, 1, 0, 0);
+            let expected = if i < 2 { Ok(()) } else { Err(RejectReason::MempoolFull) };
+            assert_eq!(blockchain.insert_transaction_with_validation(transaction), expected);
+        }
+        assert_eq!(blockchain.mempool_transactions().count(), 2);
+    }
+
+    #[test]
+    fn chain_tips() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip_hash();
+        assert_eq!(blockchain.chain_tips(), vec![(genesis_hash, 0, TipStatus::Active)]);
+
+        // a main branch and a fork that is one block behind
+        let mut main_tip = generate_random_block(&genesis_hash);
+        blockchain.insert_block(main_tip.clone());
+        let fork_tip = generate_random_block(&genesis_hash);
+        blockchain.insert_block(fork_tip.clone());
+        main_tip = generate_random_block(&main_tip.hash());
+        blockchain.insert_block(main_tip.clone());
+
+        let mut tips = blockchain.chain_tips();
+        tips.sort_by_key(|&(_, height, _)| height);
+        assert_eq!(
+            tips,
+            vec![
+                (fork_tip.hash(), 1, TipStatus::ValidFork),
+                (main_tip.hash(), 2, TipStatus::Active),
+            ],
+        );
+
+        // grow the main branch until the fork falls far behind
+        for _ in 0..STALE_FORK_DEPTH {
+            main_tip = generate_random_block(&main_tip.hash());
+            blockchain.insert_block(main_tip.clone());
+        }
+        let mut tips = blockchain.chain_tips();
+        tips.sort_by_key(|&(_, height, _)| height);
+        assert_eq!(
+            tips,
+            vec![
+                (fork_tip.hash(), 1, TipStatus::Stale),
+                (main_tip.hash(), 2 + STALE_FORK_DEPTH, TipStatus::Active),
+            ],
+        );
+
+        // a fork that is taller than the tip but has less work
+        let mut blockchain = Blockchain::new();
+        let mut hard_tip = generate_random_block(&genesis_hash);
+        hard_tip.header.difficulty = H256::with_leading_zeros(16);
+        blockchain.insert_block(hard_tip.clone());
+        let mut tall_tip = generate_random_block(&genesis_hash);
+        blockchain.insert_block(tall_tip.clone());
+        for _ in 0..3 {
+            tall_tip = generate_random_block(&tall_tip.hash());
+            blockchain.insert_block(tall_tip.clone());
+        }
+        let mut tips = blockchain.chain_tips();
+        tips.sort_by_key(|&(_, height, _)| height);
+        assert_eq!(
+            tips,
+            vec![
+                (hard_tip.hash(), 1, TipStatus::Active),
+                (tall_tip.hash(), 4, TipStatus::ValidFork),
+            ],
+        );
+    }
+
+    #[test]
+    fn reorg_events() {
+        let mut blockchain = Blockchain::new();
+        let events = blockchain.subscribe();
+        let genesis_hash = blockchain.tip_hash();
+        let mut main_tip = generate_random_block(&genesis_hash);
+        blockchain.insert_block(main_tip.clone());
+        main_tip = generate_random_block(&main_tip.hash());
+        blockchain.insert_block(main_tip.clone());
+        // extending the tip isn't a reorg
+        assert!(events.try_recv().is_err());
+
+        // a fork from genesis overtakes the main chain
+        let mut fork_tip = generate_random_block(&genesis_hash);
+        for _ in 0..3 {
+            blockchain.insert_block(fork_tip.clone());
+            fork_tip = generate_random_block(&fork_tip.hash());
+        }
+        let fork_tip_hash = blockchain.tip_hash();
+        let deep_reorg = ChainEvent::Reorg {
+            old_tip: main_tip.hash(),
+            new_tip: fork_tip_hash,
+            common_ancestor: genesis_hash,
+            depth: 2,
+        };
+        assert_eq!(events.try_recv(), Ok(deep_reorg));
+        assert!(events.try_recv().is_err());
+
+        // a fork off the fork's parent causes a shallow one
+        let (fork_block, _, _, _) = blockchain.look_up_block(&fork_tip_hash).unwrap();
+        let fork_parent = fork_block.header.parent;
+        let sibling = generate_random_block(&fork_parent);
+        blockchain.insert_block(sibling.clone());
+        let sibling_child = generate_random_block(&sibling.hash());
+        blockchain.insert_block(sibling_child.clone());
+        let Ok(ChainEvent::Reorg { common_ancestor, depth, .. }) = events.try_recv() else {
+            panic!("expected a reorg");
+        };
+        assert_eq!(common_ancestor, fork_parent);
+        assert_eq!(depth, 1);
+    }
+
+    #[test]
+    fn path_between() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip_hash();
+        let common = generate_random_block(&genesis_hash);
+        blockchain.insert_block(common.clone());
+        let main_1 = generate_random_block(&common.hash());
+        blockchain.insert_block(main_1.clone());
+        let main_2 = generate_random_block(&main_1.hash());
+        blockchain.insert_block(main_2.clone());
+        let fork_1 = generate_random_block(&common.hash());
+        blockchain.insert_block(fork_1.clone());
+
+        assert_eq!(
+            blockchain.path_between(&main_2.hash(), &fork_1.hash()),
+            Some((vec![main_2.hash(), main_1.hash()], common.hash(), vec![fork_1.hash()])),
+        );
+        assert_eq!(
+            blockchain.path_between(&fork_1.hash(), &main_2.hash()),
+            Some((vec![fork_1.hash()], common.hash(), vec![main_1.hash(), main_2.hash()])),
+        );
+        // along a single chain, there's nothing to disconnect
+        assert_eq!(
+            blockchain.path_between(&genesis_hash, &main_1.hash()),
+            Some((vec![], genesis_hash, vec![common.hash(), main_1.hash()])),
+        );
+        assert_eq!(blockchain.path_between(&main_2.hash(), &H256::default()), None);
+    }
+
+    #[test]
+    fn process_orphans_lazily() {
+        let mut blockchain = Blockchain::new();
+        blockchain.set_lazy_orphans(true);
+        let mut blocks: Vec<Block> = vec![mine_valid_block(&blockchain, vec![])];
+        for _ in 0..4 {
+            blocks.push(mine_valid_block_on(blocks.last().unwrap(), vec![]));
+        }
+        let hashes: Vec<H256> = blocks.iter().map(Block::hash).collect();
+        for block in blocks[1..].iter().rev() {
+            assert!(blockchain.insert_block_with_validation(block.clone()).added.is_empty());
+        }
+
+        // the parent arrives, but its descendants wait
+        assert_eq!(blockchain.insert_block_with_validation(blocks[0].clone()).added, vec![hashes[0]]);
+        assert_eq!(blockchain.tip_hash(), hashes[0]);
+        assert_eq!(blockchain.process_orphans(2), hashes[1..3].to_vec());
+        assert_eq!(blockchain.tip_hash(), hashes[2]);
+        assert_eq!(blockchain.process_orphans(2), hashes[3..5].to_vec());
+        assert_eq!(blockchain.tip_hash(), hashes[4]);
+        assert!(blockchain.process_orphans(2).is_empty());
+    }
+
+    #[test]
+    fn connect_orphan_by_hash() {
+        let mut blockchain = Blockchain::new();
+        blockchain.set_lazy_orphans(true);
+        let parent = mine_valid_block(&blockchain, vec![]);
+        let orphan = mine_valid_block_on(&parent, vec![]);
+        let grandchild = mine_valid_block_on(&orphan, vec![]);
+        blockchain.insert_block_with_validation(orphan.clone());
+        blockchain.insert_block_with_validation(grandchild.clone());
+
+        assert_eq!(blockchain.connect_orphan(&parent.hash()), None);
+        assert_eq!(
+            blockchain.connect_orphan(&orphan.hash()),
+            Some(BlockInsertOutcome { orphaned: Some(parent.hash()), ..Default::default() }),
+        );
+
+        // the parent arrives separately, but the orphan waits to be processed
+        assert_eq!(blockchain.insert_block_with_validation(parent.clone()).added, vec![parent.hash()]);
+        assert_eq!(
+            blockchain.connect_orphan(&orphan.hash()),
+            Some(BlockInsertOutcome { added: vec![orphan.hash(), grandchild.hash()], ..Default::default() }),
+        );
+        assert_eq!(blockchain.tip_hash(), grandchild.hash());
+        assert_eq!(blockchain.connect_orphan(&orphan.hash()), None);
+        assert!(blockchain.process_orphans(10).is_empty());
+    }
+
+    #[test]
+    fn instant_finality() {
+        let mut blockchain = Blockchain::new();
+        // a block that doesn't meet the target
+        let mut block = generate_random_block(&blockchain.tip_hash());
+        block.content.transactions = vec![SignedTransaction::coinbase(Default::default(), 0, 1)];
+        block.header.merkle_root = block.content.merkle_root();
+        block.header.timestamp = 1;
+        while block.hash() <= block.header.difficulty {
+            block.header.nonce += 1;
+        }
+
+        assert_eq!(
+            blockchain.insert_block_with_validation(block.clone()).rejected,
+            Some(RejectReason::InsufficientWork),
+        );
+        blockchain.set_instant_finality(true);
+        assert_eq!(blockchain.insert_block_with_validation(block.clone()).added, vec![block.hash()]);
+
+        // transactions are still checked
+        let sender = get_deterministic_keypair(0);
+        let from_addr = H160::from_pubkey(sender.public_key().as_ref());
+        let overspend = signed_transfer(&sender, from_addr, u64::MAX, 0, 0);
+        let bad_block = mine_valid_block_on(&block, vec![overspend]);
+        assert_eq!(
+            blockchain.insert_block_with_validation(bad_block).rejected,
+            Some(RejectReason::InvalidState),
+        );
+    }
+
+    #[test]
+    fn find_content_duplicates() {
+        let mut blockchain = Blockchain::new();
+        let sender = get_deterministic_keypair(0);
+        let to_addr = H160::from_pubkey(get_deterministic_keypair(1).public_key().as_ref());
+        let transaction = signed_transfer(&sender, to_addr, 1, 0, 0);
+        let block = mine_valid_block(&blockchain, vec![transaction]);
+        // the same content, mined with a different nonce
+        let mut twin = block.clone();
+        twin.header.nonce += 1;
+        while twin.hash() > twin.header.difficulty {
+            twin.header.nonce += 1;
+        }
+        let other = mine_valid_block(&blockchain, vec![]);
+        for block in [&block, &twin, &other] {
+            assert_eq!(blockchain.insert_block_with_validation(block.clone()).added, vec![block.hash()]);
+        }
+
+        let mut expected = vec![block.hash(), twin.hash()];
+        expected.sort();
+        assert_eq!(blockchain.find_content_duplicates(), vec![expected]);
+    }
+
+    #[test]
+    fn block_info() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip_hash();
+        let block_work = Block::genesis().header.difficulty.work();
+        let block_1 = generate_random_block(&genesis_hash);
+        blockchain.insert_block(block_1.clone());
+        let block_2 = generate_random_block(&block_1.hash());
+        blockchain.insert_block(block_2.clone());
+        let block_3 = generate_random_block(&block_2.hash());
+        blockchain.insert_block(block_3.clone());
+        let fork_block = generate_random_block(&block_1.hash());
+        blockchain.insert_block(fork_block.clone());
+
+        assert_eq!(
+            blockchain.block_info(&block_1.hash()),
+            Some(BlockInfo {
+                height: 1,
+                cumulative_work: 2 * block_work,
+                confirmations: Some(2),
+                num_children: 2,
+            }),
+        );
+        assert_eq!(
+            blockchain.block_info(&fork_block.hash()),
+            Some(BlockInfo {
+                height: 2,
+                cumulative_work: 3 * block_work,
+                confirmations: None,
+                num_children: 0,
+            }),
+        );
+        assert_eq!(blockchain.block_info(&block_3.hash()).unwrap().confirmations, Some(0));
+        assert_eq!(blockchain.block_info(&H256::default()), None);
+    }
+
+    #[test]
+    fn inclusion_commitment() {
+        let mut blockchain = Blockchain::new();
+        let to_addr = H160::from_pubkey(get_deterministic_keypair(9).public_key().as_ref());
+        let transactions: Vec<_> = (0..5)
+            .map(|i| {
+                let sender = get_deterministic_keypair(i);
+                signed_transfer(&sender, to_addr, 1, 0, 0)
+            })
+            .collect();
+        let block = mine_valid_block(&blockchain, transactions.clone());
+        assert_eq!(blockchain.insert_block_with_validation(block.clone()).added, vec![block.hash()]);
+
+        for transaction in &transactions {
+            let commitment = blockchain.inclusion_commitment(&transaction.hash()).unwrap();
+            assert_eq!(commitment.block_hash, block.hash());
+            assert_eq!(commitment.height, 1);
+            // the coinbase is a leaf too
+            assert_eq!(commitment.num_leaves, 6);
+
+            // it can be checked offline, knowing only the commitment
+            let serialized = serde_json::to_string(&commitment).unwrap();
+            let commitment: InclusionCommitment = serde_json::from_str(&serialized).unwrap();
+            assert!(crate::crypto::merkle::verify(
+                &commitment.merkle_root,
+                &commitment.tx_hash,
+                &commitment.merkle_proof,
+                commitment.index,
+                commitment.num_leaves,
+            ));
+        }
+        assert_eq!(blockchain.inclusion_commitment(&H256::default()), None);
+    }
+
+    #[test]
+    fn export_import_mempool() {
+        let mut blockchain = Blockchain::new();
+        let to_addr = H160::from_pubkey(get_deterministic_keypair(9).public_key().as_ref());
+        for i in 0..4 {
+            let sender = get_deterministic_keypair(i);
+            let transaction = signed_transfer(&sender, to_addr, 1, 0, 0);
+            assert_eq!(blockchain.insert_transaction_with_validation(transaction), Ok(()));
+        }
+        let exported = blockchain.export_mempool();
+        assert_eq!(exported.len(), 4);
+
+        // replaying it into another node with the same ICO gives the same
+        // mempool
+        let mut replayed = Blockchain::new();
+        assert_eq!(replayed.import_mempool(exported.clone(), true), 4);
+        let hashes = |transactions: Vec<SignedTransaction>| -> Vec<H256> {
+            transactions.iter().map(SignedTransaction::hash).collect()
+        };
+        assert_eq!(hashes(replayed.export_mempool()), hashes(exported.clone()));
+
+        // without validation, even transactions the node would reject are kept
+        let sender = get_deterministic_keypair(0);
+        let invalid = signed_transfer(&sender, to_addr, 1, 0, 5);
+        let mut replayed = Blockchain::new();
+        assert_eq!(replayed.import_mempool(vec![invalid.clone()], true), 0);
+        assert_eq!(replayed.import_mempool(vec![invalid.clone()], false), 1);
+        assert!(replayed.get_transaction(&invalid.hash()).is_some());
+    }
+
+    #[test]
+    fn recompute_dropped_states() {
+        let mut blockchain = Blockchain::new();
+        blockchain.set_state_retention(Some(2));
+        let genesis_hash = blockchain.tip_hash();
+        let sender = get_deterministic_keypair(0);
+        let to_addr = H160::from_pubkey(get_deterministic_keypair(1).public_key().as_ref());
+        let pay = |nonce| signed_transfer(&sender, to_addr, 5, 0, nonce);
+        let initial_balance = State::ico().get_acc_info(&to_addr).unwrap().balance;
+
+        let b1 = mine_valid_block(&blockchain, vec![pay(0)]);
+        let b2 = mine_valid_block_on(&b1, vec![pay(1)]);
+        let b3 = mine_valid_block_on(&b2, vec![]);
+        for block in [&b1, &b2, &b3] {
+            assert_eq!(blockchain.insert_block_with_validation(block.clone()).added, vec![block.hash()]);
+        }
+        assert!(blockchain.look_up_block(&b1.hash()).unwrap().3.is_none());
+        assert!(blockchain.look_up_block(&b2.hash()).unwrap().3.is_some());
+        assert!(blockchain.look_up_block(&genesis_hash).unwrap().3.is_some());
+
+        // historical balances are recomputed
+        let balance_at = |blockchain: &Blockchain, hash: &H256| {
+            blockchain.compute_state_at(hash).unwrap().get_acc_info(&to_addr).unwrap().balance
+        };
+        assert_eq!(balance_at(&blockchain, &genesis_hash), initial_balance);
+        assert_eq!(balance_at(&blockchain, &b1.hash()), initial_balance + 5);
+        assert_eq!(balance_at(&blockchain, &b3.hash()), initial_balance + 10);
+        assert!(blockchain.compute_state_at(&generate_random_block(&genesis_hash).hash()).is_none());
+
+        // blocks on a fork off a dropped state are still validated against it
+        let fork = mine_valid_block_on(&b1, vec![pay(1), pay(2)]);
+        assert_eq!(blockchain.insert_block_with_validation(fork.clone()).added, vec![fork.hash()]);
+        assert!(blockchain.look_up_block(&fork.hash()).unwrap().3.is_none());
+        assert_eq!(balance_at(&blockchain, &fork.hash()), initial_balance + 15);
+        let bad_fork = mine_valid_block_on(&b1, vec![pay(0)]);
+        assert!(blockchain.insert_block_with_validation(bad_fork).rejected.is_some());
+    }
+
+    #[test]
+    fn save_and_load() {
+        let mut blockchain = Blockchain::new();
+        let sender = get_deterministic_keypair(0);
+        let to_addr = H160::from_pubkey(get_deterministic_keypair(1).public_key().as_ref());
+        let pay = |nonce| signed_transfer(&sender, to_addr, 5, 0, nonce);
+        let b1 = mine_valid_block(&blockchain, vec![pay(0)]);
+        let b2 = mine_valid_block_on(&b1, vec![]);
+        let fork = mine_valid_block_on(&b1, vec![pay(1)]);
+        let b3 = mine_valid_block_on(&b2, vec![]);
+        for block in [&b1, &b2, &fork, &b3] {
+            assert_eq!(blockchain.insert_block_with_validation(block.clone()).added, vec![block.hash()]);
+        }
+
+        let path = std::env::temp_dir().join(format!("blockchain-{}.bin", rand::random::<u64>()));
+        blockchain.save_to_file(&path).unwrap();
+        let loaded = Blockchain::load_from_file(&path, &GenesisConfig::default()).unwrap();
+        assert_eq!(loaded.tip_hash(), b3.hash());
+        assert_eq!(loaded.tip_data().1, 3);
+        assert_eq!(loaded.look_up_block(&fork.hash()).unwrap().1, 2);
+        assert_eq!(loaded.block_info(&b1.hash()).unwrap().num_children, 2);
+        let balance = |blockchain: &Blockchain| blockchain.tip_data().2.get_acc_info(&to_addr).unwrap().balance;
+        assert_eq!(balance(&loaded), balance(&blockchain));
+
+        // a chain can only be loaded onto its own genesis
+        let other_genesis = GenesisConfig { timestamp: 1, ..Default::default() };
+        let err = Blockchain::load_from_file(&path, &other_genesis).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        std::fs::write(&path, b"not a chain").unwrap();
+        assert!(Blockchain::load_from_file(&path, &GenesisConfig::default()).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn most_work_wins() {
+        let mut blockchain = Blockchain::new();
+        let genesis_hash = blockchain.tip_hash();
+        let harder_block_on = |parent: &H256| {
+            let mut block = generate_random_block(parent);
+            block.header.difficulty = H256::with_leading_zeros(16);
+            block
+        };
+
+        // a longer chain of easy blocks
+        let mut easy_tip = genesis_hash;
+        for _ in 0..3 {
+            let block = generate_random_block(&easy_tip);
+            easy_tip = block.hash();
+            blockchain.insert_block(block);
+        }
+        assert_eq!(blockchain.tip_hash(), easy_tip);
+
+        // loses to a shorter chain with more work
+        let hard_block = harder_block_on(&genesis_hash);
+        blockchain.insert_block(hard_block.clone());
+        assert_eq!(blockchain.tip_hash(), hard_block.hash());
+        assert_eq!(blockchain.tip_data().1, 1);
+        assert!(blockchain.cumulative_work(&hard_block.hash()) > blockchain.cumulative_work(&easy_tip));
+
+        // a block can't claim more work than its parent's target requires
+        let mut blockchain = Blockchain::new();
+        blockchain.set_instant_finality(true);
+        let mut block = mine_valid_block(&blockchain, vec![]);
+        block.header.difficulty = H256::with_leading_zeros(16);
+        assert_eq!(blockchain.insert_block_with_validation(block).rejected, Some(RejectReason::WrongDifficulty));
+    }
+
+    #[test]
+    fn transaction_location() {
+        let mut blockchain = Blockchain::new();
+        let sender = get_deterministic_keypair(0);
+        let to_addr = H160::from_pubkey(get_deterministic_keypair(1).public_key().as_ref());
+        let pay = |nonce| signed_transfer(&sender, to_addr, 1, 0, nonce);
+        let confirmed = pay(0);
+        let pending = pay(1);
+        let unknown = pay(2);
+
+        let block = mine_valid_block(&blockchain, vec![confirmed.clone()]);
+        assert!(!blockchain.insert_block_with_validation(block).added.is_empty());
+        assert_eq!(blockchain.insert_transaction_with_validation(pending.clone()), Ok(()));
+
+        assert_eq!(blockchain.transaction_location(&pending.hash()), Some(TransactionLocation::Mempool));
+        assert_eq!(blockchain.transaction_location(&confirmed.hash()), Some(TransactionLocation::Block));
+        assert_eq!(blockchain.transaction_location(&unknown.hash()), None);
+
+        // mined transactions can still be found, though not in the mempool
+        assert!(blockchain.get_transaction(&confirmed.hash()).is_none());
+        assert_eq!(blockchain.find_transaction(&confirmed.hash()).map(Hashable::hash), Some(confirmed.hash()));
+        assert_eq!(blockchain.find_transaction(&pending.hash()).map(Hashable::hash), Some(pending.hash()));
+        assert!(blockchain.find_transaction(&unknown.hash()).is_none());
+    }
+
+    #[test]
+    fn bounded_orphanage() {
+        let mut blockchain = Blockchain::new();
+        blockchain.set_orphanage_capacity(10);
+        let orphans: Vec<Block> = (0..25).map(|_| generate_random_mined_block(&H256::from([1; 32]))).collect();
+
+        // sending the same orphan again doesn't take up more room
+        for _ in 0..25 {
+            let outcome = blockchain.insert_block_with_validation(orphans[0].clone());
+            assert_eq!(outcome.orphaned, Some(H256::from([1; 32])));
+        }
+        assert_eq!(blockchain.num_orphans(), 1);
+        // and one that doesn't meet its own target isn't let in at all
+        let mut unmined = orphans[1].clone();
+        while unmined.hash() <= unmined.header.difficulty {
+            unmined.header.nonce += 1;
+        }
+        assert_eq!(blockchain.insert_block_with_validation(unmined).rejected, Some(RejectReason::InsufficientWork));
+        assert_eq!(blockchain.num_orphans(), 1);
+
+        for orphan in &orphans {
+            blockchain.insert_block_with_validation(orphan.clone());
+            assert!(blockchain.num_orphans() <= 10);
+        }
+        assert_eq!(blockchain.num_orphans(), 10);
+
+        // the newest ones are kept
+        let kept: Vec<H256> = blockchain.orphanage.values().flatten().map(Block::hash).collect();
+        for orphan in &orphans[15..] {
+            assert!(kept.contains(&orphan.hash()));
+        }
+
+        // orphans that were connected don't count towards the capacity, and
+        // are eventually forgotten
+        let mut blockchain = Blockchain::new();
+        blockchain.set_orphanage_capacity(2);
+        let mut tip = Block::genesis();
+        for _ in 0..20 {
+            let parent = mine_valid_block_on(&tip, vec![]);
+            let child = mine_valid_block_on(&parent, vec![]);
+            assert!(blockchain.insert_block_with_validation(child.clone()).orphaned.is_some());
+            assert_eq!(blockchain.insert_block_with_validation(parent).added.len(), 2);
+            tip = child;
+        }
+        assert_eq!(blockchain.tip_hash(), tip.hash());
+        assert_eq!(blockchain.num_orphans(), 0);
+        assert!(blockchain.orphan_order.len() <= 4);
+    }
+
+    #[test]
+    fn check_header_first() {
+        let mut blockchain = Blockchain::new();
+        // drop the tip's parent's state, so that checking a block on it would
+        // mean replaying the chain
+        blockchain.set_state_retention(Some(1));
+        let parent = mine_valid_block(&blockchain, vec![]);
+        let tip = mine_valid_block_on(&parent, vec![]);
+        for block in [&parent, &tip] {
+            assert!(!blockchain.insert_block_with_validation(block.clone()).added.is_empty());
+        }
+        assert!(blockchain.look_up_block(&parent.hash()).unwrap().3.is_none());
+
+        // a block whose transactions are all invalid (one isn't signed by its
+        // sender, and spends more than anyone has), but whose proof of work
+        // is checked first
+        let sender = get_deterministic_keypair(0);
+        let from_addr = H160::from_pubkey(sender.public_key().as_ref());
+        let transaction = SignedTransaction::from_raw(
+            RawTransaction { from_addr, to_addr: from_addr, value: u64::MAX, fee: 0, nonce: 0, memo: vec![], valid_since: None },
+            &get_deterministic_keypair(1),
+        );
+        let mut block = mine_valid_block_on(&parent, vec![transaction]);
+        while block.hash() <= block.header.difficulty {
+            block.header.nonce += 1;
+        }
+        assert_eq!(blockchain.insert_block_with_validation(block.clone()).rejected, Some(RejectReason::InsufficientWork));
+
+        // only a valid header gets as far as the transactions
+        while block.hash() > block.header.difficulty {
+            block.header.nonce += 1;
+        }
+        assert_eq!(blockchain.insert_block_with_validation(block).rejected, Some(RejectReason::InvalidSignature));
+    }
+
+    #[test]
+    fn retarget_difficulty() {
+        const TARGET_INTERVAL: u64 = 1000;
+        let default_difficulty = Block::genesis().header.difficulty;
+        // a chain whose blocks take `interval` each, with the required
+        // difficulty
+        let build_chain = |blockchain: &mut Blockchain, interval: u64, num_blocks: usize| {
+            let mut parent = blockchain.tip_data().0.clone();
+            for _ in 0..num_blocks {
+                let mut block = mine_valid_block_on(&parent, vec![]);
+                block.header.timestamp = parent.header.timestamp + interval as u128;
+                block.header.difficulty = blockchain.required_difficulty(&parent.hash()).unwrap();
+                while block.hash() > block.header.difficulty {
+                    block.header.nonce += 1;
+                }
+                assert_eq!(blockchain.insert_block_with_validation(block.clone()).added, vec![block.hash()]);
+                parent = block;
+            }
+        };
+        let config = GenesisConfig { target_block_interval: Some(TARGET_INTERVAL), ..Default::default() };
+
+        // slow blocks make the next period easier, only at the retarget
+        let mut blockchain = Blockchain::with_genesis(&config);
+        build_chain(&mut blockchain, 2 * TARGET_INTERVAL, RETARGET_INTERVAL as usize - 2);
+        assert_eq!(blockchain.required_difficulty(&blockchain.tip_hash()), Some(default_difficulty));
+        build_chain(&mut blockchain, 2 * TARGET_INTERVAL, 1);
+        let easier = blockchain.required_difficulty(&blockchain.tip_hash()).unwrap();
+        assert_eq!(easier, default_difficulty.scale(2, 1));
+
+        // a block that keeps the old difficulty is rejected
+        let mut stale = mine_valid_block(&blockchain, vec![]);
+        stale.header.timestamp += 2 * TARGET_INTERVAL as u128;
+        while stale.hash() > stale.header.difficulty {
+            stale.header.nonce += 1;
+        }
+        assert_eq!(blockchain.insert_block_with_validation(stale).rejected, Some(RejectReason::WrongDifficulty));
+        build_chain(&mut blockchain, 2 * TARGET_INTERVAL, 1);
+        assert_eq!(blockchain.tip_data().0.header.difficulty, easier);
+        assert_eq!(blockchain.required_difficulty(&blockchain.tip_hash()), Some(easier));
+
+        // fast blocks make it harder, but only by so much at once
+        let mut blockchain = Blockchain::with_genesis(&config);
+        build_chain(&mut blockchain, 1, RETARGET_INTERVAL as usize - 1);
+        let harder = blockchain.required_difficulty(&blockchain.tip_hash()).unwrap();
+        assert_eq!(harder, default_difficulty.scale(1, MAX_RETARGET_FACTOR));
+
+        // segments are checked against the same targets
+        let mut blocks = vec![];
+        let mut parent = blockchain.tip_data().0.clone();
+        for difficulty in [harder, default_difficulty] {
+            let mut block = mine_valid_block_on(&parent, vec![]);
+            block.header.difficulty = difficulty;
+            while block.hash() > block.header.difficulty {
+                block.header.nonce += 1;
+            }
+            parent = block.clone();
+            blocks.push(block);
+        }
+        assert_eq!(blockchain.validate_chain(&blocks), Err((1, RejectReason::WrongDifficulty)));
+        assert_eq!(blockchain.validate_chain(&blocks[..1]), Ok(()));
+
+        // without a target interval, the difficulty never changes
+        let mut blockchain = Blockchain::new();
+        build_chain(&mut blockchain, 1, 2 * RETARGET_INTERVAL as usize);
+        assert_eq!(blockchain.tip_data().0.header.difficulty, default_difficulty);
+    }
+
+    #[test]
+    fn coinbase_reward() {
+        let mut blockchain = Blockchain::new();
+        blockchain.set_state_retention(Some(1));
+        let miner_addr: H160 = [7; 20].into();
+        let sender = get_deterministic_keypair(0);
+        let pay = signed_transfer(&sender, miner_addr, 5, 0, 0);
+
+        // the coinbase goes first and credits the miner
+        let coinbase = SignedTransaction::coinbase(miner_addr, BLOCK_REWARD, 1);
+        let b1 = mine_valid_block(&blockchain, vec![coinbase.clone(), pay.clone()]);
+        assert_eq!(blockchain.insert_block_with_validation(b1.clone()).added, vec![b1.hash()]);
+        let balance = |state: &State| state.get_acc_info(&miner_addr).unwrap().balance;
+        assert_eq!(balance(blockchain.tip_data().2), BLOCK_REWARD + 5);
+
+        // and still does when the state is recomputed
+        let b2 = mine_valid_block_on(&b1, vec![]);
+        assert_eq!(blockchain.insert_block_with_validation(b2).added.len(), 1);
+        assert!(blockchain.look_up_block(&b1.hash()).unwrap().3.is_none());
+        assert_eq!(balance(&blockchain.compute_state_at(&b1.hash()).unwrap()), BLOCK_REWARD + 5);
+
+        // every block needs one
+        let mut without = mine_valid_block(&Blockchain::new(), vec![pay.clone()]);
+        without.content.transactions.remove(0);
+        without.header.merkle_root = without.content.merkle_root();
+        while without.hash() > without.header.difficulty {
+            without.header.nonce += 1;
+        }
+        assert_eq!(Blockchain::new().insert_block_with_validation(without).rejected, Some(RejectReason::MissingCoinbase));
+
+        // but only one coinbase, only first, and only up to the reward
+        let rejected = |transactions: Vec<SignedTransaction>| {
+            let mut blockchain = Blockchain::new();
+            let block = mine_valid_block(&blockchain, transactions);
+            blockchain.insert_block_with_validation(block).rejected
+        };
+        let second = SignedTransaction::coinbase(miner_addr, 1, 2);
+        assert_eq!(rejected(vec![coinbase.clone(), second]), Some(RejectReason::MisplacedCoinbase));
+        assert_eq!(rejected(vec![pay, coinbase]), Some(RejectReason::MisplacedCoinbase));
+        let greedy = SignedTransaction::coinbase(miner_addr, BLOCK_REWARD + 1, 1);
+        assert_eq!(rejected(vec![greedy]), Some(RejectReason::ExcessiveCoinbase));
+
+        // coinbases can't be relayed on their own
+        let unmined = SignedTransaction::coinbase(miner_addr, BLOCK_REWARD, 3);
+        assert_eq!(blockchain.insert_verified_transaction(unmined), Err(RejectReason::MisplacedCoinbase));
+    }
+
+    #[test]
+    fn create_account() {
+        let mut blockchain = Blockchain::new();
+        let funder = get_deterministic_keypair(0);
+        let new_addr: H160 = [7; 20].into();
+        assert!(blockchain.tip_data().2.get_acc_info(&new_addr).is_none());
+
+        let funding_tx = blockchain.fund_account(&funder, new_addr, 100).unwrap();
+        assert_eq!(blockchain.transaction_location(&funding_tx), Some(TransactionLocation::Mempool));
+        assert_eq!(blockchain.account_proof(&funding_tx), None);
+        // the funder can't fund another account until this one is mined
+        assert_eq!(blockchain.fund_account(&funder, [8; 20].into(), 100), Err(RejectReason::DoubleSpend));
+
+        let transactions: Vec<_> = blockchain.mempool_transactions().map(|(_, transaction)| transaction.clone()).collect();
+        let block = mine_valid_block(&blockchain, transactions);
+        assert_eq!(blockchain.insert_block_with_validation(block.clone()).added, vec![block.hash()]);
+        assert_eq!(blockchain.tip_data().2.get_acc_info(&new_addr).unwrap().balance, 100);
+
+        // the proof names the account and checks out against the block
+        let proof = blockchain.account_proof(&funding_tx).unwrap();
+        assert_eq!((proof.addr, proof.value), (new_addr, 100));
+        assert_eq!(proof.funding.merkle_root, block.header.merkle_root);
+        assert!(crate::crypto::merkle::verify(
+            &proof.funding.merkle_root,
+            &funding_tx,
+            &proof.funding.merkle_proof,
+            proof.funding.index,
+            proof.funding.num_leaves,
+        ));
+
+        // now the funder can fund another account
+        let next_tx = blockchain.fund_account(&funder, [8; 20].into(), 100).unwrap();
+        assert_ne!(next_tx, funding_tx);
+    }
+
+    #[test]
+    fn locate_headers() {
+        let mut blockchain = Blockchain::new();
+        let mut hashes = vec![blockchain.tip_hash()];
+        for _ in 0..30 {
+            let block = generate_random_block(hashes.last().unwrap());
+            hashes.push(block.hash());
+            blockchain.insert_block(block);
+        }
+
+        // dense near the tip, then sparser, down to genesis
+        let locator = blockchain.block_locator();
+        let heights: Vec<usize> = locator.iter().map(|hash| hashes.iter().position(|h| h == hash).unwrap()).collect();
+        assert_eq!(heights, vec![30, 29, 28, 27, 26, 25, 24, 23, 22, 21, 19, 15, 7, 0]);
+
+        // headers after the first block on the longest chain
+        let fork = generate_random_block(&hashes[5]);
+        blockchain.insert_block(fork.clone());
+        let headers = blockchain.headers_after(&[fork.hash(), hashes[10], hashes[3]], 3);
+        let header_hashes: Vec<H256> = headers.iter().map(Header::hash).collect();
+        assert_eq!(header_hashes, hashes[11..14].to_vec());
+        assert_eq!(blockchain.headers_after(&[], 100).len(), 30);
+        assert!(blockchain.headers_after(&[hashes[30]], 100).is_empty());
+    }
+
+    #[test]
+    fn prune_unreachable_orphans() {
+        let mut blockchain = Blockchain::new();
+
+        // a chain of orphans descending from an invalid block
+        let mut invalid = mine_valid_block(&blockchain, vec![]);
+        invalid.header.timestamp = 0;
+        let doomed_child = mine_valid_block_on(&invalid, vec![]);
+        let doomed_grandchild = mine_valid_block_on(&doomed_child, vec![]);
+        // and orphans still waiting for a valid parent
+        let parent = mine_valid_block(&blockchain, vec![]);
+        let child = mine_valid_block_on(&parent, vec![]);
+        for orphan in [&doomed_grandchild, &doomed_child, &child] {
+            assert!(blockchain.insert_block_with_validation(orphan.clone()).orphaned.is_some());
+        }
+        assert!(blockchain.insert_block_with_validation(invalid).rejected.is_some());
+
+        assert_eq!(blockchain.prune_unreachable_orphans(), 2);
+        assert_eq!(blockchain.num_orphans(), 1);
+        assert_eq!(blockchain.insert_block_with_validation(parent).added, vec![child.header.parent, child.hash()]);
+
+        // orphans that wait too long are given up on, with their descendants
+        blockchain.set_orphan_max_age(Duration::from_millis(20));
+        let old = mine_valid_block_on(&mine_valid_block(&blockchain, vec![]), vec![]);
+        let old_child = mine_valid_block_on(&old, vec![]);
+        blockchain.insert_block_with_validation(old.clone());
+        std::thread::sleep(Duration::from_millis(30));
+        let young = generate_random_mined_block(&H256::from([1; 32]));
+        blockchain.insert_block_with_validation(old_child);
+        blockchain.insert_block_with_validation(young.clone());
+        assert_eq!(blockchain.prune_unreachable_orphans(), 2);
+        let remaining: Vec<H256> = blockchain.orphanage.values().flatten().map(Block::hash).collect();
+        assert_eq!(remaining, vec![young.hash()]);
+        assert_eq!(blockchain.orphan_order.len(), 1);
+    }
+
     #[cfg(feature = "my-tests")]
     mod my_tests {
         use super::*;
@@ -1,4 +1,5 @@
 use serde::{Serialize,Deserialize};
+use std::convert::TryInto;
 
 /// A 160-bit public address.
 #[derive(Eq, PartialEq, PartialOrd, Ord, Serialize, Deserialize, Clone, Hash, Default, Copy)]
@@ -22,6 +23,19 @@ impl std::fmt::Display for H160 {
     }
 }
 
+impl std::str::FromStr for H160 {
+    type Err = String;
+
+    /// Parse an address from 40 hex digits
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(s).map_err(|e| e.to_string())?;
+        let bytes: [u8; 20] = bytes
+            .try_into()
+            .map_err(|_| "expected 20 bytes".to_string())?;
+        Ok(bytes.into())
+    }
+}
+
 impl std::fmt::Debug for H160 {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
@@ -56,3 +70,16 @@ impl std::convert::From<[u8; 20]> for H160 {
         H160(input)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_address() {
+        let addr: H160 = [0xab; 20].into();
+        assert_eq!(addr.to_string().parse::<H160>(), Ok(addr));
+        assert!("abab".parse::<H160>().is_err());
+        assert!("not hex".parse::<H160>().is_err());
+    }
+}
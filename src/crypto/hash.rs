@@ -24,7 +24,7 @@ impl Hashable for str {
 }
 
 impl H256 {
-    pub fn with_leading_zeros(num_zeroes: u8) -> Self {
+    pub const fn with_leading_zeros(num_zeroes: u8) -> Self {
         let mut remaining_zeroes = num_zeroes;
         let mut hash = [0xff; 32];
         let mut current_byte_index = 0;
@@ -34,7 +34,70 @@ impl H256 {
             current_byte_index += 1;
         }
         hash[current_byte_index] >>= remaining_zeroes;
-        hash.into()
+        H256(hash)
+    }
+
+    /// Interpreting this hash as a proof-of-work target, returns the expected
+    /// number of hashes needed to find a block at or below it, i.e.
+    /// 2^256 / (target + 1). Saturates at `u128::MAX`
+    pub fn work(&self) -> u128 {
+        (2f64.powi(256) / (self.as_f64() + 1.0)) as u128
+    }
+
+    /// Interpreting this hash as a proof-of-work target, returns how many
+    /// times harder it is to meet than the easiest possible target (all
+    /// ones), which has difficulty 1
+    pub fn to_difficulty(&self) -> f64 {
+        H256([0xff; 32]).as_f64() / self.as_f64()
+    }
+
+    /// Interpreting this hash as a big endian integer, multiply it by
+    /// `numerator / denominator`, rounding down. Saturates at all ones
+    pub fn scale(&self, numerator: u64, denominator: u64) -> H256 {
+        assert!(denominator > 0, "can't divide by zero");
+        // little endian 64-bit limbs, with room for the product to overflow
+        let mut limbs = [0u64; 5];
+        for (limb, chunk) in limbs.iter_mut().zip(self.0.rchunks(8)) {
+            *limb = u64::from_be_bytes(chunk.try_into().unwrap());
+        }
+        let mut carry: u128 = 0;
+        for limb in limbs.iter_mut() {
+            let product = *limb as u128 * numerator as u128 + carry;
+            *limb = product as u64;
+            carry = product >> 64;
+        }
+        let mut remainder: u128 = 0;
+        for limb in limbs.iter_mut().rev() {
+            let dividend = (remainder << 64) | *limb as u128;
+            *limb = (dividend / denominator as u128) as u64;
+            remainder = dividend % denominator as u128;
+        }
+        if limbs[4] != 0 {
+            return H256([0xff; 32]);
+        }
+        let mut bytes = [0u8; 32];
+        for (chunk, limb) in bytes.rchunks_mut(8).zip(&limbs) {
+            chunk.copy_from_slice(&limb.to_be_bytes());
+        }
+        H256(bytes)
+    }
+
+    /// The value of this hash as a big endian integer, approximated
+    fn as_f64(&self) -> f64 {
+        self.0.iter().fold(0f64, |acc, &byte| acc * 256.0 + byte as f64)
+    }
+}
+
+impl std::str::FromStr for H256 {
+    type Err = String;
+
+    /// Parse a hash from 64 hex digits
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(s).map_err(|e| e.to_string())?;
+        let bytes: [u8; 32] = bytes
+            .try_into()
+            .map_err(|_| "expected 32 bytes".to_string())?;
+        Ok(bytes.into())
     }
 }
 
@@ -128,11 +191,42 @@ impl PartialOrd for H256 {
     }
 }
 
-#[cfg(any(test, test_utilities))]
+#[cfg(test)]
 pub mod tests {
     use super::H256;
     use rand::Rng;
 
+    #[test]
+    fn to_difficulty() {
+        assert_eq!(H256::with_leading_zeros(0).to_difficulty(), 1.0);
+        let easy = H256::with_leading_zeros(4).to_difficulty();
+        let hard = H256::with_leading_zeros(12).to_difficulty();
+        assert!(1.0 < easy && easy < hard);
+        assert!((hard - 4096.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn scale() {
+        let target = H256::with_leading_zeros(8);
+        assert_eq!(target.scale(1, 1), target);
+        assert_eq!(target.scale(3, 3), target);
+        assert_eq!(target.scale(1, 4), H256::with_leading_zeros(10));
+        let mut power_of_two = [0u8; 32];
+        power_of_two[1] = 1;
+        let mut doubled = [0u8; 32];
+        doubled[1] = 2;
+        assert_eq!(H256::from(power_of_two).scale(2, 1), H256::from(doubled));
+        // carries across limbs
+        let mut one = [0u8; 32];
+        one[31] = 1;
+        let mut shifted = [0u8; 32];
+        shifted[23] = 1;
+        assert_eq!(H256::from(one).scale(u64::MAX, 1).scale(1, u64::MAX), H256::from(one));
+        assert_eq!(H256::from(one).scale(1 << 32, 1).scale(1 << 32, 1), H256::from(shifted));
+        // saturates instead of overflowing
+        assert_eq!(target.scale(1 << 9, 1), H256::from([0xff; 32]));
+    }
+
     pub fn generate_random_hash() -> H256 {
         let mut rng = rand::thread_rng();
         let random_bytes: Vec<u8> = (0..32).map(|_| rng.gen()).collect();
@@ -1,9 +1,12 @@
 use super::hash::{Hashable, H256};
+use std::collections::HashMap;
 
 #[derive(Debug, Default, Clone)]
 struct MerkleTreeNode {
     hash: H256,
     lhs: Option<Box<MerkleTreeNode>>,
+    /// `None` for the last node of a level with an odd number of nodes, which
+    /// is paired with a copy of its left child
     rhs: Option<Box<MerkleTreeNode>>,
 }
 
@@ -12,6 +15,7 @@ struct MerkleTreeNode {
 pub struct MerkleTree {
     root: Box<MerkleTreeNode>,
     num_aggregations: usize, // one less than the number of levels
+    num_leaves: usize,
 }
 
 /// Given the hash of the left and right nodes, compute the hash of the parent node.
@@ -21,11 +25,15 @@ fn hash_children(lhs: &H256, rhs: &H256) -> H256 {
 }
 
 impl MerkleTree {
+    /// Build the tree over the hashes of the data. A tree over no data has
+    /// the all-zero hash as its root, and no leaves to prove
     pub fn new<T>(data: &[T]) -> Self
     where
         T: Hashable,
     {
-        assert!(!data.is_empty());
+        if data.is_empty() {
+            return MerkleTree::default();
+        }
 
         // turn each item into the leaf nodes
         let mut nodes: Vec<_> = data
@@ -48,7 +56,7 @@ impl MerkleTree {
                 // helper function that takes the Box<MerkleTreeNode> at an
                 // index that might be out of bounds
                 fn get_node(
-                    vec: &mut Vec<Option<Box<MerkleTreeNode>>>,
+                    vec: &mut [Option<Box<MerkleTreeNode>>],
                     index: usize,
                 ) -> Option<Box<MerkleTreeNode>> {
                     vec.get_mut(index).unwrap_or(&mut None).take()
@@ -60,19 +68,20 @@ impl MerkleTree {
                     break 'single_pass;
                 };
 
-                // take either the next node or clone the left node
-                let rhs = get_node(&mut nodes, i * 2 + 1).unwrap_or_else(|| lhs.clone());
+                // take the next node, if there is one to pair the left node
+                // with rather than a copy of itself
+                let rhs = get_node(&mut nodes, i * 2 + 1);
 
                 // replace the left node's spot with a parent that has both
                 // lhs and rhs as children
                 nodes[i] = Some(Box::new(MerkleTreeNode {
-                    hash: hash_children(&lhs.hash, &rhs.hash),
+                    hash: hash_children(&lhs.hash, &rhs.as_ref().unwrap_or(&lhs).hash),
                     lhs: Some(lhs),
-                    rhs: Some(rhs),
+                    rhs,
                 }));
             }
 
-            num_remaining_nodes = (num_remaining_nodes + 1) / 2;
+            num_remaining_nodes = num_remaining_nodes.div_ceil(2);
             num_aggregations += 1;
         }
 
@@ -80,6 +89,7 @@ impl MerkleTree {
         MerkleTree {
             root: nodes[0].take().expect("there remains exactly one node"),
             num_aggregations,
+            num_leaves: data.len(),
         }
     }
 
@@ -87,8 +97,45 @@ impl MerkleTree {
         self.root.hash
     }
 
+    pub fn num_leaves(&self) -> usize {
+        self.num_leaves
+    }
+
+    /// Add a leaf after the existing ones, giving the same tree as building
+    /// it over all the data with `new`. Only the nodes on the path from the
+    /// new leaf to the root are touched, so this takes O(log n)
+    pub fn append<T: Hashable>(&mut self, item: &T) {
+        let leaf_hash = item.hash();
+        if self.num_leaves == 0 {
+            *self = MerkleTree {
+                root: Box::new(MerkleTreeNode { hash: leaf_hash, lhs: None, rhs: None }),
+                num_aggregations: 0,
+                num_leaves: 1,
+            };
+            return;
+        }
+
+        let index = self.num_leaves;
+        if index == 1 << self.num_aggregations {
+            // the tree is full, so the old root becomes the left child of a
+            // new one, whose right subtree holds only the new leaf
+            let rhs = lone_leaf_subtree(leaf_hash, self.num_aggregations);
+            let lhs = std::mem::take(&mut self.root);
+            *self.root = MerkleTreeNode {
+                hash: hash_children(&lhs.hash, &rhs.hash),
+                lhs: Some(lhs),
+                rhs: Some(rhs),
+            };
+            self.num_aggregations += 1;
+        } else {
+            append_at(&mut self.root, self.num_aggregations, index, leaf_hash);
+        }
+        self.num_leaves += 1;
+    }
+
     /// Returns the Merkle Proof of data at index i. The closest sibling hash
-    /// is at the end of the returned array; i.e. the proof goes top-down
+    /// is at the start of the returned array; i.e. the proof goes bottom-up,
+    /// in the order `verify` hashes it in
     pub fn proof(&self, index: usize) -> Vec<H256> {
         // the binary representation of the index, read from MSB to LSB, serves
         // as sequence of instructions to locate the `index`th leaf, where 0
@@ -104,35 +151,83 @@ impl MerkleTree {
 
         let mut result = Vec::new();
         let mut current_node = &self.root;
-        for direction in directions {
+        // the directions were found LSB first, but the MSB says which way to
+        // go from the root
+        for direction in directions.into_iter().rev() {
             const ERROR_MSG: &str =
                 "can traverse through `self.num_aggregations` levels; the tree is full";
+            let lhs = current_node.lhs.as_ref().expect(ERROR_MSG);
+            // a missing right child stands for a copy of the left one
+            let rhs = current_node.rhs.as_ref().unwrap_or(lhs);
             if direction {
                 // go right
-                result.push(current_node.lhs.as_ref().expect(ERROR_MSG).hash);
-                current_node = current_node.rhs.as_ref().expect(ERROR_MSG);
+                result.push(lhs.hash);
+                current_node = rhs;
             } else {
                 // go left
-                result.push(current_node.rhs.as_ref().expect(ERROR_MSG).hash);
-                current_node = current_node.lhs.as_ref().expect(ERROR_MSG);
+                result.push(rhs.hash);
+                current_node = lhs;
             };
         }
+        // the siblings were found from the root down
+        result.reverse();
         result
     }
 }
 
+/// Build the subtree of the given height whose only leaf has the given hash,
+/// i.e. where every node is paired with a copy of itself
+fn lone_leaf_subtree(leaf_hash: H256, height: usize) -> Box<MerkleTreeNode> {
+    let mut node = Box::new(MerkleTreeNode { hash: leaf_hash, lhs: None, rhs: None });
+    for _ in 0..height {
+        node = Box::new(MerkleTreeNode {
+            hash: hash_children(&node.hash, &node.hash),
+            lhs: Some(node),
+            rhs: None,
+        });
+    }
+    node
+}
+
+/// Put a new leaf at `index` (counted within the subtree of the given height
+/// under `node`), which must be just past the last leaf, and rehash the nodes
+/// above it
+fn append_at(node: &mut MerkleTreeNode, height: usize, index: usize, leaf_hash: H256) {
+    const ERROR_MSG: &str = "the new leaf's position is within the tree, so there are nodes above it";
+    let half = 1 << (height - 1);
+    if index & half != 0 {
+        match &mut node.rhs {
+            Some(rhs) => append_at(rhs, height - 1, index & !half, leaf_hash),
+            // the right subtree only held a copy of the left one until now
+            None => node.rhs = Some(lone_leaf_subtree(leaf_hash, height - 1)),
+        }
+    } else {
+        append_at(node.lhs.as_mut().expect(ERROR_MSG), height - 1, index, leaf_hash);
+    }
+    let lhs = node.lhs.as_ref().expect(ERROR_MSG);
+    node.hash = hash_children(&lhs.hash, &node.rhs.as_ref().unwrap_or(lhs).hash);
+}
+
 /// Verify that the datum hash with a vector of proofs will produce the Merkle root. Also need the
-/// index of datum and `leaf_size`, the total number of leaves.
+/// index of datum and `leaf_size`, the total number of leaves. The proof goes bottom-up, as
+/// `MerkleTree::proof` makes it. Where a level has an odd number of nodes, the last is paired
+/// with a copy of itself, so its proof holds its own hash at that level. Malformed input, such
+/// as an index out of range or a proof that doesn't have exactly one hash per level of the
+/// tree, is rejected.
 pub fn verify(
     root_hash: &H256,
     datum_hash: &H256,
     proof: &[H256],
     index: usize,
-    _num_leaves: usize,
+    num_leaves: usize,
 ) -> bool {
+    if index >= num_leaves || proof.len() != tree_depth(num_leaves) {
+        return false;
+    }
+
     let mut bit_path = index;
     let mut current_hash = *datum_hash;
-    for sibling_hash in proof.iter().rev() {
+    for sibling_hash in proof {
         let direction = bit_path & 1 == 1; // true iff the current node is a right child
         bit_path >>= 1;
 
@@ -147,6 +242,62 @@ pub fn verify(
     *root_hash == current_hash
 }
 
+/// Verify several data of the same tree at once. Each item is a datum hash,
+/// its index and its proof, as `verify` takes them. Nodes shared by the paths
+/// of several items are hashed only once, but every hash of every proof is
+/// still checked against them, so the batch is accepted exactly when each item
+/// would pass `verify` on its own.
+pub fn verify_batch(root_hash: &H256, items: &[(H256, usize, Vec<H256>)], num_leaves: usize) -> bool {
+    let depth = tree_depth(num_leaves);
+    if items
+        .iter()
+        .any(|(_, index, proof)| *index >= num_leaves || proof.len() != depth)
+    {
+        return false;
+    }
+
+    // the hashes known so far at the current level, by position
+    let mut known: HashMap<usize, H256> = HashMap::new();
+    for (datum_hash, index, _) in items {
+        if *known.entry(*index).or_insert(*datum_hash) != *datum_hash {
+            return false;
+        }
+    }
+    for level in 0..depth {
+        let mut parents: HashMap<usize, H256> = HashMap::new();
+        for (_, index, proof) in items {
+            let position = index >> level;
+            // the sibling may be known from another item's path (or proof);
+            // either way they have to agree
+            let sibling_hash = *known.entry(position ^ 1).or_insert(proof[level]);
+            if sibling_hash != proof[level] {
+                return false;
+            }
+            if parents.contains_key(&(position >> 1)) {
+                continue;
+            }
+            let current_hash = known[&position];
+            let parent_hash = if position & 1 == 1 {
+                hash_children(&sibling_hash, &current_hash)
+            } else {
+                hash_children(&current_hash, &sibling_hash)
+            };
+            parents.insert(position >> 1, parent_hash);
+        }
+        known = parents;
+    }
+    known.values().all(|hash| hash == root_hash)
+}
+
+/// The number of levels above the leaves in a tree with the given number of
+/// leaves, i.e. ceil(log2(num_leaves))
+fn tree_depth(num_leaves: usize) -> usize {
+    match num_leaves {
+        0 | 1 => 0,
+        n => (usize::BITS - (n - 1).leading_zeros()) as usize,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -213,18 +364,29 @@ mod tests {
         let merkle_tree = MerkleTree::new(&input_data);
         let proof = merkle_tree.proof(5);
 
-        // We accept the proof in either the top-down or bottom-up order; you should stick to either of them.
-        let expected_proof_bottom_up: Vec<H256> = vec![
+        // proofs go bottom-up, closest sibling first
+        let expected_proof: Vec<H256> = vec![
             (hex!("c8c37c89fcc6ee7f5e8237d2b7ed8c17640c154f8d7751c774719b2b82040c76")).into(),
             (hex!("bada70a695501195fb5ad950a5a41c02c0f9c449a918937267710a0425151b77")).into(),
             (hex!("1e28fb71415f259bd4b0b3b98d67a1240b4f3bed5923aa222c5fdbd97c8fb002")).into(),
         ];
-        let expected_proof_top_down: Vec<H256> = vec![
-            (hex!("1e28fb71415f259bd4b0b3b98d67a1240b4f3bed5923aa222c5fdbd97c8fb002")).into(),
-            (hex!("bada70a695501195fb5ad950a5a41c02c0f9c449a918937267710a0425151b77")).into(),
-            (hex!("c8c37c89fcc6ee7f5e8237d2b7ed8c17640c154f8d7751c774719b2b82040c76")).into(),
-        ];
-        assert!(proof == expected_proof_bottom_up || proof == expected_proof_top_down);
+        assert_eq!(proof, expected_proof);
+    }
+
+    #[test]
+    fn proof_six_leaves() {
+        // leaf 5 is the last of an odd-sized pair of pairs, so its parent is
+        // paired with a copy of itself one level up
+        let input_data: Vec<H256> = gen_merkle_tree_large!();
+        let input_data = &input_data[..6];
+        let merkle_tree = MerkleTree::new(input_data);
+        let proof = merkle_tree.proof(5);
+        let leaf_4 = input_data[4].hash();
+        let leaf_5 = input_data[5].hash();
+        let parent = hash_children(&leaf_4, &leaf_5);
+        assert_eq!(&proof[..2], &[leaf_4, parent]);
+        assert!(verify(&merkle_tree.root(), &leaf_5, &proof, 5, 6));
+        assert!(!verify(&merkle_tree.root(), &leaf_5, &proof, 4, 6));
     }
 
     #[test]
@@ -241,6 +403,115 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn verifying_every_index() {
+        let input_data: Vec<H256> = gen_merkle_tree_large!();
+        for num_leaves in 1..=input_data.len() {
+            let input_data = &input_data[..num_leaves];
+            let merkle_tree = MerkleTree::new(input_data);
+            for (index, datum) in input_data.iter().enumerate() {
+                let proof = merkle_tree.proof(index);
+                assert!(verify(&merkle_tree.root(), &datum.hash(), &proof, index, num_leaves));
+            }
+        }
+    }
+
+    #[test]
+    fn verifying_last_leaf() {
+        // the last leaf of these trees gets paired with a copy of itself, or
+        // sits under a node that does
+        let input_data: Vec<H256> = gen_merkle_tree_large!();
+        for num_leaves in [3, 5, 6, 7] {
+            let input_data = &input_data[..num_leaves];
+            let merkle_tree = MerkleTree::new(input_data);
+            let index = num_leaves - 1;
+            let proof = merkle_tree.proof(index);
+            assert_eq!(proof.len(), tree_depth(num_leaves));
+            assert!(verify(&merkle_tree.root(), &input_data[index].hash(), &proof, index, num_leaves));
+        }
+    }
+
+    #[test]
+    fn verifying_batch() {
+        let input_data: Vec<H256> = gen_merkle_tree_large!();
+        let input_data = &input_data[..7];
+        let merkle_tree = MerkleTree::new(input_data);
+        let item = |index: usize| (input_data[index].hash(), index, merkle_tree.proof(index));
+        let items: Vec<_> = [0, 1, 4, 6].iter().map(|&index| item(index)).collect();
+        assert!(verify_batch(&merkle_tree.root(), &items, 7));
+        assert!(verify_batch(&merkle_tree.root(), &items[3..], 7));
+
+        // one bad item spoils the batch, even where its path is shared
+        let mut bad_datum = items.clone();
+        bad_datum[1].0 = input_data[2].hash();
+        assert!(!verify_batch(&merkle_tree.root(), &bad_datum, 7));
+        let mut bad_proof = items.clone();
+        bad_proof[0].2[1] = H256::default();
+        assert!(!verify_batch(&merkle_tree.root(), &bad_proof, 7));
+        let mut bad_index = items.clone();
+        bad_index[2].1 = 7;
+        assert!(!verify_batch(&merkle_tree.root(), &bad_index, 7));
+        assert!(!verify_batch(&merkle_tree.root(), &items, 9));
+    }
+
+    #[test]
+    fn appending() {
+        let input_data: Vec<H256> = gen_merkle_tree_large!();
+        let mut merkle_tree = MerkleTree::new::<H256>(&[]);
+        for num_leaves in 1..=input_data.len() {
+            merkle_tree.append(&input_data[num_leaves - 1]);
+            let input_data = &input_data[..num_leaves];
+            let expected = MerkleTree::new(input_data);
+            assert_eq!(merkle_tree.root(), expected.root());
+            assert_eq!(merkle_tree.num_leaves(), num_leaves);
+            for index in 0..num_leaves {
+                assert_eq!(merkle_tree.proof(index), expected.proof(index));
+            }
+        }
+    }
+
+    #[test]
+    fn empty_tree() {
+        let merkle_tree = MerkleTree::new::<H256>(&[]);
+        assert_eq!(merkle_tree.root(), H256::default());
+        assert!(!verify(&merkle_tree.root(), &H256::default(), &merkle_tree.proof(0), 0, 0));
+    }
+
+    #[test]
+    fn single_leaf() {
+        let input_data: Vec<H256> = gen_merkle_tree_data!();
+        let merkle_tree = MerkleTree::new(&input_data[..1]);
+        let datum_hash = input_data[0].hash();
+        assert_eq!(merkle_tree.root(), datum_hash);
+        let proof = merkle_tree.proof(0);
+        assert!(proof.is_empty());
+        assert!(verify(&merkle_tree.root(), &datum_hash, &proof, 0, 1));
+        // the empty proof only works for the leaf itself
+        assert!(!verify(&merkle_tree.root(), &input_data[1].hash(), &proof, 0, 1));
+        assert!(!verify(&merkle_tree.root(), &datum_hash, &[datum_hash], 0, 1));
+    }
+
+    #[test]
+    fn reject_over_long_proof() {
+        let input_data: Vec<H256> = gen_merkle_tree_data!();
+        let merkle_tree = MerkleTree::new(&input_data);
+        let mut proof = merkle_tree.proof(0);
+        proof.push(input_data[1].hash());
+        assert!(!verify(&merkle_tree.root(), &input_data[0].hash(), &proof, 0, input_data.len()));
+        // nor can it be passed off as a proof in a bigger tree
+        assert!(!verify(&merkle_tree.root(), &input_data[0].hash(), &proof, 0, 1 << 40));
+    }
+
+    #[test]
+    fn reject_out_of_range_index() {
+        let input_data: Vec<H256> = gen_merkle_tree_data!();
+        let merkle_tree = MerkleTree::new(&input_data);
+        let proof = merkle_tree.proof(0);
+        assert!(!verify(&merkle_tree.root(), &input_data[0].hash(), &proof, 2, input_data.len()));
+        assert!(!verify(&merkle_tree.root(), &input_data[0].hash(), &proof, usize::MAX, input_data.len()));
+        assert!(!verify(&merkle_tree.root(), &input_data[0].hash(), &[], 0, 0));
+    }
+
     #[cfg(feature = "my-tests")]
     mod my_tests {
         use super::*;
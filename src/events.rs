@@ -0,0 +1,32 @@
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+/// Delivers a copy of every published event to each subscriber
+pub struct EventBus<T> {
+    subscribers: Vec<Sender<T>>,
+}
+
+impl<T: Clone> EventBus<T> {
+    pub fn new() -> Self {
+        EventBus { subscribers: Vec::new() }
+    }
+
+    /// Start receiving every event published from now on. Dropping the
+    /// receiver unsubscribes
+    pub fn subscribe(&mut self) -> Receiver<T> {
+        let (tx, rx) = channel();
+        self.subscribers.push(tx);
+        rx
+    }
+
+    /// Send the event to every subscriber, forgetting those that have gone
+    /// away
+    pub fn publish(&mut self, event: T) {
+        self.subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+}
+
+impl<T: Clone> Default for EventBus<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
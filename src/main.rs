@@ -6,6 +6,9 @@ pub mod api;
 pub mod block;
 pub mod blockchain;
 pub mod crypto;
+pub mod events;
+pub mod mempool;
+pub mod metrics;
 pub mod miner;
 pub mod network;
 pub mod state;
@@ -13,20 +16,25 @@ pub mod transaction;
 pub mod transaction_generator;
 
 use api::Server as ApiServer;
-use blockchain::Blockchain;
+use blockchain::{Blockchain, GenesisConfig};
+use crypto::address::H160;
+use state::ValidationRules;
 use clap::clap_app;
 use crossbeam::channel;
 use log::{error, info};
 use network::{server, worker};
-use state::State;
 use transaction_generator::TransactionGenerator;
 use std::net;
+use std::path::PathBuf;
 use std::process;
 use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time;
 
+/// How often the blockchain is saved to the chain file
+const CHAIN_SAVE_INTERVAL: time::Duration = time::Duration::from_secs(30);
+
 fn main() {
     // parse command line arguments
     let matches = clap_app!(Bitcoin =>
@@ -37,6 +45,20 @@ fn main() {
      (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
      (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg p2p_backlog: --("p2p-backlog") [INT] default_value("1024") "Sets the maximum number of pending incoming P2P connections")
+     (@arg max_tx_value: --("max-tx-value") [VALUE] "Sets the most a single transaction may transfer")
+     (@arg reject_zero_value: --("reject-zero-value") "Treats transactions that transfer nothing as invalid")
+     (@arg lazy_orphans: --("lazy-orphans") "Connects orphan blocks a few at a time instead of all at once")
+     (@arg state_retention: --("state-retention") [INT] "Keeps the ledger state of only this many blocks at the tip, recomputing older ones when needed")
+     (@arg chain_file: --("chain-file") [PATH] "Loads the blockchain from this file at start, and saves it there periodically")
+     (@arg target_block_interval: --("target-block-interval") [MS] default_value("1000") "Sets the time between blocks, in milliseconds, that the difficulty is retargeted towards")
+     (@arg strict_blocks: --("strict-blocks") "Only accepts blocks that were requested from peers")
+     (@arg p2p_read_timeout: --("p2p-read-timeout") [SECS] "Disconnects peers that send nothing for this many seconds")
+     (@arg miner_threads: --("miner-threads") [INT] default_value("1") "Sets the number of threads searching for nonces")
+     (@arg miner_addr: --("miner-addr") ... [ADDR] "Sets the address credited with the reward of each block mined; several addresses take turns")
+     (@arg min_size_timeout: --("min-block-size-timeout") [SECS] "Mines blocks smaller than the minimum size once the mempool has been too short for this many seconds")
+     (@arg min_peers_to_mine: --("min-peers-to-mine") [INT] "Waits until this many peers are connected before mining each block")
+     (@arg p2p_fanout: --("p2p-fanout") [INT] "Announces new blocks and transactions to only this many random peers")
     )
     .get_matches();
 
@@ -65,13 +87,98 @@ fn main() {
         });
 
     // create blockchain
-    let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+    let max_tx_value = matches.value_of("max_tx_value").map(|value| {
+        value.parse::<u64>().unwrap_or_else(|e| {
+            error!("Error parsing max transaction value: {}", e);
+            process::exit(1);
+        })
+    });
+    let rules = ValidationRules {
+        max_tx_value,
+        reject_zero_value: matches.is_present("reject_zero_value"),
+    };
+    let target_block_interval = matches
+        .value_of("target_block_interval")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing target block interval: {}", e);
+            process::exit(1);
+        });
+    let genesis_config = GenesisConfig {
+        rules,
+        target_block_interval: Some(target_block_interval),
+        ..Default::default()
+    };
+    let state_retention = matches.value_of("state_retention").map(|retention| {
+        match retention.parse::<u64>() {
+            Ok(retention) if retention > 0 => retention,
+            Ok(_) => {
+                error!("State retention must keep at least the tip's state");
+                process::exit(1);
+            }
+            Err(e) => {
+                error!("Error parsing state retention: {}", e);
+                process::exit(1);
+            }
+        }
+    });
+    let chain_file = matches.value_of("chain_file").map(PathBuf::from);
+    let mut blockchain = match &chain_file {
+        Some(path) if path.exists() => Blockchain::load_from_file(path, &genesis_config).unwrap_or_else(|e| {
+            error!("Error loading blockchain from {}: {}", path.display(), e);
+            process::exit(1);
+        }),
+        _ => Blockchain::with_genesis(&genesis_config),
+    };
+    blockchain.set_lazy_orphans(matches.is_present("lazy_orphans"));
+    blockchain.set_state_retention(state_retention);
+    let blockchain = Arc::new(Mutex::new(blockchain));
+
+    // save the blockchain periodically
+    if let Some(path) = chain_file {
+        let blockchain = Arc::clone(&blockchain);
+        thread::spawn(move || loop {
+            thread::sleep(CHAIN_SAVE_INTERVAL);
+            if let Err(e) = blockchain.lock().unwrap().save_to_file(&path) {
+                error!("Error saving blockchain to {}: {}", path.display(), e);
+            }
+        });
+    }
 
     // create channels between server and worker
     let (msg_tx, msg_rx) = channel::unbounded();
 
+    // parse p2p server config
+    let listen_backlog = matches
+        .value_of("p2p_backlog")
+        .unwrap()
+        .parse::<i32>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing P2P backlog: {}", e);
+            process::exit(1);
+        });
+    let read_timeout = matches.value_of("p2p_read_timeout").map(|secs| {
+        let secs = secs.parse::<u64>().unwrap_or_else(|e| {
+            error!("Error parsing P2P read timeout: {}", e);
+            process::exit(1);
+        });
+        time::Duration::from_secs(secs)
+    });
+    let broadcast_fanout = matches.value_of("p2p_fanout").map(|fanout| {
+        fanout.parse::<usize>().unwrap_or_else(|e| {
+            error!("Error parsing P2P fan-out: {}", e);
+            process::exit(1);
+        })
+    });
+    let server_config = server::Config {
+        listen_backlog,
+        read_timeout,
+        broadcast_fanout,
+    };
+
     // start the p2p server
-    let (server_ctx, server) = server::new(p2p_addr, msg_tx).unwrap();
+    let (server_ctx, server) = server::new(p2p_addr, msg_tx, server_config).unwrap();
     server_ctx.start().unwrap();
 
     // start the worker
@@ -83,11 +190,50 @@ fn main() {
             error!("Error parsing P2P workers: {}", e);
             process::exit(1);
         });
-    let worker_ctx = worker::new(p2p_workers, msg_rx, &server, Arc::clone(&blockchain));
-    worker_ctx.start();
+    let block_policy = if matches.is_present("strict_blocks") {
+        worker::BlockPolicy::Strict
+    } else {
+        worker::BlockPolicy::Promiscuous
+    };
+    let worker_ctx = worker::new(p2p_workers, msg_rx, &server, Arc::clone(&blockchain), block_policy);
+    worker_ctx.clone().start();
 
     // start the miner
-    let (miner_ctx, miner) = miner::new(&server, Arc::clone(&blockchain));
+    let miner_threads = matches
+        .value_of("miner_threads")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing miner threads: {}", e);
+            process::exit(1);
+        });
+    let min_peers_to_mine = matches.value_of("min_peers_to_mine").map(|min_peers| {
+        min_peers.parse::<usize>().unwrap_or_else(|e| {
+            error!("Error parsing minimum peers to mine: {}", e);
+            process::exit(1);
+        })
+    });
+    let min_size_timeout = matches.value_of("min_size_timeout").map(|secs| {
+        let secs = secs.parse::<u64>().unwrap_or_else(|e| {
+            error!("Error parsing minimum block size timeout: {}", e);
+            process::exit(1);
+        });
+        time::Duration::from_secs(secs)
+    });
+    let (mut miner_ctx, miner) = miner::new(&server, Arc::clone(&blockchain), miner_threads);
+    miner_ctx.set_min_peers_to_mine(min_peers_to_mine);
+    miner_ctx.set_min_size_timeout(min_size_timeout);
+    if let Some(miner_addrs) = matches.values_of("miner_addr") {
+        let miner_addrs = miner_addrs
+            .map(|miner_addr| {
+                miner_addr.parse::<H160>().unwrap_or_else(|e| {
+                    error!("Error parsing miner address: {}", e);
+                    process::exit(1);
+                })
+            })
+            .collect();
+        miner_ctx.set_reward_rotation(miner_addrs);
+    }
     miner_ctx.start();
 
     // connect to known peers
@@ -129,7 +275,7 @@ fn main() {
     transaction_generator.start();
 
     // start the API server
-    ApiServer::start(api_addr, &miner, &server, tx_gen_tx, Arc::clone(&blockchain));
+    ApiServer::start(api_addr, &miner, &server, tx_gen_tx, Arc::clone(&blockchain), worker_ctx);
 
     loop {
         std::thread::park();
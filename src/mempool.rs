@@ -0,0 +1,366 @@
+use crate::blockchain::RejectReason;
+use crate::crypto::address::H160;
+use crate::crypto::hash::{Hashable, H256};
+use crate::state::State;
+use crate::transaction::SignedTransaction as Transaction;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+/// The default maximum number of transactions in the mempool
+pub const DEFAULT_MEMPOOL_CAPACITY: usize = 10000;
+
+/// The order in which the miner considers transactions for a block: the
+/// highest fees first, and each sender's transactions in nonce order among
+/// equal fees
+pub fn mining_priority(transaction: &Transaction) -> (Reverse<u64>, H160, u32) {
+    let raw = &transaction.raw_transaction;
+    (Reverse(raw.fee), raw.from_addr, raw.nonce)
+}
+
+/// Apply up to `max_transactions` of the candidate transactions to `state`,
+/// trying them in `mining_priority` order. A transaction depending on another
+/// one (e.g. using the next nonce, or spending coins that the other one
+/// sends) is tried again once the other one is applied, instead of being
+/// dropped. Each sender's transactions are tried in nonce order, and a sender
+/// that can't afford its next one waits until it receives coins, so every
+/// candidate is tried only a few times. Returns the transactions applied, in
+/// the order they were applied
+pub fn apply_in_priority_order<'a>(
+    state: &mut State,
+    candidates: impl Iterator<Item = &'a Transaction>,
+    max_transactions: usize,
+) -> Vec<&'a Transaction> {
+    // each sender's transactions, the next one to try last
+    let mut queues: HashMap<H160, Vec<&Transaction>> = HashMap::new();
+    for transaction in candidates {
+        queues.entry(transaction.raw_transaction.from_addr).or_default().push(transaction);
+    }
+    for queue in queues.values_mut() {
+        queue.sort_by_key(|transaction| Reverse((transaction.raw_transaction.nonce, mining_priority(transaction))));
+    }
+    // the next transaction of each sender that isn't waiting for coins, the
+    // best one on top
+    let mut next: BinaryHeap<_> = queues
+        .values()
+        .filter_map(|queue| queue.last())
+        .map(|transaction| Reverse(mining_priority(transaction)))
+        .collect();
+    let mut waiting_for_coins = HashSet::new();
+
+    let mut applied = Vec::new();
+    while let Some(Reverse((_, sender, _))) = next.pop() {
+        if applied.len() >= max_transactions {
+            break;
+        }
+        let queue = queues.get_mut(&sender).expect("every sender has a queue");
+        let transaction = queue.pop().expect("a sender is only tried while it has transactions");
+        let raw = &transaction.raw_transaction;
+        let was_applied = state.update_in_place(raw);
+        if !was_applied && state.get_acc_info(&sender).is_none_or(|acc_info| raw.nonce >= acc_info.nonce) {
+            queue.push(transaction);
+            waiting_for_coins.insert(sender);
+            continue;
+        }
+        // go on to the sender's next one, since this one either was applied
+        // or has a nonce that was already used
+        if let Some(sender_next) = queue.last() {
+            next.push(Reverse(mining_priority(sender_next)));
+        }
+        if was_applied {
+            applied.push(transaction);
+            if waiting_for_coins.remove(&raw.to_addr) {
+                let receiver_next = queues[&raw.to_addr].last().expect("a waiting sender has transactions");
+                next.push(Reverse(mining_priority(receiver_next)));
+            }
+        }
+    }
+    applied
+}
+
+/// Store all the received valid transactions which have not been included in
+/// the blockchain yet
+#[derive(Debug, Clone)]
+pub struct Mempool {
+    hash_to_transaction: HashMap<H256, Transaction>,
+    /// Maps each (sender, nonce) pair spent by a transaction in the mempool
+    /// to that transaction's hash, so that conflicting transactions can be
+    /// detected
+    spends: HashMap<(H160, u32), H256>,
+    /// The maximum number of transactions `insert_with_validation` lets in
+    capacity: usize,
+    /// The projected state (see `projected_state`) of the state given to
+    /// `insert_with_validation`, kept up to date as that inserts
+    /// transactions, so that it isn't recomputed for each of them. Any other
+    /// change to the mempool clears it, and so must a change to that state
+    /// (see `reset_projection`)
+    projection: Option<State>,
+}
+
+impl Mempool {
+    pub fn new() -> Self {
+        Mempool {
+            hash_to_transaction: HashMap::new(),
+            spends: HashMap::new(),
+            capacity: DEFAULT_MEMPOOL_CAPACITY,
+            projection: None,
+        }
+    }
+
+    /// Set the maximum number of transactions the mempool may hold
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+    }
+
+    pub fn len(&self) -> usize {
+        self.hash_to_transaction.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hash_to_transaction.is_empty()
+    }
+
+    /// Get a transaction from the mempool by hash (or `None` if it does not exist)
+    pub fn get_transaction(&self, hash: &H256) -> Option<&Transaction> {
+        self.hash_to_transaction.get(hash)
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.hash_to_transaction.contains_key(hash)
+    }
+
+    /// Every transaction with its hash, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (&H256, &Transaction)> {
+        self.hash_to_transaction.iter()
+    }
+
+    /// Check a transaction, whose signature the caller has already verified,
+    /// against the given state (normally the one at the tip) with the
+    /// transactions already in the mempool applied, and insert it if it
+    /// passes. So a transaction may use the nonce after its sender's pending
+    /// ones, or spend coins that a pending transaction sends. Transactions
+    /// that are only timelocked are let in to wait. The state must be the
+    /// same from one call to the next until `reset_projection` is called.
+    ///
+    /// The signature is deliberately not verified here. Callers verify it
+    /// first, so that transactions arriving together can be verified as a
+    /// batch (see `transaction::verify_batch`) rather than one at a time.
+    /// Rejections return the reason rather than just `false`, so it can be
+    /// reported to peers and API clients
+    pub fn insert_with_validation(&mut self, transaction: Transaction, state: &State) -> Result<(), RejectReason> {
+        let raw = &transaction.raw_transaction;
+        if self.contains(&transaction.hash()) {
+            return Err(RejectReason::AlreadyKnown);
+        }
+        if raw.is_coinbase() {
+            // coinbases only belong at the start of a block
+            return Err(RejectReason::MisplacedCoinbase);
+        }
+        if state.rules().exceeds_max_value(raw) {
+            return Err(RejectReason::ValueTooLarge);
+        }
+        if state.rules().forbids_zero_value(raw) {
+            return Err(RejectReason::ZeroValue);
+        }
+        if raw.memo_too_long() {
+            return Err(RejectReason::MemoTooLong);
+        }
+        // check that it doesn't conflict with a transaction already in the
+        // mempool
+        if self.spends.contains_key(&(raw.from_addr, raw.nonce)) {
+            return Err(RejectReason::DoubleSpend);
+        }
+        if self.projection.is_none() {
+            self.projection = Some(self.projected_state(state));
+        }
+        let projection = self.projection.as_mut().expect("the projection was just computed");
+        if !projection.check_transaction_validity_eventually(raw) {
+            return Err(RejectReason::InvalidState);
+        }
+        // make sure there's room for it
+        if self.hash_to_transaction.len() >= self.capacity {
+            return Err(RejectReason::MempoolFull);
+        }
+        let applied = projection.update_in_place(raw);
+        debug_assert!(applied, "the projection ignores timelocks");
+        self.insert_keeping_projection(transaction);
+        Ok(())
+    }
+
+    /// Insert a transaction into the mempool without checking it. Returns
+    /// whether it wasn't there already
+    pub fn insert(&mut self, transaction: Transaction) -> bool {
+        self.projection = None;
+        self.insert_keeping_projection(transaction)
+    }
+
+    /// Forget the projected state, e.g. because the state passed to
+    /// `insert_with_validation` changes with the tip of the chain
+    pub fn reset_projection(&mut self) {
+        self.projection = None;
+    }
+
+    /// Like `insert`, for when the caller has updated the projection itself
+    fn insert_keeping_projection(&mut self, transaction: Transaction) -> bool {
+        let hash = transaction.hash();
+        if self.contains(&hash) {
+            return false;
+        }
+        let raw = &transaction.raw_transaction;
+        self.spends.insert((raw.from_addr, raw.nonce), hash);
+        self.hash_to_transaction.insert(hash, transaction);
+        true
+    }
+
+    /// Remove a transaction, e.g. once a block confirms it, so that its
+    /// nonce can be spent by another transaction
+    pub fn remove(&mut self, hash: &H256) -> Option<Transaction> {
+        let transaction = self.hash_to_transaction.remove(hash)?;
+        self.projection = None;
+        let raw = &transaction.raw_transaction;
+        if self.spends.get(&(raw.from_addr, raw.nonce)) == Some(hash) {
+            self.spends.remove(&(raw.from_addr, raw.nonce));
+        }
+        Some(transaction)
+    }
+
+    /// The given state with as many of the mempool's transactions applied as
+    /// can be, ignoring timelocks, i.e. the state once they are all mined
+    pub fn projected_state(&self, state: &State) -> State {
+        let mut projected = state.clone();
+        projected.set_block_timestamp(u128::MAX);
+        apply_in_priority_order(&mut projected, self.hash_to_transaction.values(), usize::MAX);
+        projected
+    }
+
+    /// Remove every transaction that can't be applied to the given state
+    /// along with the rest of the mempool, like those whose nonces were used
+    /// by a block, or that depended on such a transaction. Transactions that
+    /// are only timelocked are kept. Returns the number of transactions
+    /// removed
+    pub fn prune_invalid(&mut self, state: &State) -> usize {
+        let mut projected = state.clone();
+        projected.set_block_timestamp(u128::MAX);
+        let applicable: HashSet<H256> =
+            apply_in_priority_order(&mut projected, self.hash_to_transaction.values(), usize::MAX)
+                .into_iter()
+                .map(Hashable::hash)
+                .collect();
+        let num_removed = self.prune(|transaction| !applicable.contains(&transaction.hash()));
+        // what is left is exactly what was applied
+        self.projection = Some(projected);
+        num_removed
+    }
+
+    /// Remove every transaction for which `is_invalid` is true. Returns the
+    /// number of transactions removed
+    pub fn prune<F: Fn(&Transaction) -> bool>(&mut self, is_invalid: F) -> usize {
+        let invalid_hashes: Vec<H256> = self
+            .hash_to_transaction
+            .iter()
+            .filter(|(_, transaction)| is_invalid(transaction))
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in &invalid_hashes {
+            self.remove(hash);
+        }
+        invalid_hashes.len()
+    }
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::key_pair::get_deterministic_keypair;
+    use crate::transaction::RawTransaction;
+    use ring::signature::KeyPair;
+
+    #[test]
+    fn spends_follow_transactions() {
+        let state = State::ico();
+        let sender = get_deterministic_keypair(0);
+        let from_addr = H160::from_pubkey(sender.public_key().as_ref());
+        let transaction = |value| {
+            Transaction::from_raw(
+                RawTransaction { from_addr, to_addr: from_addr, value, fee: 0, nonce: 0, memo: vec![], valid_since: None },
+                &sender,
+            )
+        };
+        let mut mempool = Mempool::new();
+        let first = transaction(1);
+        assert_eq!(mempool.insert_with_validation(first.clone(), &state), Ok(()));
+        assert_eq!(mempool.insert_with_validation(first.clone(), &state), Err(RejectReason::AlreadyKnown));
+        assert_eq!(mempool.insert_with_validation(transaction(2), &state), Err(RejectReason::DoubleSpend));
+
+        // once the first is gone, its nonce is free again
+        assert_eq!(mempool.prune(|transaction| transaction.raw_transaction.value == 1), 1);
+        assert!(mempool.is_empty());
+        assert_eq!(mempool.insert_with_validation(transaction(2), &state), Ok(()));
+
+        // the sender's next nonce waits behind the pending one
+        let next = Transaction::from_raw(
+            RawTransaction { from_addr, to_addr: from_addr, value: 1, fee: 0, nonce: 1, memo: vec![], valid_since: None },
+            &sender,
+        );
+        assert_eq!(mempool.insert_with_validation(next.clone(), &state), Ok(()));
+        // until the pending one goes, which takes its dependents with it
+        assert_eq!(mempool.prune_invalid(&state), 0);
+        mempool.remove(&transaction(2).hash());
+        assert_eq!(mempool.prune_invalid(&state), 1);
+        assert!(!mempool.contains(&next.hash()));
+        assert_eq!(mempool.insert_with_validation(transaction(2), &state), Ok(()));
+
+        mempool.set_capacity(1);
+        let other = get_deterministic_keypair(1);
+        let other_addr = H160::from_pubkey(other.public_key().as_ref());
+        let raw = RawTransaction { from_addr: other_addr, to_addr: from_addr, value: 1, fee: 0, nonce: 0, memo: vec![], valid_since: None };
+        let full = Transaction::from_raw(raw, &other);
+        assert_eq!(mempool.insert_with_validation(full.clone(), &state), Err(RejectReason::MempoolFull));
+        assert!(mempool.remove(&transaction(2).hash()).is_some());
+        assert_eq!(mempool.insert_with_validation(full, &state), Ok(()));
+    }
+
+    #[test]
+    fn apply_dependent_transactions() {
+        let state = State::ico();
+        let sender = get_deterministic_keypair(0);
+        let from_addr = H160::from_pubkey(sender.public_key().as_ref());
+        let to_addr = H160::from_pubkey(get_deterministic_keypair(1).public_key().as_ref());
+
+        // a nonce chain whose fees rise with the nonce, so that each
+        // transaction ranks ahead of the one it depends on
+        let chain: Vec<_> = (0..100)
+            .map(|nonce| {
+                let raw = RawTransaction { from_addr, to_addr, value: 1, fee: nonce as u64, nonce, memo: vec![], valid_since: None };
+                Transaction::from_raw(raw, &sender)
+            })
+            .collect();
+        let mut mempool = Mempool::new();
+        for transaction in &chain {
+            assert_eq!(mempool.insert_with_validation(transaction.clone(), &state), Ok(()));
+        }
+        let applied = apply_in_priority_order(&mut state.clone(), chain.iter().rev(), 60);
+        let nonces: Vec<u32> = applied.iter().map(|transaction| transaction.raw_transaction.nonce).collect();
+        assert_eq!(nonces, (0..60).collect::<Vec<_>>());
+
+        // an account can spend what a pending transaction sends it first
+        let new_key = crate::crypto::key_pair::random();
+        let new_addr = H160::from_pubkey(new_key.public_key().as_ref());
+        let raw = RawTransaction { from_addr: new_addr, to_addr, value: 5, fee: 1000, nonce: 0, memo: vec![], valid_since: None };
+        let spend = Transaction::from_raw(raw, &new_key);
+        assert_eq!(mempool.insert_with_validation(spend.clone(), &state), Err(RejectReason::InvalidState));
+        let other = get_deterministic_keypair(2);
+        let other_addr = H160::from_pubkey(other.public_key().as_ref());
+        let raw = RawTransaction { from_addr: other_addr, to_addr: new_addr, value: 2000, fee: 0, nonce: 0, memo: vec![], valid_since: None };
+        let fund = Transaction::from_raw(raw, &other);
+        assert_eq!(mempool.insert_with_validation(fund.clone(), &state), Ok(()));
+        assert_eq!(mempool.insert_with_validation(spend.clone(), &state), Ok(()));
+        assert_eq!(mempool.prune_invalid(&state), 0);
+        assert_eq!(mempool.len(), 102);
+    }
+}
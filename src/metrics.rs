@@ -0,0 +1,309 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::time::SystemTime;
+
+/// How many minutes of history each counter keeps
+pub const METRICS_WINDOW_MINUTES: usize = 60;
+
+const MILLIS_PER_MINUTE: u128 = 60_000;
+
+/// How many seconds of history the hash rate is averaged over
+pub const HASH_RATE_WINDOW_SECONDS: u128 = 10;
+
+/// The current time in milliseconds since the Unix epoch
+pub fn now_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("system time should always be after Unix epoch")
+        .as_millis()
+}
+
+/// The number of events in one minute
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MinuteCount {
+    /// The start of the minute, in milliseconds since the Unix epoch
+    pub minute: u128,
+    pub count: u64,
+}
+
+/// Counts events per minute over a sliding window of minutes. The counts are
+/// kept in a ring buffer, where minute `m` (counting from the Unix epoch) is
+/// in bucket `m % window`
+#[derive(Debug, Clone)]
+pub struct PerMinuteCounter {
+    buckets: Vec<u64>,
+    /// The most recent minute with a bucket. Buckets for later minutes are
+    /// cleared as the clock reaches them
+    latest_minute: u128,
+}
+
+impl PerMinuteCounter {
+    pub fn new(window: usize) -> Self {
+        assert!(window > 0);
+        PerMinuteCounter { buckets: vec![0; window], latest_minute: 0 }
+    }
+
+    /// Count one event at the given time, in milliseconds since the Unix
+    /// epoch. Events from before the window are dropped
+    pub fn record_at(&mut self, now: u128) {
+        let minute = now / MILLIS_PER_MINUTE;
+        self.advance_to(minute);
+        if minute + self.window() <= self.latest_minute {
+            return;
+        }
+        let index = self.bucket(minute);
+        self.buckets[index] += 1;
+    }
+
+    /// The count for each minute in the window ending at the given time,
+    /// oldest first
+    pub fn per_minute_at(&self, now: u128) -> Vec<MinuteCount> {
+        let minute = now / MILLIS_PER_MINUTE;
+        let window = self.window();
+        (minute.saturating_sub(window - 1)..=minute)
+            .map(|m| {
+                // buckets outside what the ring currently holds are empty
+                let in_ring = m <= self.latest_minute && m + window > self.latest_minute;
+                let count = if in_ring { self.buckets[self.bucket(m)] } else { 0 };
+                MinuteCount { minute: m * MILLIS_PER_MINUTE, count }
+            })
+            .collect()
+    }
+
+    /// Roll the ring forward, clearing the buckets of minutes that are
+    /// reused
+    fn advance_to(&mut self, minute: u128) {
+        if minute <= self.latest_minute {
+            return;
+        }
+        let num_stale = (minute - self.latest_minute).min(self.window());
+        for m in (minute + 1 - num_stale)..=minute {
+            let index = self.bucket(m);
+            self.buckets[index] = 0;
+        }
+        self.latest_minute = minute;
+    }
+
+    fn window(&self) -> u128 {
+        self.buckets.len() as u128
+    }
+
+    fn bucket(&self, minute: u128) -> usize {
+        (minute % self.window()) as usize
+    }
+}
+
+/// Throughput of the blockchain over time
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    pub blocks_accepted: PerMinuteCounter,
+    pub transactions_accepted: PerMinuteCounter,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            blocks_accepted: PerMinuteCounter::new(METRICS_WINDOW_MINUTES),
+            transactions_accepted: PerMinuteCounter::new(METRICS_WINDOW_MINUTES),
+        }
+    }
+
+    pub fn record_block(&mut self) {
+        self.blocks_accepted.record_at(now_millis());
+    }
+
+    pub fn record_transaction(&mut self) {
+        self.transactions_accepted.record_at(now_millis());
+    }
+
+    /// The per-minute counts as JSON, for plotting
+    pub fn to_json(&self) -> serde_json::Value {
+        let now = now_millis();
+        serde_json::json!({
+            "window_minutes": METRICS_WINDOW_MINUTES,
+            "blocks_per_minute": self.blocks_accepted.per_minute_at(now),
+            "transactions_per_minute": self.transactions_accepted.per_minute_at(now),
+        })
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How long blocks take to reach us after they are mined, judging by their
+/// timestamps
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencyStats {
+    pub num_blocks: u64,
+    /// The sum of the latencies, in milliseconds
+    pub total_millis: u128,
+    pub max_millis: u128,
+}
+
+impl LatencyStats {
+    /// Record a block with the given timestamp arriving at the given time,
+    /// both in milliseconds since the Unix epoch. A block from the future,
+    /// which only happens when clocks are skewed, counts as arriving
+    /// instantly
+    pub fn record_at(&mut self, timestamp: u128, now: u128) {
+        let latency = now.saturating_sub(timestamp);
+        self.num_blocks += 1;
+        self.total_millis = self.total_millis.saturating_add(latency);
+        self.max_millis = self.max_millis.max(latency);
+    }
+
+    /// The average latency in milliseconds, or `None` before any block
+    pub fn average_millis(&self) -> Option<u128> {
+        (self.num_blocks > 0).then(|| self.total_millis / self.num_blocks as u128)
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "num_blocks": self.num_blocks,
+            "average_millis": self.average_millis(),
+            "max_millis": self.max_millis,
+        })
+    }
+}
+
+/// How hard the miner is working
+#[derive(Debug, Clone, Default)]
+pub struct MinerStats {
+    pub nonces_tried: u64,
+    pub blocks_mined: u64,
+    /// The number of nonces tried in each recent second (counting from the
+    /// Unix epoch) in which any were, oldest first
+    recent_nonces: VecDeque<(u128, u64)>,
+    /// When the first nonce was tried, in milliseconds since the Unix epoch
+    started: Option<u128>,
+}
+
+impl MinerStats {
+    /// Count `count` nonces tried at the given time, in milliseconds since
+    /// the Unix epoch
+    pub fn record_nonces_at(&mut self, count: u64, now: u128) {
+        self.nonces_tried += count;
+        self.started.get_or_insert(now);
+        let second = now / 1000;
+        match self.recent_nonces.back_mut() {
+            Some((latest, latest_count)) if *latest >= second => *latest_count += count,
+            _ => self.recent_nonces.push_back((second, count)),
+        }
+        self.forget_before(second);
+    }
+
+    pub fn record_block(&mut self) {
+        self.blocks_mined += 1;
+    }
+
+    /// The nonces tried per second over the last `HASH_RATE_WINDOW_SECONDS`,
+    /// or since mining started if that was more recent
+    pub fn hash_rate_at(&self, now: u128) -> f64 {
+        let Some(started) = self.started else {
+            return 0.0;
+        };
+        let second = now / 1000;
+        let num_nonces: u64 = self
+            .recent_nonces
+            .iter()
+            .filter(|(s, _)| *s + HASH_RATE_WINDOW_SECONDS > second)
+            .map(|(_, count)| count)
+            .sum();
+        let window_millis = now.saturating_sub(started).min(HASH_RATE_WINDOW_SECONDS * 1000);
+        if window_millis == 0 {
+            return 0.0;
+        }
+        num_nonces as f64 * 1000.0 / window_millis as f64
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "hash_rate": self.hash_rate_at(now_millis()),
+            "blocks_mined": self.blocks_mined,
+        })
+    }
+
+    /// Drop the counts of seconds that have left the window
+    fn forget_before(&mut self, second: u128) {
+        while self
+            .recent_nonces
+            .front()
+            .is_some_and(|(s, _)| *s + HASH_RATE_WINDOW_SECONDS <= second)
+        {
+            self.recent_nonces.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts(counter: &PerMinuteCounter, now: u128) -> Vec<u64> {
+        counter.per_minute_at(now).iter().map(|minute_count| minute_count.count).collect()
+    }
+
+    #[test]
+    fn buckets_roll() {
+        let mut counter = PerMinuteCounter::new(3);
+        // a mock clock, starting partway through some minute
+        let mut now = 1_000 * MILLIS_PER_MINUTE + 30_000;
+
+        counter.record_at(now);
+        counter.record_at(now);
+        assert_eq!(counts(&counter, now), vec![0, 0, 2]);
+        assert_eq!(counter.per_minute_at(now)[2].minute, 1_000 * MILLIS_PER_MINUTE);
+
+        now += MILLIS_PER_MINUTE;
+        counter.record_at(now);
+        assert_eq!(counts(&counter, now), vec![0, 2, 1]);
+
+        // a quiet minute
+        now += 2 * MILLIS_PER_MINUTE;
+        assert_eq!(counts(&counter, now), vec![1, 0, 0]);
+        counter.record_at(now);
+        assert_eq!(counts(&counter, now), vec![1, 0, 1]);
+
+        // a late event still lands in its own minute, unless it's too old
+        counter.record_at(now - MILLIS_PER_MINUTE);
+        counter.record_at(now - 5 * MILLIS_PER_MINUTE);
+        assert_eq!(counts(&counter, now), vec![1, 1, 1]);
+
+        // after a long gap, the old counts are gone
+        now += 10 * MILLIS_PER_MINUTE;
+        counter.record_at(now);
+        assert_eq!(counts(&counter, now), vec![0, 0, 1]);
+    }
+
+    #[test]
+    fn average_latency() {
+        let mut latency = LatencyStats::default();
+        assert_eq!(latency.to_json()["average_millis"], serde_json::Value::Null);
+        latency.record_at(1_000, 1_300);
+        latency.record_at(1_000, 1_100);
+        // skewed clocks don't underflow
+        latency.record_at(2_000, 1_500);
+        assert_eq!(latency.average_millis(), Some(400 / 3));
+        assert_eq!(latency.max_millis, 300);
+        assert_eq!(latency.to_json()["num_blocks"], 3);
+    }
+
+    #[test]
+    fn hash_rate() {
+        let mut stats = MinerStats::default();
+        let start = 1_000_000;
+        assert_eq!(stats.hash_rate_at(start), 0.0);
+        stats.record_nonces_at(500, start);
+        stats.record_nonces_at(500, start + 500);
+        assert_eq!(stats.hash_rate_at(start + 2_000), 500.0);
+
+        // once the window is full, only its nonces count
+        stats.record_nonces_at(3_000, start + 11_000);
+        assert_eq!(stats.hash_rate_at(start + 11_000), 300.0);
+        assert_eq!(stats.hash_rate_at(start + 30_000), 0.0);
+        assert_eq!(stats.nonces_tried, 4_000);
+    }
+}
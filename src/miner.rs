@@ -1,26 +1,46 @@
 use crate::block::{Block, Content, Header};
-use crate::blockchain::Blockchain;
+use crate::blockchain::{validate_transactions_in_place, Blockchain, RejectReason};
+use crate::crypto::address::H160;
 use crate::crypto::hash::Hashable;
+use crate::crypto::key_pair::get_deterministic_keypair;
 use crate::crypto::merkle::MerkleTree;
+use crate::mempool::apply_in_priority_order;
+use crate::metrics::MinerStats;
 use crate::network::message::Message;
 use crate::network::server::Handle as ServerHandle;
-use crate::transaction;
+use crate::state::State;
+use crate::transaction::{verify_batch, SignedTransaction, BLOCK_REWARD};
 
-use log::{debug, info, trace};
+use log::{debug, info, warn};
 
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
-use std::iter::FromIterator;
+use ring::signature::KeyPair;
+use std::iter;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
-use std::thread::current;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 
-use std::{iter, thread};
+use std::thread;
 
 const OUR_MINIMUM_BLOCK_SIZE: usize = 5;
-const OUR_MAXIMUM_BLOCK_SIZE: usize = 7;
+pub const OUR_MAXIMUM_BLOCK_SIZE: usize = 7;
+
+/// With multiple hashing threads, how many nonces each thread tries before
+/// the miner checks for control signals and a new tip again
+const NONCES_PER_THREAD: u32 = 4096;
+
+/// How long to wait before checking again whether enough peers are connected
+/// to mine
+const PEER_WAIT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long the mempool may have some transactions, but too few for a block,
+/// before the miner warns about it
+const SHORT_MEMPOOL_WARNING_DELAY: Duration = Duration::from_secs(10);
 
 enum ControlSignal {
     Start(u64), // the number controls the lambda of interval between block generation
+    MineCount(u64), // mine this many blocks, then pause
+    Pause,
     Exit,
 }
 
@@ -36,26 +56,63 @@ pub struct Context {
     operating_state: OperatingState,
     server: ServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
+    /// How many threads search for a nonce at once
+    num_threads: usize,
+    /// If set, no block is started until at least this many peers are
+    /// connected, so that the node doesn't mine a chain of its own in
+    /// isolation
+    min_peers_to_mine: Option<usize>,
+    /// The accounts credited with the rewards of the blocks mined, taking
+    /// turns. Never empty
+    reward_addrs: Vec<H160>,
+    /// Which of `reward_addrs` gets the reward of the next block mined
+    next_reward: usize,
+    /// If set, once the mempool has had too few transactions for a block for
+    /// this long, a block is mined with whatever transactions there are
+    min_size_timeout: Option<Duration>,
+    /// Since when the mempool has had some transactions, but too few for a
+    /// block
+    short_since: Option<Instant>,
+    /// Whether the mempool being short has been warned about since
+    /// `short_since`
+    warned_short: bool,
+    /// If set, how many more blocks to mine before pausing. Only blocks this
+    /// node mined count
+    remaining_blocks: Option<u64>,
+    stats: Arc<Mutex<MinerStats>>,
 }
 
 #[derive(Clone)]
 pub struct Handle {
     /// Channel for sending signal to the miner thread
     control_chan: Sender<ControlSignal>,
+    stats: Arc<Mutex<MinerStats>>,
 }
 
-pub fn new(server: &ServerHandle, blockchain: Arc<Mutex<Blockchain>>) -> (Context, Handle) {
+pub fn new(server: &ServerHandle, blockchain: Arc<Mutex<Blockchain>>, num_threads: usize) -> (Context, Handle) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
+    let stats = Arc::new(Mutex::new(MinerStats::default()));
 
     let ctx = Context {
         control_chan: signal_chan_receiver,
         operating_state: OperatingState::Paused,
         server: server.clone(),
         blockchain,
+        num_threads: num_threads.max(1),
+        min_peers_to_mine: None,
+        // the first ICO account, unless told otherwise
+        reward_addrs: vec![H160::from_pubkey(get_deterministic_keypair(0).public_key().as_ref())],
+        next_reward: 0,
+        min_size_timeout: None,
+        short_since: None,
+        warned_short: false,
+        remaining_blocks: None,
+        stats: Arc::clone(&stats),
     };
 
     let handle = Handle {
         control_chan: signal_chan_sender,
+        stats,
     };
 
     (ctx, handle)
@@ -71,9 +128,77 @@ impl Handle {
             .send(ControlSignal::Start(lambda))
             .unwrap();
     }
+
+    /// Mine `count` blocks, then pause
+    pub fn mine_count(&self, count: u64) {
+        self.control_chan.send(ControlSignal::MineCount(count)).unwrap();
+    }
+
+    /// Stop mining until the next `start`, keeping the miner thread around
+    pub fn pause(&self) {
+        self.control_chan.send(ControlSignal::Pause).unwrap();
+    }
+
+    /// How many nonces the miner has tried, and how many blocks it has mined
+    pub fn stats(&self) -> &Arc<Mutex<MinerStats>> {
+        &self.stats
+    }
 }
 
 impl Context {
+    /// Wait for at least this many connected peers before mining each block
+    pub fn set_min_peers_to_mine(&mut self, min_peers: Option<usize>) {
+        self.min_peers_to_mine = min_peers;
+    }
+
+    /// Credit the reward of each block mined to this account
+    pub fn set_reward_address(&mut self, reward_addr: H160) {
+        self.set_reward_rotation(vec![reward_addr]);
+    }
+
+    /// Credit the rewards of the blocks mined to these accounts in turn, one
+    /// block each, like a pool sharing out its rewards
+    pub fn set_reward_rotation(&mut self, reward_addrs: Vec<H160>) {
+        assert!(!reward_addrs.is_empty(), "someone must get the reward");
+        self.reward_addrs = reward_addrs;
+        self.next_reward = 0;
+    }
+
+    /// Mine blocks smaller than the minimum size once the mempool has been
+    /// too short for a full block for this long
+    pub fn set_min_size_timeout(&mut self, timeout: Option<Duration>) {
+        self.min_size_timeout = timeout;
+    }
+
+    /// Whether a block of only `num_transactions` transactions, fewer than the
+    /// minimum, may be mined. Keeps track of how long the mempool has been
+    /// short, warning once it has been for `SHORT_MEMPOOL_WARNING_DELAY`
+    fn allow_short_block(&mut self, num_transactions: usize) -> bool {
+        if num_transactions == 0 {
+            // nothing to wait for
+            self.short_since = None;
+            self.warned_short = false;
+            return false;
+        }
+        let short_for = self.short_since.get_or_insert_with(Instant::now).elapsed();
+        if short_for >= SHORT_MEMPOOL_WARNING_DELAY && !self.warned_short {
+            warn!(
+                "Only {} transactions have been available for {:?}, fewer than the {} needed to mine a block",
+                num_transactions, short_for, OUR_MINIMUM_BLOCK_SIZE
+            );
+            self.warned_short = true;
+        }
+        self.min_size_timeout.is_some_and(|timeout| short_for >= timeout)
+    }
+
+    /// Whether enough peers are connected to mine
+    fn has_enough_peers(&self) -> bool {
+        match self.min_peers_to_mine {
+            Some(min_peers) => self.server.peers().len() >= min_peers,
+            None => true,
+        }
+    }
+
     pub fn start(mut self) {
         thread::Builder::new()
             .name("miner".to_string())
@@ -93,12 +218,31 @@ impl Context {
             ControlSignal::Start(i) => {
                 info!("Miner starting in continuous mode with lambda {}", i);
                 self.operating_state = OperatingState::Run(i);
+                self.remaining_blocks = None;
+            }
+            ControlSignal::MineCount(0) => {
+                info!("Miner asked to mine no blocks, pausing");
+                self.operating_state = OperatingState::Paused;
+                self.remaining_blocks = None;
+            }
+            ControlSignal::MineCount(count) => {
+                info!("Miner mining {} blocks", count);
+                // keep the lambda if already running
+                if !matches!(self.operating_state, OperatingState::Run(_)) {
+                    self.operating_state = OperatingState::Run(0);
+                }
+                self.remaining_blocks = Some(count);
+            }
+            ControlSignal::Pause => {
+                info!("Miner pausing");
+                self.operating_state = OperatingState::Paused;
             }
         }
     }
 
     fn miner_loop(&mut self) {
         let mut current_block = None;
+        let mut instant_finality = false;
 
         // main mining loop
         loop {
@@ -120,34 +264,58 @@ impl Context {
                     Err(TryRecvError::Disconnected) => panic!("Miner control channel detached"),
                 },
             }
-            if let OperatingState::ShutDown = self.operating_state {
-                return;
+            match self.operating_state {
+                OperatingState::ShutDown => return,
+                // wait for the next signal before mining any further
+                OperatingState::Paused => continue,
+                OperatingState::Run(_) => {}
             }
 
 
             // do one iteration of mining
-            // make sure we have a block to work on
+            // make sure we have a block to work on, on top of the current tip
+            self.discard_if_stale(&mut current_block);
             if current_block.is_none() {
+                if !self.has_enough_peers() {
+                    debug!("Waiting for more peers before mining");
+                    thread::sleep(PEER_WAIT_INTERVAL);
+                    continue;
+                }
                 current_block = self.create_next_block(rand::random());
+                instant_finality = self.blockchain.lock().expect("idk why this should succeed").instant_finality();
             }
             if let Some(block) = &mut current_block {
-                block.header.timestamp = SystemTime::now()
+                let now = SystemTime::now()
                     .duration_since(SystemTime::UNIX_EPOCH)
                     .expect("system time should always be after Unix epoch")
                     .as_millis();
-                let hash = block.hash();
-                if hash <= block.header.difficulty {
-                    // add the block to the chain
-                    let mut blockchain = self.blockchain.lock().expect("idk why this should succeed");
-                    blockchain.insert_block_with_validation(current_block.take().expect("should exist"));
-                    drop(blockchain);
-                    info!("Mined a block! Added to blockchain");
-                    self.server.broadcast(Message::NewBlockHashes(vec![hash]));
+                // never go back on a timestamp bumped by `advance_nonce`
+                block.header.timestamp = block.header.timestamp.max(now);
+                let (solved, nonces_tried) = if instant_finality {
+                    (true, 1)
+                } else if self.num_threads > 1 {
+                    match search_nonces(&block.header, self.num_threads, NONCES_PER_THREAD) {
+                        (Some(nonce), nonces_tried) => {
+                            block.header.nonce = nonce;
+                            (true, nonces_tried)
+                        }
+                        (None, nonces_tried) => {
+                            // move every thread on to the next nonces in its part
+                            advance_nonce(&mut block.header, NONCES_PER_THREAD);
+                            (false, nonces_tried)
+                        }
+                    }
                 } else {
+                    (block.hash() <= block.header.difficulty, 1)
+                };
+                self.stats.lock().unwrap().record_nonces_at(nonces_tried, now);
+                if solved {
+                    self.finish_block(current_block.take().expect("should exist"));
+                } else if self.num_threads == 1 {
                     debug!("Didn't work, trying another nonce");
-                    // increment the nonce for the next iteration
-                    block.header.nonce += 1;
-                    // should never wrap back around to the starting nonce
+                    advance_nonce(&mut block.header, 1);
+                } else {
+                    debug!("Didn't work, trying more nonces");
                 }
             } else {
                 debug!("couldn't build a block");
@@ -155,42 +323,111 @@ impl Context {
 
             if let OperatingState::Run(i) = self.operating_state {
                 if i != 0 {
-                    let interval = Duration::from_micros(i as u64);
+                    let interval = Duration::from_micros(i);
                     thread::sleep(interval);
                 }
             }
         }
     }
 
-    fn create_next_block(&self, starting_nonce: u32) -> Option<Block> {
+    /// Add a block we solved to the chain and announce it
+    fn finish_block(&mut self, block: Block) {
+        let hash = block.hash();
+        let mut blockchain = self.blockchain.lock().expect("idk why this should succeed");
+        let outcome = blockchain.insert_block_with_validation(block);
+        drop(blockchain);
+        if !outcome.added.contains(&hash) {
+            warn!("The block we mined wasn't added to the blockchain");
+            return;
+        }
+        info!("Mined a block! Added to blockchain");
+        self.stats.lock().unwrap().record_block();
+        if let Some(remaining) = &mut self.remaining_blocks {
+            *remaining -= 1;
+            if *remaining == 0 {
+                info!("Mined the requested number of blocks, pausing");
+                self.operating_state = OperatingState::Paused;
+                self.remaining_blocks = None;
+            }
+        }
+        // the next account in the rotation gets the next reward
+        self.next_reward = (self.next_reward + 1) % self.reward_addrs.len();
+        self.server.mark_originated(&[hash]);
+        self.server.broadcast(Message::NewBlockHashes(vec![hash]));
+    }
+
+    /// Drop the block being mined if it no longer extends the tip, e.g.
+    /// because a block arrived from the network in the meantime, so that a new
+    /// one is built on the new tip. Returns whether it was dropped
+    fn discard_if_stale(&self, current_block: &mut Option<Block>) -> bool {
+        let Some(block) = current_block else {
+            return false;
+        };
+        let tip_hash = self.blockchain.lock().expect("idk why this should succeed").tip_hash();
+        if block.header.parent == tip_hash {
+            return false;
+        }
+        debug!("The tip moved to {}, rebuilding the block being mined", tip_hash);
+        *current_block = None;
+        true
+    }
+
+    fn create_next_block(&mut self, starting_nonce: u32) -> Option<Block> {
         let blockchain = self.blockchain.lock().expect("idk why this should be safe");
         let parent_hash = blockchain.tip_hash();
-        let (parent_block, _, parent_state) = blockchain.tip_data();
-        let difficulty = parent_block.header.difficulty;
-
-        // attempt to build a block from the transactions in the mempool
-        let mut transactions = Vec::new();
-        let mut state = parent_state.clone();
-        for (_, transaction) in blockchain.mempool_transactions() {
-            if transactions.len() >= OUR_MAXIMUM_BLOCK_SIZE {
-                break;
-            }
+        let (_, parent_height, parent_state) = blockchain.tip_data();
+        let difficulty = blockchain.required_difficulty(&parent_hash).expect("the tip is in the chain");
+
+        // every block starts by paying us the reward and the fees
+        let reward_addr = self.reward_addrs[self.next_reward];
+        let with_coinbase = |transfers: Vec<&SignedTransaction>| -> Vec<SignedTransaction> {
+            let fees: u64 = transfers.iter().map(|transaction| transaction.raw_transaction.fee).sum();
+            let coinbase = SignedTransaction::coinbase(reward_addr, BLOCK_REWARD + fees, parent_height + 1);
+            iter::once(coinbase).chain(transfers.into_iter().cloned()).collect()
+        };
+
+        // attempt to build a block from the transactions in the mempool,
+        // skipping any that are somehow already in the chain we're extending
+        let candidates: Vec<&SignedTransaction> = blockchain
+            .mempool_transactions()
+            .filter(|(hash, _)| !blockchain.is_confirmed_in_chain(hash, &parent_hash))
+            .map(|(_, transaction)| transaction)
+            .collect();
+        // the selection and the checks below all try transactions out on
+        // this one copy, undoing them each time
+        let mut scratch_state = parent_state.clone();
+        let mut transactions = with_coinbase(select_transactions(&mut scratch_state, candidates.iter().copied(), OUR_MAXIMUM_BLOCK_SIZE));
 
-            if state.update_in_place(&transaction.raw_transaction) {
-                transactions.push(transaction);
-            // } else {
-            //     debug!("rejected tx: {:?}", &transaction);
+        // make sure the block would be accepted before grinding on it. The
+        // selection only checks the state, so e.g. a transaction imported
+        // into the mempool without validation can still spoil it
+        if let Err(reason) = dry_run(&transactions, &mut scratch_state) {
+            warn!("Candidate block would be rejected ({}), rebuilding it", reason);
+            let signed: Vec<SignedTransaction> = candidates.into_iter().cloned().collect();
+            let valid_signatures = verify_batch(&signed);
+            let candidates = signed
+                .iter()
+                .zip(valid_signatures)
+                .filter(|(_, valid)| *valid)
+                .map(|(transaction, _)| transaction);
+            transactions = with_coinbase(select_transactions(&mut scratch_state, candidates, OUR_MAXIMUM_BLOCK_SIZE));
+            if let Err(reason) = dry_run(&transactions, &mut scratch_state) {
+                warn!("Rebuilt candidate block would still be rejected ({})", reason);
+                return None;
             }
         }
-        if transactions.len() < OUR_MINIMUM_BLOCK_SIZE {
+        drop(blockchain);
+        // the coinbase doesn't count towards the size
+        let num_transactions = transactions.len() - 1;
+        if num_transactions < OUR_MINIMUM_BLOCK_SIZE && !self.allow_short_block(num_transactions) {
             // unable to build a block
             return None;
         }
+        self.short_since = None;
+        self.warned_short = false;
 
         // we have the transactions, now put them together into a block
         debug!("Creating the next block!");
-        let transactions: Vec<_> = transactions.into_iter().map(|tx| tx.clone()).collect();
-        drop(blockchain);
         let timestamp = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .expect("system time should always be after Unix epoch")
@@ -209,3 +446,654 @@ impl Context {
         })
     }
 }
+
+/// Move the header on to the nonce `step` places further. Rather than
+/// overflow, the nonce wraps around and the timestamp is bumped, so that the
+/// headers tried after wrapping are new ones
+fn advance_nonce(header: &mut Header, step: u32) {
+    match header.nonce.checked_add(step) {
+        Some(nonce) => header.nonce = nonce,
+        None => {
+            header.nonce = header.nonce.wrapping_add(step);
+            header.timestamp += 1;
+        }
+    }
+}
+
+/// Look for a nonce that makes the header meet its difficulty target, using
+/// `num_threads` threads. The nonce space is split into one part per thread,
+/// and each thread tries `attempts` nonces of its part, starting at
+/// `header.nonce` places into it. The first thread to find a nonce stops the
+/// others. Returns the nonce found, if any, and the number of nonces tried
+fn search_nonces(header: &Header, num_threads: usize, attempts: u32) -> (Option<u32>, u64) {
+    let part_size = ((u32::MAX as u64 + 1) / num_threads as u64) as u32;
+    let found = AtomicBool::new(false);
+    let winner = Mutex::new(None);
+    let nonces_tried = AtomicU64::new(0);
+    crossbeam::scope(|scope| {
+        for i in 0..num_threads {
+            let (found, winner, nonces_tried) = (&found, &winner, &nonces_tried);
+            scope.spawn(move |_| {
+                let mut header = header.clone();
+                let start = (i as u32).wrapping_mul(part_size).wrapping_add(header.nonce);
+                for attempt in 0..attempts {
+                    if found.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    nonces_tried.fetch_add(1, Ordering::Relaxed);
+                    header.nonce = start.wrapping_add(attempt);
+                    if header.hash() <= header.difficulty {
+                        // only the first thread to get here wins
+                        if !found.swap(true, Ordering::Relaxed) {
+                            *winner.lock().unwrap() = Some(header.nonce);
+                        }
+                        return;
+                    }
+                }
+            });
+        }
+    })
+    .expect("hashing threads shouldn't panic");
+    (winner.into_inner().unwrap(), nonces_tried.into_inner())
+}
+
+/// Choose up to `max_transactions` of the candidate transactions, in an order
+/// in which they can all be applied to `state` (see
+/// `mempool::apply_in_priority_order`). The state is left as it was
+fn select_transactions<'a>(
+    state: &mut State,
+    candidates: impl Iterator<Item = &'a SignedTransaction>,
+    max_transactions: usize,
+) -> Vec<&'a SignedTransaction> {
+    state.begin();
+    let selected = apply_in_priority_order(state, candidates, max_transactions);
+    state.rollback();
+    selected
+}
+
+/// Check that a block of these transactions would be accepted on top of
+/// `state`, leaving the state as it was
+fn dry_run(transactions: &[SignedTransaction], state: &mut State) -> Result<(), RejectReason> {
+    state.begin();
+    let result = validate_transactions_in_place(transactions, state);
+    state.rollback();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::mine_valid_block;
+    use crate::crypto::address::H160;
+    use crate::crypto::hash::H256;
+    use crate::crypto::key_pair::{self, get_deterministic_keypair};
+    use crate::network::{peer, server};
+    use crate::transaction::RawTransaction;
+    use ring::signature::KeyPair;
+
+    /// A server that is never started, for miners that only need a handle
+    fn idle_server() -> (server::Context, server::Handle) {
+        let (msg_tx, _msg_rx) = crossbeam::channel::unbounded();
+        server::new("127.0.0.1:0".parse().unwrap(), msg_tx, Default::default()).unwrap()
+    }
+
+    /// A listening server, with its address and the messages it receives
+    fn listening_server() -> (std::net::SocketAddr, server::Handle, crossbeam::channel::Receiver<(Vec<u8>, peer::Handle)>) {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let (msg_tx, msg_rx) = crossbeam::channel::unbounded();
+        let (server_ctx, server) = server::new(addr, msg_tx, Default::default()).unwrap();
+        server_ctx.start().unwrap();
+        (addr, server, msg_rx)
+    }
+
+    /// One transfer from each of the first `count` deterministic accounts, all
+    /// with the same nonce
+    fn transfers(count: usize, nonce: u32) -> Vec<SignedTransaction> {
+        let to_addr = H160::from_pubkey(get_deterministic_keypair(9).public_key().as_ref());
+        (0..count as u8)
+            .map(|i| {
+                let sender = get_deterministic_keypair(i);
+                let from_addr = H160::from_pubkey(sender.public_key().as_ref());
+                SignedTransaction::from_raw(RawTransaction { from_addr, to_addr, value: 1, fee: 0, nonce, memo: vec![], valid_since: None }, &sender)
+            })
+            .collect()
+    }
+
+    /// Put just enough transactions for a block into the mempool
+    fn fill_mempool(blockchain: &mut Blockchain, nonce: u32) {
+        for transaction in transfers(OUR_MINIMUM_BLOCK_SIZE, nonce) {
+            blockchain.insert_transaction_with_validation(transaction).unwrap();
+        }
+    }
+
+    #[test]
+    fn select_dependent_transactions() {
+        let mut state = State::ico();
+        let funder = get_deterministic_keypair(0);
+        let a = key_pair::random();
+        let b = key_pair::random();
+        let c = key_pair::random();
+        let address = |key: &ring::signature::Ed25519KeyPair| H160::from_pubkey(key.public_key().as_ref());
+
+        // A and B start with nothing, so B can only spend after A pays it
+        let fund_a = SignedTransaction::from_raw(
+            RawTransaction { from_addr: address(&funder), to_addr: address(&a), value: 10, fee: 0, nonce: 0, memo: vec![], valid_since: None },
+            &funder,
+        );
+        let a_to_b = SignedTransaction::from_raw(
+            RawTransaction { from_addr: address(&a), to_addr: address(&b), value: 10, fee: 0, nonce: 0, memo: vec![], valid_since: None },
+            &a,
+        );
+        let b_to_c = SignedTransaction::from_raw(
+            RawTransaction { from_addr: address(&b), to_addr: address(&c), value: 5, fee: 0, nonce: 0, memo: vec![], valid_since: None },
+            &b,
+        );
+
+        // offer them in the worst possible order
+        let candidates = [b_to_c.clone(), a_to_b.clone(), fund_a.clone()];
+        let selected = select_transactions(&mut state, candidates.iter(), OUR_MAXIMUM_BLOCK_SIZE);
+        let selected: Vec<_> = selected.into_iter().map(SignedTransaction::hash).collect();
+        assert_eq!(selected, vec![fund_a.hash(), a_to_b.hash(), b_to_c.hash()]);
+        // trying them out leaves the state alone
+        assert_eq!(state, State::ico());
+
+        // the limit is respected
+        let selected = select_transactions(&mut state, candidates.iter(), 2);
+        assert_eq!(selected.len(), 2);
+    }
+
+    #[test]
+    fn assemble_dependent_transactions_from_mempool() {
+        let (_server_ctx, server) = idle_server();
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let (mut ctx, _handle) = new(&server, Arc::clone(&blockchain), 1);
+        ctx.set_min_size_timeout(Some(Duration::ZERO));
+
+        let funder = get_deterministic_keypair(0);
+        let a = key_pair::random();
+        let b = key_pair::random();
+        let c = key_pair::random();
+        let address = |key: &ring::signature::Ed25519KeyPair| H160::from_pubkey(key.public_key().as_ref());
+        let transfer = |from: &ring::signature::Ed25519KeyPair, to, value, fee, nonce| {
+            SignedTransaction::from_raw(
+                RawTransaction { from_addr: address(from), to_addr: to, value, fee, nonce, memo: vec![], valid_since: None },
+                from,
+            )
+        };
+        // each spends what the one before it sends, or uses the funder's next
+        // nonce, and the last pays the highest fee
+        let fund_a = transfer(&funder, address(&a), 10, 0, 0);
+        let fund_c = transfer(&funder, address(&c), 10, 0, 1);
+        let a_to_b = transfer(&a, address(&b), 10, 0, 0);
+        let b_to_c = transfer(&b, address(&c), 4, 1, 0);
+        let chain = [fund_a, fund_c, a_to_b, b_to_c];
+        let mut blockchain_guard = blockchain.lock().unwrap();
+        for transaction in &chain {
+            assert_eq!(blockchain_guard.insert_transaction_with_validation(transaction.clone()), Ok(()));
+        }
+        // but a conflicting or overdrawn one still isn't let in
+        let overdrawn = transfer(&b, address(&a), 10, 0, 1);
+        assert_eq!(blockchain_guard.insert_transaction_with_validation(overdrawn), Err(RejectReason::InvalidState));
+        drop(blockchain_guard);
+
+        let candidate = ctx.create_next_block(0).unwrap();
+        let included: Vec<_> = candidate.content.transactions[1..].iter().map(SignedTransaction::hash).collect();
+        assert_eq!(included.len(), chain.len());
+        let position = |transaction: &SignedTransaction| included.iter().position(|hash| *hash == transaction.hash()).unwrap();
+        assert!(position(&chain[0]) < position(&chain[1]));
+        assert!(position(&chain[0]) < position(&chain[2]));
+        assert!(position(&chain[2]) < position(&chain[3]));
+        let hash = candidate.hash();
+        let mut blockchain = blockchain.lock().unwrap();
+        blockchain.set_instant_finality(true);
+        assert_eq!(blockchain.insert_block_with_validation(candidate).added, vec![hash]);
+        assert_eq!(blockchain.mempool_transactions().count(), 0);
+    }
+
+    #[test]
+    fn skip_already_confirmed_transactions() {
+        let (_server_ctx, server) = idle_server();
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let (mut ctx, _handle) = new(&server, Arc::clone(&blockchain), 1);
+
+        let transactions = transfers(OUR_MAXIMUM_BLOCK_SIZE, 0);
+        let confirmed = transactions[0].clone();
+
+        // confirm one transaction in a block, but leave it in the mempool
+        let mut blockchain = blockchain.lock().unwrap();
+        let mut block = crate::block::test::generate_random_block(&blockchain.tip_hash());
+        block.content.transactions = vec![confirmed.clone()];
+        blockchain.insert_block(block);
+        assert_eq!(blockchain.import_mempool(transactions, false), OUR_MAXIMUM_BLOCK_SIZE);
+        drop(blockchain);
+
+        let candidate = ctx.create_next_block(0).unwrap();
+        let included: Vec<_> = candidate.content.transactions.iter().map(SignedTransaction::hash).collect();
+        // every unconfirmed transaction, after the coinbase
+        assert_eq!(included.len(), OUR_MAXIMUM_BLOCK_SIZE);
+        assert!(candidate.content.transactions[0].is_coinbase());
+        assert!(!included.contains(&confirmed.hash()));
+    }
+
+    #[test]
+    fn mine_with_instant_finality() {
+        let (_server_ctx, server) = idle_server();
+        let mut blockchain = Blockchain::new();
+        blockchain.set_instant_finality(true);
+        fill_mempool(&mut blockchain, 0);
+        let genesis_hash = blockchain.tip_hash();
+        let blockchain = Arc::new(Mutex::new(blockchain));
+        let (ctx, handle) = new(&server, Arc::clone(&blockchain), 1);
+        ctx.start();
+
+        // the very first nonce tried is good enough, so the block is mined on
+        // the first iteration
+        handle.start(0);
+        let start = std::time::Instant::now();
+        while blockchain.lock().unwrap().tip_hash() == genesis_hash {
+            assert!(start.elapsed() < Duration::from_secs(5), "no block was mined");
+            thread::sleep(Duration::from_millis(1));
+        }
+        handle.exit();
+        let blockchain = blockchain.lock().unwrap();
+        let (tip, height, _) = blockchain.tip_data();
+        assert_eq!(height, 1);
+        assert_eq!(tip.content.transactions.len(), OUR_MINIMUM_BLOCK_SIZE + 1);
+    }
+
+    #[test]
+    fn pause_and_resume() {
+        let (_server_ctx, server) = idle_server();
+        let mut blockchain = Blockchain::new();
+        blockchain.set_instant_finality(true);
+        let blockchain = Arc::new(Mutex::new(blockchain));
+        let add_transactions = |nonce| fill_mempool(&mut blockchain.lock().unwrap(), nonce);
+        let wait_for_height = |height| {
+            let start = std::time::Instant::now();
+            while blockchain.lock().unwrap().tip_data().1 < height {
+                assert!(start.elapsed() < Duration::from_secs(5), "no block was mined");
+                thread::sleep(Duration::from_millis(1));
+            }
+        };
+        let (ctx, handle) = new(&server, Arc::clone(&blockchain), 1);
+        ctx.start();
+
+        add_transactions(0);
+        handle.start(0);
+        wait_for_height(1);
+
+        // a paused miner leaves a ready block alone
+        handle.pause();
+        thread::sleep(Duration::from_millis(100));
+        add_transactions(1);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(blockchain.lock().unwrap().tip_data().1, 1);
+
+        // and picks it up once started again
+        handle.start(0);
+        wait_for_height(2);
+        handle.exit();
+    }
+
+    #[test]
+    fn mine_fixed_number_of_blocks() {
+        let (_server_ctx, server) = idle_server();
+        let mut blockchain = Blockchain::new();
+        blockchain.set_instant_finality(true);
+        // more transactions than fit in one block
+        for i in 0..10 {
+            let sender = get_deterministic_keypair(i);
+            let from_addr = H160::from_pubkey(sender.public_key().as_ref());
+            let to_addr = H160::from_pubkey(get_deterministic_keypair((i + 1) % 10).public_key().as_ref());
+            let raw = RawTransaction { from_addr, to_addr, value: 1, fee: 0, nonce: 0, memo: vec![], valid_since: None };
+            blockchain.insert_transaction_with_validation(SignedTransaction::from_raw(raw, &sender)).unwrap();
+        }
+        let blockchain = Arc::new(Mutex::new(blockchain));
+        let (mut ctx, handle) = new(&server, Arc::clone(&blockchain), 1);
+        // the leftovers would make a block right away
+        ctx.set_min_size_timeout(Some(Duration::ZERO));
+        ctx.start();
+
+        handle.mine_count(1);
+        let start = std::time::Instant::now();
+        while blockchain.lock().unwrap().tip_data().1 < 1 {
+            assert!(start.elapsed() < Duration::from_secs(5), "no block was mined");
+            thread::sleep(Duration::from_millis(1));
+        }
+        // but the miner stops after the one block
+        thread::sleep(Duration::from_millis(100));
+        let blockchain_guard = blockchain.lock().unwrap();
+        assert_eq!(blockchain_guard.tip_data().1, 1);
+        assert_eq!(blockchain_guard.mempool_transactions().count(), 10 - OUR_MAXIMUM_BLOCK_SIZE);
+        drop(blockchain_guard);
+        let stats = handle.stats().lock().unwrap().clone();
+        assert_eq!(stats.blocks_mined, 1);
+        assert!(stats.nonces_tried >= 1);
+
+        // and counts again from a new request
+        handle.mine_count(1);
+        let start = std::time::Instant::now();
+        while blockchain.lock().unwrap().tip_data().1 < 2 {
+            assert!(start.elapsed() < Duration::from_secs(5), "no block was mined");
+            thread::sleep(Duration::from_millis(1));
+        }
+        handle.exit();
+    }
+
+    #[test]
+    fn nonce_wraps_around() {
+        let mut header = Block::genesis().header;
+        header.nonce = u32::MAX - 2;
+        let timestamp = header.timestamp;
+        let mut tried = vec![(header.timestamp, header.nonce)];
+        for _ in 0..4 {
+            advance_nonce(&mut header, 1);
+            tried.push((header.timestamp, header.nonce));
+        }
+        assert_eq!(
+            tried,
+            vec![
+                (timestamp, u32::MAX - 2),
+                (timestamp, u32::MAX - 1),
+                (timestamp, u32::MAX),
+                (timestamp + 1, 0),
+                (timestamp + 1, 1),
+            ]
+        );
+
+        // bigger steps wrap to the same place in the next round
+        header.nonce = u32::MAX - 1;
+        advance_nonce(&mut header, NONCES_PER_THREAD);
+        assert_eq!((header.timestamp, header.nonce), (timestamp + 2, NONCES_PER_THREAD - 2));
+    }
+
+    #[test]
+    fn search_nonces_in_parallel() {
+        let mut header = Block::genesis().header;
+        header.difficulty = H256::with_leading_zeros(2);
+        header.nonce = rand::random();
+        // enough attempts that some thread is all but certain to succeed
+        let nonce = search_nonces(&header, 4, 1 << 20).0.expect("no nonce found");
+        header.nonce = nonce;
+        assert!(header.hash() <= header.difficulty);
+
+        // an impossible target is never met
+        header.difficulty = H256::default();
+        assert_eq!(search_nonces(&header, 4, 16), (None, 4 * 16));
+    }
+
+    #[test]
+    fn mine_with_multiple_threads() {
+        let (_, server, _msg_rx) = listening_server();
+        let (peer_addr, _peer_server, peer_msg_rx) = listening_server();
+        // the peer might not be listening yet
+        let mut attempts = 0;
+        while server.connect(peer_addr).is_err() {
+            attempts += 1;
+            assert!(attempts < 100, "couldn't connect to {}", peer_addr);
+            thread::sleep(Duration::from_millis(10));
+        }
+        let mut blockchain = Blockchain::new();
+        fill_mempool(&mut blockchain, 0);
+        let genesis_hash = blockchain.tip_hash();
+        let blockchain = Arc::new(Mutex::new(blockchain));
+        let (ctx, handle) = new(&server, Arc::clone(&blockchain), 4);
+        ctx.start();
+
+        handle.start(0);
+        let start = std::time::Instant::now();
+        while blockchain.lock().unwrap().tip_hash() == genesis_hash {
+            assert!(start.elapsed() < Duration::from_secs(30), "no block was mined");
+            thread::sleep(Duration::from_millis(10));
+        }
+        // give a losing thread the chance to insert a block, if it could
+        thread::sleep(Duration::from_millis(100));
+        handle.exit();
+
+        let blockchain = blockchain.lock().unwrap();
+        let (tip, height, _) = blockchain.tip_data();
+        assert_eq!(height, 1);
+        assert!(tip.hash() <= tip.header.difficulty);
+        assert_eq!(blockchain.chain_tips().len(), 1);
+
+        // and the block is announced once
+        let mut announcements = vec![];
+        while let Ok((raw_msg, _)) = peer_msg_rx.recv_timeout(Duration::from_millis(200)) {
+            let msg: Message = bincode::deserialize(&raw_msg).unwrap();
+            if let Message::NewBlockHashes(hashes) = msg {
+                announcements.push(hashes);
+            }
+        }
+        assert_eq!(announcements, vec![vec![tip.hash()]]);
+    }
+
+    #[test]
+    fn rebuild_invalid_candidate() {
+        let (_server_ctx, server) = idle_server();
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let (mut ctx, _handle) = new(&server, Arc::clone(&blockchain), 1);
+
+        let mut transactions = transfers(OUR_MINIMUM_BLOCK_SIZE, 0);
+        // the state accepts this one, but it isn't signed by its sender
+        let from_addr = H160::from_pubkey(get_deterministic_keypair(8).public_key().as_ref());
+        let to_addr = H160::from_pubkey(get_deterministic_keypair(9).public_key().as_ref());
+        let forged = SignedTransaction::from_raw(
+            RawTransaction { from_addr, to_addr, value: 1, fee: 0, nonce: 0, memo: vec![], valid_since: None },
+            &get_deterministic_keypair(9),
+        );
+        transactions.push(forged.clone());
+        let mut blockchain_guard = blockchain.lock().unwrap();
+        blockchain_guard.set_instant_finality(true);
+        assert_eq!(blockchain_guard.import_mempool(transactions, false), OUR_MINIMUM_BLOCK_SIZE + 1);
+
+        // selecting by state alone would include it and spoil the block
+        let (_, _, state) = blockchain_guard.tip_data();
+        let mut state = state.clone();
+        let coinbase = SignedTransaction::coinbase(Default::default(), BLOCK_REWARD, 1);
+        let naive: Vec<_> = iter::once(&coinbase)
+            .chain(select_transactions(
+                &mut state,
+                blockchain_guard.mempool_transactions().map(|(_, transaction)| transaction),
+                OUR_MAXIMUM_BLOCK_SIZE,
+            ))
+            .cloned()
+            .collect();
+        assert!(naive.iter().any(|transaction| transaction.hash() == forged.hash()));
+        assert_eq!(dry_run(&naive, &mut state), Err(RejectReason::InvalidSignature));
+        drop(blockchain_guard);
+
+        let candidate = ctx.create_next_block(0).unwrap();
+        assert_eq!(candidate.content.transactions.len(), OUR_MINIMUM_BLOCK_SIZE + 1);
+        assert!(candidate.content.transactions.iter().all(|transaction| transaction.hash() != forged.hash()));
+        let hash = candidate.hash();
+        assert_eq!(blockchain.lock().unwrap().insert_block_with_validation(candidate).added, vec![hash]);
+    }
+
+    #[test]
+    fn wait_for_peers_to_mine() {
+        let (_, server, _msg_rx) = listening_server();
+        let (peer_addr, _peer_server, peer_msg_rx) = listening_server();
+
+        let mut blockchain = Blockchain::new();
+        blockchain.set_instant_finality(true);
+        fill_mempool(&mut blockchain, 0);
+        let genesis_hash = blockchain.tip_hash();
+        let blockchain = Arc::new(Mutex::new(blockchain));
+        let (mut ctx, handle) = new(&server, Arc::clone(&blockchain), 1);
+        ctx.set_min_peers_to_mine(Some(1));
+        ctx.start();
+
+        // without peers, nothing is mined, even though a block is ready
+        handle.start(0);
+        thread::sleep(3 * PEER_WAIT_INTERVAL);
+        assert_eq!(blockchain.lock().unwrap().tip_hash(), genesis_hash);
+
+        // with one, the block is mined and announced to it
+        server.connect(peer_addr).unwrap();
+        let start = std::time::Instant::now();
+        while blockchain.lock().unwrap().tip_hash() == genesis_hash {
+            assert!(start.elapsed() < Duration::from_secs(5), "no block was mined");
+            thread::sleep(Duration::from_millis(10));
+        }
+        handle.exit();
+        let tip_hash = blockchain.lock().unwrap().tip_hash();
+        // the connection itself starts with asking for the peer's tip
+        let (raw_msg, _) = peer_msg_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        let msg: Message = bincode::deserialize(&raw_msg).unwrap();
+        assert!(matches!(msg, Message::GetTip));
+        let (raw_msg, _) = peer_msg_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        let msg: Message = bincode::deserialize(&raw_msg).unwrap();
+        assert!(matches!(msg, Message::NewBlockHashes(hashes) if hashes == vec![tip_hash]));
+    }
+
+    #[test]
+    fn short_mempool() {
+        let (_server_ctx, server) = idle_server();
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let (mut ctx, _handle) = new(&server, Arc::clone(&blockchain), 1);
+
+        // nothing to mine is nothing to warn about
+        assert!(ctx.create_next_block(0).is_none());
+        assert!(ctx.short_since.is_none());
+
+        for transaction in transfers(OUR_MINIMUM_BLOCK_SIZE - 1, 0) {
+            blockchain.lock().unwrap().insert_transaction_with_validation(transaction).unwrap();
+        }
+
+        // too few transactions for a block are warned about eventually
+        assert!(ctx.create_next_block(0).is_none());
+        assert!(!ctx.warned_short);
+        ctx.short_since = Some(Instant::now() - SHORT_MEMPOOL_WARNING_DELAY);
+        assert!(ctx.create_next_block(0).is_none());
+        assert!(ctx.warned_short);
+
+        // with a timeout, they are mined anyway
+        ctx.set_min_size_timeout(Some(Duration::from_millis(50)));
+        ctx.short_since = None;
+        assert!(ctx.create_next_block(0).is_none());
+        thread::sleep(Duration::from_millis(50));
+        let block = ctx.create_next_block(0).unwrap();
+        assert_eq!(block.content.transactions.len(), OUR_MINIMUM_BLOCK_SIZE);
+        assert!(ctx.short_since.is_none());
+    }
+
+    #[test]
+    fn highest_fees_first() {
+        let (_server_ctx, server) = idle_server();
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let (mut ctx, _handle) = new(&server, Arc::clone(&blockchain), 1);
+        let reward_addr: H160 = [7; 20].into();
+        ctx.set_reward_address(reward_addr);
+
+        // more transactions than fit in a block, each paying a different fee
+        let to_addr = H160::from_pubkey(get_deterministic_keypair(9).public_key().as_ref());
+        let num_transactions = OUR_MAXIMUM_BLOCK_SIZE as u64 + 2;
+        for i in 0..num_transactions {
+            let sender = get_deterministic_keypair(i as u8);
+            let from_addr = H160::from_pubkey(sender.public_key().as_ref());
+            let raw = RawTransaction { from_addr, to_addr, value: 1, fee: i, nonce: 0, memo: vec![], valid_since: None };
+            let transaction = SignedTransaction::from_raw(raw, &sender);
+            blockchain.lock().unwrap().insert_transaction_with_validation(transaction).unwrap();
+        }
+
+        let block = ctx.create_next_block(0).unwrap();
+        let (coinbase, transfers) = block.content.transactions.split_first().unwrap();
+        let mut fees: Vec<u64> = transfers.iter().map(|transaction| transaction.raw_transaction.fee).collect();
+        fees.sort();
+        let expected: Vec<u64> = (num_transactions - OUR_MAXIMUM_BLOCK_SIZE as u64..num_transactions).collect();
+        assert_eq!(fees, expected);
+        assert_eq!(coinbase.raw_transaction.value, BLOCK_REWARD + expected.iter().sum::<u64>());
+
+        // the fees move from the senders to the miner
+        let sender = H160::from_pubkey(get_deterministic_keypair(num_transactions as u8 - 1).public_key().as_ref());
+        let sender_balance = State::ico().get_acc_info(&sender).unwrap().balance;
+        let hash = block.hash();
+        let mut blockchain = blockchain.lock().unwrap();
+        blockchain.set_instant_finality(true);
+        assert_eq!(blockchain.insert_block_with_validation(block.clone()).added, vec![hash]);
+        let (_, _, state) = blockchain.tip_data();
+        assert_eq!(state.get_acc_info(&reward_addr).unwrap().balance, coinbase.raw_transaction.value);
+        assert_eq!(state.get_acc_info(&sender).unwrap().balance, sender_balance - 1 - (num_transactions - 1));
+    }
+
+    #[test]
+    fn timelocked_transactions_wait() {
+        let mut blockchain = Blockchain::new();
+        blockchain.set_instant_finality(true);
+        let sender = get_deterministic_keypair(0);
+        let from_addr = H160::from_pubkey(sender.public_key().as_ref());
+        let to_addr = H160::from_pubkey(get_deterministic_keypair(1).public_key().as_ref());
+        let genesis_timestamp = blockchain.tip_data().0.header.timestamp;
+        let valid_since = Some(genesis_timestamp + 2);
+        let raw = RawTransaction { from_addr, to_addr, value: 1, fee: 0, nonce: 0, memo: vec![], valid_since };
+        let transaction = SignedTransaction::from_raw(raw, &sender);
+        blockchain.insert_transaction_with_validation(transaction.clone()).unwrap();
+
+        // it waits in the mempool, and blocks that include it early are invalid
+        let selectable = |blockchain: &Blockchain| {
+            let (_, _, state) = blockchain.tip_data();
+            select_transactions(&mut state.clone(), blockchain.mempool_transactions().map(|(_, tx)| tx), 10).len()
+        };
+        assert_eq!(selectable(&blockchain), 0);
+        let early = mine_valid_block(&blockchain, vec![transaction.clone()]);
+        assert_eq!(blockchain.insert_block_with_validation(early).rejected, Some(RejectReason::InvalidState));
+        let block = mine_valid_block(&blockchain, vec![]);
+        blockchain.insert_block_with_validation(block);
+        assert_eq!(selectable(&blockchain), 0);
+        assert!(blockchain.get_transaction(&transaction.hash()).is_some());
+
+        // once the tip reaches its time, it can be mined
+        let block = mine_valid_block(&blockchain, vec![]);
+        blockchain.insert_block_with_validation(block);
+        assert_eq!(selectable(&blockchain), 1);
+        let block = mine_valid_block(&blockchain, vec![transaction]);
+        assert_eq!(blockchain.insert_block_with_validation(block.clone()).added, vec![block.hash()]);
+    }
+
+    #[test]
+    fn rebuild_on_new_tip() {
+        let (_server_ctx, server) = idle_server();
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let (mut ctx, _handle) = new(&server, Arc::clone(&blockchain), 1);
+        fill_mempool(&mut blockchain.lock().unwrap(), 0);
+
+        let mut current_block = ctx.create_next_block(0);
+        let genesis_hash = current_block.as_ref().unwrap().header.parent;
+        assert!(!ctx.discard_if_stale(&mut current_block));
+        assert!(current_block.is_some());
+
+        // a competing block arrives and becomes the tip
+        let competing = crate::block::test::generate_random_block(&genesis_hash);
+        blockchain.lock().unwrap().insert_block(competing.clone());
+        assert!(ctx.discard_if_stale(&mut current_block));
+        assert!(current_block.is_none());
+        let rebuilt = ctx.create_next_block(0).unwrap();
+        assert_eq!(rebuilt.header.parent, competing.hash());
+    }
+
+    #[test]
+    fn rotate_reward_address() {
+        let (_server_ctx, server) = idle_server();
+        let mut blockchain = Blockchain::new();
+        blockchain.set_instant_finality(true);
+        let blockchain = Arc::new(Mutex::new(blockchain));
+        let (mut ctx, _handle) = new(&server, Arc::clone(&blockchain), 1);
+        let pool: Vec<H160> = (1..=3).map(|i| [i; 20].into()).collect();
+        ctx.set_reward_rotation(pool.clone());
+
+        for nonce in 0..7 {
+            fill_mempool(&mut blockchain.lock().unwrap(), nonce);
+            let block = ctx.create_next_block(0).unwrap();
+            let hash = block.hash();
+            ctx.finish_block(block);
+            assert_eq!(blockchain.lock().unwrap().tip_hash(), hash);
+        }
+
+        // seven blocks go round the pool twice, and once more to the first
+        let blockchain = blockchain.lock().unwrap();
+        let (_, height, state) = blockchain.tip_data();
+        assert_eq!(height, 7);
+        let rewards: Vec<u64> = pool.iter().map(|addr| state.get_acc_info(addr).unwrap().balance).collect();
+        assert_eq!(rewards, vec![3 * BLOCK_REWARD, 2 * BLOCK_REWARD, 2 * BLOCK_REWARD]);
+    }
+}
@@ -1,6 +1,17 @@
-use crate::{block::Block, crypto::hash::H256, transaction::SignedTransaction};
+use crate::{
+    block::{Block, Header},
+    crypto::hash::H256,
+    transaction::SignedTransaction,
+};
 use serde::{Deserialize, Serialize};
 
+/// A reference to a block or transaction, for announcing or requesting them
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvItem {
+    Block(H256),
+    Transaction(H256),
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
     Ping(String),
@@ -11,4 +22,93 @@ pub enum Message {
     NewTransactionHashes(Vec<H256>),
     GetTransactions(Vec<H256>),
     Transactions(Vec<SignedTransaction>),
+    /// Announces blocks and transactions together
+    Inv(Vec<InvItem>),
+    /// Requests blocks and transactions together
+    GetData(Vec<InvItem>),
+    /// Requests the headers of the blocks after the first of these blocks
+    /// that is on the peer's longest chain (see `Blockchain::block_locator`)
+    GetHeaders(Vec<H256>),
+    /// Headers of consecutive blocks, oldest first, so that their proof of
+    /// work can be checked before the blocks are downloaded
+    Headers(Vec<Header>),
+    /// Asks for the hash of the tip of the peer's longest chain, which nodes
+    /// do as soon as they connect so that they can catch up
+    GetTip,
+    /// The hash of the tip of the sender's longest chain
+    Tip(H256),
+}
+
+impl Message {
+    /// A short name for the type of message
+    pub fn name(&self) -> &'static str {
+        match self {
+            Message::Ping(_) => "ping",
+            Message::Pong(_) => "pong",
+            Message::NewBlockHashes(_) => "new_block_hashes",
+            Message::GetBlocks(_) => "get_blocks",
+            Message::Blocks(_) => "blocks",
+            Message::NewTransactionHashes(_) => "new_transaction_hashes",
+            Message::GetTransactions(_) => "get_transactions",
+            Message::Transactions(_) => "transactions",
+            Message::Inv(_) => "inv",
+            Message::GetData(_) => "get_data",
+            Message::GetHeaders(_) => "get_headers",
+            Message::Headers(_) => "headers",
+            Message::GetTip => "get_tip",
+            Message::Tip(_) => "tip",
+        }
+    }
+
+    /// Whether the message tells peers about new blocks or transactions, as
+    /// opposed to answering a request
+    pub fn is_announcement(&self) -> bool {
+        matches!(
+            self,
+            Message::NewBlockHashes(_) | Message::NewTransactionHashes(_) | Message::Inv(_)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::hash::Hashable;
+
+    #[test]
+    fn bincode_round_trip() {
+        let hash = H256::from([1; 32]);
+        let block = Block::genesis();
+        let transaction = SignedTransaction::generate_random();
+        let messages = vec![
+            Message::Ping(String::from("ping")),
+            Message::Pong(String::from("pong")),
+            Message::NewBlockHashes(vec![hash]),
+            Message::GetBlocks(vec![hash]),
+            Message::Blocks(vec![block.clone()]),
+            Message::NewTransactionHashes(vec![hash, transaction.hash()]),
+            Message::GetTransactions(vec![transaction.hash()]),
+            Message::Transactions(vec![transaction.clone()]),
+            Message::Inv(vec![InvItem::Block(hash), InvItem::Transaction(transaction.hash())]),
+            Message::GetData(vec![InvItem::Transaction(hash)]),
+            Message::GetHeaders(vec![block.hash()]),
+            Message::Headers(vec![block.header.clone()]),
+            Message::GetTip,
+            Message::Tip(hash),
+        ];
+        for msg in messages {
+            let bytes = bincode::serialize(&msg).unwrap();
+            let decoded: Message = bincode::deserialize(&bytes).unwrap();
+            assert_eq!(decoded.name(), msg.name());
+            assert_eq!(bincode::serialize(&decoded).unwrap(), bytes);
+        }
+
+        // the payloads survive, not just the encoding
+        let bytes = bincode::serialize(&Message::Transactions(vec![transaction.clone()])).unwrap();
+        let Message::Transactions(decoded) = bincode::deserialize(&bytes).unwrap() else {
+            panic!("decoded as another variant");
+        };
+        assert_eq!(decoded[0].hash(), transaction.hash());
+        assert_eq!(decoded[0].verify_signature(), transaction.verify_signature());
+    }
 }
@@ -1,5 +1,5 @@
 use super::message;
-use log::{trace, warn};
+use log::trace;
 use mio;
 use mio_extras::channel;
 use std::convert::TryInto;
@@ -192,6 +192,7 @@ pub fn new(
         writer: write_ctx,
         handle: handle.clone(),
         direction,
+        last_read: std::time::Instant::now(),
     };
     Ok((ctx, handle))
 }
@@ -209,6 +210,8 @@ pub struct Context {
     pub writer: WriteContext,
     pub handle: Handle,
     pub direction: Direction,
+    /// When we last received data from this peer
+    pub last_read: std::time::Instant,
 }
 
 #[derive(Clone)]
@@ -218,14 +221,29 @@ pub struct Handle {
 }
 
 impl Handle {
-    pub fn write(&self, msg: message::Message) {
-        // TODO: return result
-        let buffer = bincode::serialize(&msg).unwrap();
-        if self.write_queue.send(buffer).is_err() {
-            warn!(
-                "Failed to send write request for peer {}, channel detached",
-                self.addr
-            );
-        }
+    /// The address of the peer
+    pub fn addr(&self) -> std::net::SocketAddr {
+        self.addr
+    }
+
+    /// Queue a message to be written to the peer. Fails if the message
+    /// couldn't be queued, e.g. because the connection is gone
+    pub fn write(&self, msg: &message::Message) -> std::io::Result<()> {
+        let buffer = bincode::serialize(msg).unwrap();
+        self.write_queue.send(buffer).map_err(|e| match e {
+            channel::SendError::Io(e) => e,
+            channel::SendError::Disconnected(_) => std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "write channel detached",
+            ),
+        })
     }
 }
+
+/// Create a handle to a pretend peer at the given address, along with the
+/// queue of serialized messages written to it
+#[cfg(any(test, feature = "test-utilities"))]
+pub fn test_handle(addr: std::net::SocketAddr) -> (Handle, channel::Receiver<Vec<u8>>) {
+    let (write_queue, written) = channel::channel();
+    (Handle { addr, write_queue }, written)
+}
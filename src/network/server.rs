@@ -1,27 +1,62 @@
 use super::message;
 use super::peer::{self, ReadResult, WriteResult};
+use crate::crypto::hash::H256;
 use crossbeam::channel as cbchannel;
 use log::{debug, error, info, trace, warn};
 use mio::{self, net};
 use mio_extras::channel;
-use std::sync::mpsc;
+use rand::seq::SliceRandom;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
 const MAX_INCOMING_CLIENT: usize = 256;
 const MAX_EVENT: usize = 1024;
 
+/// How many hashes of blocks and transactions we created are remembered, to
+/// recognize announcements of them echoed back by peers
+const ORIGINATED_CAPACITY: usize = 1024;
+
+/// Tunable parameters of the P2P server
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    /// The maximum number of pending connections waiting to be accepted
+    pub listen_backlog: i32,
+    /// How long a peer may go without sending us any data before we
+    /// disconnect it. `None` means idle peers are never disconnected
+    pub read_timeout: Option<Duration>,
+    /// How many randomly chosen peers to send each announcement to. Gossip
+    /// carries it to the rest of the network. `None` means every peer
+    pub broadcast_fanout: Option<usize>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen_backlog: 1024,
+            read_timeout: None,
+            broadcast_fanout: None,
+        }
+    }
+}
+
 pub fn new(
     addr: std::net::SocketAddr,
     msg_sink: cbchannel::Sender<(Vec<u8>, peer::Handle)>,
+    config: Config,
 ) -> std::io::Result<(Context, Handle)> {
     let (control_signal_sender, control_signal_receiver) = channel::channel();
     let handle = Handle {
         control_chan: control_signal_sender,
+        pending_pings: Arc::new(Mutex::new(HashMap::new())),
+        originated: Default::default(),
     };
     let ctx = Context {
         peers: slab::Slab::new(),
         peer_list: vec![],
         addr,
+        config,
         poll: mio::Poll::new()?,
         control_chan: control_signal_receiver,
         new_msg_chan: msg_sink,
@@ -34,6 +69,7 @@ pub struct Context {
     peers: slab::Slab<peer::Context>,
     peer_list: Vec<usize>,
     addr: std::net::SocketAddr,
+    config: Config,
     poll: mio::Poll,
     control_chan: channel::Receiver<ControlSignal>,
     new_msg_chan: cbchannel::Sender<(Vec<u8>, peer::Handle)>,
@@ -62,8 +98,7 @@ impl Context {
         let key: usize = vacant.key();
         if key >= MAX_INCOMING_CLIENT {
             // too many connections
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
+            return Err(std::io::Error::other(
                 "max peer reached, cannot accept new connections",
             ));
         }
@@ -89,6 +124,12 @@ impl Context {
             mio::PollOpt::edge() | mio::PollOpt::oneshot(),
         )?;
 
+        // ask the peer for its tip right away, so that a node that has just
+        // started catches up without waiting for the next block to be mined
+        if let Err(e) = handle.write(&message::Message::GetTip) {
+            warn!("Failed to ask peer {} for its tip: {}", ctx.addr, e);
+        }
+
         // insert the context and return the handle
         vacant.insert(ctx);
         // record the key of this peer
@@ -133,10 +174,54 @@ impl Context {
             }
             ControlSignal::BroadcastMessage(msg) => {
                 trace!("Processing BroadcastMessage command");
-                for peer_id in &self.peer_list {
-                    self.peers[*peer_id].handle.write(msg.clone());
+                let recipients: Vec<usize> = match self.config.broadcast_fanout {
+                    Some(fanout) if msg.is_announcement() => self
+                        .peer_list
+                        .choose_multiple(&mut rand::thread_rng(), fanout)
+                        .copied()
+                        .collect(),
+                    _ => self.peer_list.clone(),
+                };
+                for peer_id in recipients {
+                    let peer = &self.peers[peer_id];
+                    if let Err(e) = peer.handle.write(&msg) {
+                        warn!("Failed to send message to peer {}: {}", peer.addr, e);
+                    }
+                }
+            }
+            ControlSignal::SendMessage(peer_id, msg) => {
+                trace!("Processing SendMessage command");
+                match self.peers.get(peer_id) {
+                    Some(peer) => {
+                        if let Err(e) = peer.handle.write(&msg) {
+                            warn!("Failed to send message to peer {}: {}", peer.addr, e);
+                        }
+                    }
+                    None => warn!("Cannot send message to nonexistent peer {}", peer_id),
+                }
+            }
+            ControlSignal::DisconnectPeer(addr) => {
+                trace!("Processing DisconnectPeer command");
+                let peer_id = self.peer_list.iter().copied().find(|&peer_id| self.peers[peer_id].addr == addr);
+                match peer_id {
+                    Some(peer_id) => {
+                        info!("Disconnecting peer {}", addr);
+                        self.peers.remove(peer_id);
+                        let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
+                        self.peer_list.swap_remove(index);
+                    }
+                    None => warn!("Cannot disconnect nonexistent peer {}", addr),
                 }
             }
+            ControlSignal::ListPeers(result_chan) => {
+                trace!("Processing ListPeers command");
+                let peers = self
+                    .peer_list
+                    .iter()
+                    .map(|&peer_id| (peer_id, self.peers[peer_id].addr))
+                    .collect();
+                result_chan.send(peers).unwrap();
+            }
         }
         Ok(())
     }
@@ -171,11 +256,13 @@ impl Context {
                 }
                 Ok(ReadResult::Continue) => {
                     trace!("Peer {} reading continue", peer_id);
+                    peer.last_read = Instant::now();
                     // no full message has been received
                     continue;
                 }
                 Ok(ReadResult::Message(m)) => {
                     trace!("Peer {} yield message", peer_id);
+                    peer.last_read = Instant::now();
                     // we just received a full message
                     self.new_msg_chan.send((m, peer.handle.clone())).unwrap();
                     continue;
@@ -255,13 +342,43 @@ impl Context {
         Ok(())
     }
 
+    /// Disconnect all peers that haven't sent us any data within the read
+    /// timeout
+    fn drop_idle_peers(&mut self, read_timeout: Duration) {
+        let idle_peers: Vec<usize> = self
+            .peer_list
+            .iter()
+            .copied()
+            .filter(|&peer_id| self.peers[peer_id].last_read.elapsed() > read_timeout)
+            .collect();
+        for peer_id in idle_peers {
+            info!("Peer {} timed out, disconnecting", self.peers[peer_id].addr);
+            self.peers.remove(peer_id);
+            let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
+            self.peer_list.swap_remove(index);
+        }
+    }
+
+    /// Bind the listening socket with the configured backlog
+    fn bind(&self) -> std::io::Result<net::TcpListener> {
+        let builder = match self.addr {
+            std::net::SocketAddr::V4(_) => net2::TcpBuilder::new_v4()?,
+            std::net::SocketAddr::V6(_) => net2::TcpBuilder::new_v6()?,
+        };
+        let listener = builder
+            .reuse_address(true)?
+            .bind(self.addr)?
+            .listen(self.config.listen_backlog)?;
+        net::TcpListener::from_std(listener)
+    }
+
     /// The main event loop of the server.
     fn listen(&mut self) -> std::io::Result<()> {
         // bind server to passed addr and register to the poll
-        let server = net::TcpListener::bind(&self.addr)?;
+        let server = self.bind()?;
 
         // token for new incoming connection
-        const INCOMING: mio::Token = mio::Token(std::usize::MAX - 1);
+        const INCOMING: mio::Token = mio::Token(usize::MAX - 1);
         self.poll.register(
             &server,
             INCOMING,
@@ -270,7 +387,7 @@ impl Context {
         )?;
 
         // token for new control signal from the handle
-        const CONTROL: mio::Token = mio::Token(std::usize::MAX - 2);
+        const CONTROL: mio::Token = mio::Token(usize::MAX - 2);
         self.poll.register(
             &self.control_chan,
             CONTROL,
@@ -283,8 +400,14 @@ impl Context {
         // initialize space for polled events
         let mut events = mio::Events::with_capacity(MAX_EVENT);
 
+        // if idle peers time out, wake up regularly to check for them
+        let poll_timeout = self.config.read_timeout.map(|read_timeout| read_timeout / 2);
+
         loop {
-            self.poll.poll(&mut events, None)?;
+            self.poll.poll(&mut events, poll_timeout)?;
+            if let Some(read_timeout) = self.config.read_timeout {
+                self.drop_idle_peers(read_timeout);
+            }
 
             for event in events.iter() {
                 match event.token() {
@@ -367,6 +490,19 @@ impl Context {
 #[derive(Clone)]
 pub struct Handle {
     control_chan: channel::Sender<ControlSignal>,
+    /// Maps the nonce of each ping awaiting a pong to the channel used to
+    /// notify the pinger
+    pending_pings: Arc<Mutex<HashMap<String, cbchannel::Sender<Instant>>>>,
+    /// The hashes of the blocks and transactions we created recently
+    originated: Arc<Mutex<OriginatedHashes>>,
+}
+
+/// A bounded set of hashes, which forgets the oldest ones first
+#[derive(Default)]
+struct OriginatedHashes {
+    hashes: HashSet<H256>,
+    /// The same hashes, oldest first
+    order: VecDeque<H256>,
 }
 
 impl Handle {
@@ -387,14 +523,171 @@ impl Handle {
             .send(ControlSignal::BroadcastMessage(msg))
             .unwrap();
     }
+
+    /// Send a message to a single peer
+    pub fn send(&self, peer_id: usize, msg: message::Message) {
+        self.control_chan
+            .send(ControlSignal::SendMessage(peer_id, msg))
+            .unwrap();
+    }
+
+    /// Disconnect the peer with the given address, if it's connected
+    pub fn disconnect(&self, addr: std::net::SocketAddr) {
+        self.control_chan
+            .send(ControlSignal::DisconnectPeer(addr))
+            .unwrap();
+    }
+
+    /// Get the id and address of every connected peer
+    pub fn peers(&self) -> Vec<(usize, std::net::SocketAddr)> {
+        let (sender, receiver) = cbchannel::unbounded();
+        self.control_chan
+            .send(ControlSignal::ListPeers(sender))
+            .unwrap();
+        receiver.recv().unwrap()
+    }
+
+    /// Ping a peer and wait for its pong, returning the round-trip time
+    pub fn ping(&self, peer_id: usize, timeout: Duration) -> Result<Duration, String> {
+        if !self.peers().iter().any(|&(id, _)| id == peer_id) {
+            return Err(format!("no peer with id {}", peer_id));
+        }
+
+        let nonce = format!("{:016x}", rand::random::<u64>());
+        let (sender, receiver) = cbchannel::bounded(1);
+        self.pending_pings.lock().unwrap().insert(nonce.clone(), sender);
+        let start = Instant::now();
+        self.send(peer_id, message::Message::Ping(nonce.clone()));
+        let result = receiver.recv_timeout(timeout);
+        self.pending_pings.lock().unwrap().remove(&nonce);
+        match result {
+            Ok(received) => Ok(received - start),
+            Err(_) => Err(format!("peer {} did not respond within {:?}", peer_id, timeout)),
+        }
+    }
+
+    /// Remember that we created these blocks or transactions, so that peers
+    /// announcing them back to us can be ignored cheaply
+    pub fn mark_originated(&self, hashes: &[H256]) {
+        let mut originated = self.originated.lock().unwrap();
+        for hash in hashes {
+            if originated.hashes.insert(*hash) {
+                originated.order.push_back(*hash);
+            }
+        }
+        while originated.order.len() > ORIGINATED_CAPACITY {
+            let oldest = originated.order.pop_front().expect("more than the capacity");
+            originated.hashes.remove(&oldest);
+        }
+    }
+
+    /// Whether we recently created the block or transaction with this hash
+    pub fn is_originated(&self, hash: &H256) -> bool {
+        self.originated.lock().unwrap().hashes.contains(hash)
+    }
+
+    /// Notify whoever is waiting for a pong with the given nonce, if anyone
+    pub fn resolve_pong(&self, nonce: &str) {
+        let received = Instant::now();
+        if let Some(sender) = self.pending_pings.lock().unwrap().remove(nonce) {
+            let _ = sender.send(received);
+        }
+    }
 }
 
 enum ControlSignal {
     ConnectNewPeer(ConnectRequest),
     BroadcastMessage(message::Message),
+    SendMessage(usize, message::Message),
+    DisconnectPeer(std::net::SocketAddr),
+    ListPeers(cbchannel::Sender<Vec<(usize, std::net::SocketAddr)>>),
 }
 
 struct ConnectRequest {
     addr: std::net::SocketAddr,
     result_chan: cbchannel::Sender<std::io::Result<peer::Handle>>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn idle_peer_times_out() {
+        // find a free port to listen on
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let (msg_tx, _msg_rx) = cbchannel::unbounded();
+        let config = Config {
+            read_timeout: Some(Duration::from_millis(200)),
+            ..Default::default()
+        };
+        let (server_ctx, _server) = new(addr, msg_tx, config).unwrap();
+        server_ctx.start().unwrap();
+
+        // connect a peer that never sends anything
+        let mut stream = loop {
+            match std::net::TcpStream::connect(addr) {
+                Ok(stream) => break stream,
+                Err(_) => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        // the server hangs up on it, after asking for its tip
+        let start = Instant::now();
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).unwrap();
+        let get_tip = bincode::serialize(&message::Message::GetTip).unwrap();
+        assert_eq!(&buf[std::mem::size_of::<u32>()..], &get_tip[..]);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn announcements_reach_fanout_peers() {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let (msg_tx, _msg_rx) = cbchannel::unbounded();
+        let config = Config {
+            broadcast_fanout: Some(2),
+            ..Default::default()
+        };
+        let (server_ctx, server) = new(addr, msg_tx, config).unwrap();
+        server_ctx.start().unwrap();
+
+        let mut streams: Vec<_> = (0..6)
+            .map(|_| loop {
+                match std::net::TcpStream::connect(addr) {
+                    Ok(stream) => break stream,
+                    Err(_) => thread::sleep(Duration::from_millis(10)),
+                }
+            })
+            .collect();
+        while server.peers().len() < streams.len() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        // every peer is first asked for its tip
+        let get_tip_len = std::mem::size_of::<u32>() + bincode::serialize(&message::Message::GetTip).unwrap().len();
+        for stream in &mut streams {
+            let mut get_tip = vec![0; get_tip_len];
+            stream.read_exact(&mut get_tip).unwrap();
+        }
+
+        // count the peers that hear about something
+        let mut num_reached = |msg| {
+            server.broadcast(msg);
+            streams
+                .iter_mut()
+                .map(|stream| {
+                    stream.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+                    let mut buf = [0; 1024];
+                    stream.read(&mut buf).is_ok_and(|len| len > 0)
+                })
+                .filter(|&reached| reached)
+                .count()
+        };
+        assert_eq!(num_reached(message::Message::NewBlockHashes(vec![Default::default()])), 2);
+        assert_eq!(num_reached(message::Message::NewTransactionHashes(vec![Default::default()])), 2);
+        // other messages still go to everyone
+        assert_eq!(num_reached(message::Message::Ping(String::from("hello"))), 6);
+    }
+}
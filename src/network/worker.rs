@@ -1,25 +1,135 @@
-use super::message::Message;
+use super::message::{InvItem, Message};
 use super::peer;
 use crate::{
-    block::Block,
-    blockchain::Blockchain,
+    block::{Block, Header},
+    blockchain::{Blockchain, RejectReason},
     crypto::hash::{Hashable, H256},
+    metrics::LatencyStats,
     network::server::Handle as ServerHandle,
-    transaction::SignedTransaction as Transaction
+    transaction::{verify_batch, SignedTransaction as Transaction},
 };
 use crossbeam::channel;
 use log::{debug, warn};
+use serde::Serialize;
 use std::{
-    sync::{Arc, Mutex},
-    thread, time::SystemTime,
+    collections::{HashMap, HashSet, VecDeque},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 
+/// How many waiting orphans to try connecting after each `Blocks` message
+const ORPHANS_PER_MESSAGE: usize = 16;
+
+/// How many times to try writing a message to a peer before giving up on the
+/// peer
+const WRITE_ATTEMPTS: usize = 3;
+
+/// How long to wait before trying a failed write again
+const WRITE_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// How many `Blocks` and `Transactions` messages are kept while paused. More
+/// are dropped
+const PAUSED_QUEUE_CAPACITY: usize = 256;
+
+/// The most headers sent in one `Headers` message. A peer that sends this many
+/// probably has more
+const MAX_HEADERS_PER_MESSAGE: usize = 500;
+
+/// The most blocks or transactions a peer may send in one message. Larger
+/// messages are dropped without looking at their contents
+const MAX_ITEMS_PER_MESSAGE: usize = 1000;
+
+/// How many invalid blocks a peer may send before it is banned
+const BAN_SCORE: u64 = 10;
+
+/// Which blocks received from peers are considered for the blockchain
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BlockPolicy {
+    /// Accept any valid block, even if we didn't ask for it
+    #[default]
+    Promiscuous,
+    /// Only accept blocks that we requested with `GetBlocks`, to save
+    /// bandwidth and processing on constrained nodes
+    Strict,
+}
+
+/// How long a requested block is waited for before the request is forgotten
+const BLOCK_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The blocks we requested that haven't arrived yet, with when they were
+/// requested. Requests that peers never answer expire after
+/// `BLOCK_REQUEST_TIMEOUT`, so that they don't pile up
+#[derive(Default)]
+struct RequestedBlocks(HashMap<H256, Instant>);
+
+impl RequestedBlocks {
+    /// Remember that these blocks were just requested, forgetting the
+    /// requests that expired
+    fn record(&mut self, hashes: impl IntoIterator<Item = H256>) {
+        let now = Instant::now();
+        self.0.retain(|_, requested_at| now.duration_since(*requested_at) < BLOCK_REQUEST_TIMEOUT);
+        self.0.extend(hashes.into_iter().map(|hash| (hash, now)));
+    }
+
+    /// Forget the request for a block that arrived. Returns whether it was
+    /// requested, and the request hadn't expired
+    fn remove(&mut self, hash: &H256) -> bool {
+        self.0.remove(hash).is_some_and(|requested_at| requested_at.elapsed() < BLOCK_REQUEST_TIMEOUT)
+    }
+}
+
+/// Counters for the messages received from a peer
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerStats {
+    /// The number of messages of each type
+    pub messages_received: HashMap<String, u64>,
+    pub bytes_received: u64,
+    /// The number of messages that couldn't be decoded
+    pub invalid_messages: u64,
+    /// The number of blocks the peer sent that we added to the blockchain
+    pub blocks_contributed: u64,
+    /// The number of blocks the peer sent that were invalid
+    pub rejected_blocks: u64,
+    /// The number of transactions the peer sent that we added to the mempool
+    pub transactions_contributed: u64,
+}
+
+/// The stats of every peer that has sent us a message, keyed by address
+pub type PeerStatsTable = Arc<Mutex<HashMap<SocketAddr, PeerStats>>>;
+
 #[derive(Clone)]
 pub struct Context {
     msg_chan: channel::Receiver<(Vec<u8>, peer::Handle)>,
     num_worker: usize,
     server: ServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
+    block_policy: BlockPolicy,
+    /// The blocks we requested that haven't arrived yet
+    requested_blocks: Arc<Mutex<RequestedBlocks>>,
+    peer_stats: PeerStatsTable,
+    /// Whether incoming blocks and transactions are queued instead of
+    /// processed
+    paused: Arc<AtomicBool>,
+    /// The messages received while paused, oldest first
+    paused_queue: Arc<Mutex<VecDeque<(Message, peer::Handle)>>>,
+    /// For each peer that sent a full `Headers` message, the last header's
+    /// hash. Once that block arrives, the peer is asked for the next headers
+    header_sync: Arc<Mutex<HashMap<SocketAddr, H256>>>,
+    /// How long the blocks we received took to get here
+    block_latency: Arc<Mutex<LatencyStats>>,
+    /// How badly the peers at each IP address have behaved, e.g. by sending
+    /// invalid blocks. Keyed by IP rather than by socket address, since a
+    /// peer that reconnects comes from a new port
+    misbehavior: Arc<Mutex<HashMap<IpAddr, u64>>>,
+    /// IP addresses whose misbehavior reached `BAN_SCORE`. Messages from
+    /// peers there are ignored, and they are disconnected whenever they send
+    /// one
+    banned: Arc<Mutex<HashSet<IpAddr>>>,
 }
 
 pub fn new(
@@ -27,16 +137,76 @@ pub fn new(
     msg_src: channel::Receiver<(Vec<u8>, peer::Handle)>,
     server: &ServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
+    block_policy: BlockPolicy,
 ) -> Context {
     Context {
         msg_chan: msg_src,
         num_worker,
         server: server.clone(),
         blockchain,
+        block_policy,
+        requested_blocks: Default::default(),
+        peer_stats: Default::default(),
+        paused: Default::default(),
+        paused_queue: Default::default(),
+        header_sync: Default::default(),
+        block_latency: Default::default(),
+        misbehavior: Default::default(),
+        banned: Default::default(),
     }
 }
 
 impl Context {
+    /// Get the per-peer message stats, which the workers keep up to date
+    pub fn peer_stats(&self) -> PeerStatsTable {
+        Arc::clone(&self.peer_stats)
+    }
+
+    /// Get the latency of the blocks received from peers, which the workers
+    /// keep up to date
+    pub fn block_latency(&self) -> Arc<Mutex<LatencyStats>> {
+        Arc::clone(&self.block_latency)
+    }
+
+    /// The IP addresses that were banned for misbehaving, in order
+    pub fn banned_peers(&self) -> Vec<IpAddr> {
+        let mut banned: Vec<IpAddr> = self.banned.lock().unwrap().iter().copied().collect();
+        banned.sort();
+        banned
+    }
+
+    /// Stop processing incoming blocks and transactions, e.g. during
+    /// maintenance. They are queued until `resume`, up to a limit
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Process incoming blocks and transactions again, starting with the ones
+    /// queued while paused, in the order they arrived. Returns the number of
+    /// queued messages processed
+    pub fn resume(&self) -> usize {
+        // hold the queue so that nothing is queued behind our back
+        let mut paused_queue = self.paused_queue.lock().unwrap();
+        self.paused.store(false, Ordering::SeqCst);
+        let num_queued = paused_queue.len();
+        for (msg, peer) in paused_queue.drain(..) {
+            self.process_message(msg, &peer);
+        }
+        num_queued
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Start syncing with a peer, headers first: ask for the headers of the
+    /// blocks it has after our longest chain. Their blocks are requested once
+    /// the headers check out
+    pub fn sync_headers(&self, peer: &peer::Handle) {
+        let locator = self.blockchain.lock().expect("idk why this should succeed").block_locator();
+        self.send(peer, Message::GetHeaders(locator));
+    }
+
     pub fn start(self) {
         let num_worker = self.num_worker;
         for i in 0..num_worker {
@@ -50,100 +220,966 @@ impl Context {
 
     fn worker_loop(&self) {
         loop {
-            let msg = self.msg_chan.recv().unwrap();
-            let (msg, peer) = msg;
-            let msg: Message = bincode::deserialize(&msg).unwrap();
-            match msg {
-                Message::Ping(nonce) => {
-                    debug!("Ping: {}", nonce);
-                    peer.write(Message::Pong(nonce.to_string()));
-                }
-                Message::Pong(nonce) => {
-                    debug!("Pong: {}", nonce);
-                }
-                Message::NewBlockHashes(new_block_hashes) => {
-                    debug!("NewBlockHashes: {:?}", new_block_hashes);
-                    let blockchain = self.blockchain.lock().expect("idk why this should succeed");
-                    let unknown_hashes: Vec<H256> = new_block_hashes
-                        .into_iter()
-                        .filter(|new_hash| blockchain.look_up_block(new_hash).is_none())
-                        .collect();
-                    drop(blockchain);
-                    if !unknown_hashes.is_empty() {
-                        peer.write(Message::GetBlocks(unknown_hashes));
+            let (msg, peer) = self.msg_chan.recv().unwrap();
+            self.handle_message(&msg, &peer);
+        }
+    }
+
+    /// Send a message to a peer, retrying a few times if it can't be queued.
+    /// If it keeps failing, the peer is disconnected. Returns whether the
+    /// message was sent
+    fn send(&self, peer: &peer::Handle, msg: Message) -> bool {
+        match retry_write(WRITE_ATTEMPTS, WRITE_RETRY_DELAY, || peer.write(&msg)) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("Failed to send {} message to peer {}, disconnecting: {}", msg.name(), peer.addr(), e);
+                self.server.disconnect(peer.addr());
+                false
+            }
+        }
+    }
+
+    /// Handle one serialized message received from a peer
+    fn handle_message(&self, raw_msg: &[u8], peer: &peer::Handle) {
+        if self.banned.lock().unwrap().contains(&peer.addr().ip()) {
+            debug!("Ignoring message from banned peer {}", peer.addr());
+            self.server.disconnect(peer.addr());
+            return;
+        }
+        let msg: Message = match bincode::deserialize(raw_msg) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                warn!("Invalid message from peer {}: {}", peer.addr(), e);
+                self.update_stats(peer, |stats| {
+                    stats.bytes_received += raw_msg.len() as u64;
+                    stats.invalid_messages += 1;
+                });
+                return;
+            }
+        };
+        let num_items = match &msg {
+            Message::Blocks(blocks) => blocks.len(),
+            Message::Transactions(transactions) => transactions.len(),
+            _ => 0,
+        };
+        if num_items > MAX_ITEMS_PER_MESSAGE {
+            warn!("Peer {} sent {} items in one {} message, dropping it", peer.addr(), num_items, msg.name());
+            self.update_stats(peer, |stats| {
+                stats.bytes_received += raw_msg.len() as u64;
+                stats.invalid_messages += 1;
+            });
+            return;
+        }
+        self.update_stats(peer, |stats| {
+            stats.bytes_received += raw_msg.len() as u64;
+            *stats.messages_received.entry(msg.name().to_string()).or_default() += 1;
+        });
+        if let Message::Blocks(_) | Message::Transactions(_) = msg {
+            if self.is_paused() {
+                let mut paused_queue = self.paused_queue.lock().unwrap();
+                // check again, in case `resume` drained the queue meanwhile
+                if self.is_paused() {
+                    if paused_queue.len() < PAUSED_QUEUE_CAPACITY {
+                        paused_queue.push_back((msg, peer.clone()));
+                    } else {
+                        warn!("Paused queue is full, dropping {} message from peer {}", msg.name(), peer.addr());
                     }
+                    return;
                 }
-                Message::GetBlocks(requested_block_hashes) => {
-                    debug!("GetBlocks: {:?}", requested_block_hashes);
-                    let blockchain = self.blockchain.lock().expect("idk why this should succeed");
-                    let requested_blocks: Vec<Block> = requested_block_hashes
-                        .into_iter()
-                        .filter_map(|hash| blockchain.look_up_block(&hash))
-                        .map(|(block, _, _)| block.clone())
-                        .collect();
-                    drop(blockchain);
-                    if !requested_blocks.is_empty() {
-                        peer.write(Message::Blocks(requested_blocks));
-                    }
+            }
+        }
+        self.process_message(msg, peer);
+    }
+
+    /// Act on a decoded message from a peer
+    fn process_message(&self, msg: Message, peer: &peer::Handle) {
+        match msg {
+            Message::Ping(nonce) => {
+                debug!("Ping: {}", nonce);
+                self.send(peer, Message::Pong(nonce.to_string()));
+            }
+            Message::Pong(nonce) => {
+                debug!("Pong: {}", nonce);
+                self.server.resolve_pong(&nonce);
+            }
+            Message::NewBlockHashes(new_block_hashes) => {
+                debug!("NewBlockHashes: {:?}", new_block_hashes);
+                let new_block_hashes = self.drop_echoes(new_block_hashes, |hash| *hash);
+                if new_block_hashes.is_empty() {
+                    return;
+                }
+                let blockchain = self.blockchain.lock().expect("idk why this should succeed");
+                let unknown_hashes: Vec<H256> = new_block_hashes
+                    .into_iter()
+                    .filter(|new_hash| blockchain.look_up_block(new_hash).is_none())
+                    .collect();
+                drop(blockchain);
+                if !unknown_hashes.is_empty() {
+                    self.requested_blocks
+                        .lock()
+                        .unwrap()
+                        .record(unknown_hashes.iter().copied());
+                    self.send(peer, Message::GetBlocks(unknown_hashes));
                 }
-                Message::Blocks(blocks) => {
-                    debug!("Blocks: {:?}", blocks.iter().map(Block::hash).collect::<Vec<_>>());
-                    let now: u128 = SystemTime::now()
-                        .duration_since(SystemTime::UNIX_EPOCH)
-                        .expect("system time should always be after Unix epoch")
-                        .as_millis();
-                    let mut blockchain =
-                        self.blockchain.lock().expect("idk why this should succeed");
-                    let mut all_added_blocks = vec![];
-                    for block in blocks {
-                        let latency = now - block.header.timestamp;
-                        let mut added_blocks = blockchain.insert_block_with_validation(block);
-                        all_added_blocks.append(&mut added_blocks);
+            }
+            Message::GetBlocks(requested_block_hashes) => {
+                debug!("GetBlocks: {:?}", requested_block_hashes);
+                let blockchain = self.blockchain.lock().expect("idk why this should succeed");
+                let requested_blocks: Vec<Block> = requested_block_hashes
+                    .into_iter()
+                    .filter_map(|hash| blockchain.look_up_block(&hash))
+                    .map(|(block, _, _, _)| block.clone())
+                    .collect();
+                drop(blockchain);
+                if !requested_blocks.is_empty() {
+                    self.send(peer, Message::Blocks(requested_blocks));
+                }
+            }
+            Message::Blocks(blocks) => {
+                debug!("Blocks: {:?}", blocks.iter().map(Block::hash).collect::<Vec<_>>());
+                let all_added_blocks = self.process_blocks(blocks, peer);
+                self.update_stats(peer, |stats| {
+                    stats.blocks_contributed += all_added_blocks.len() as u64;
+                });
+                if !all_added_blocks.is_empty() {
+                    self.server.broadcast(Message::NewBlockHashes(all_added_blocks));
+                }
+            }
+            Message::NewTransactionHashes(new_transaction_hashes) => {
+                debug!("NewTransactionHashes: {:?}", new_transaction_hashes);
+                let new_transaction_hashes = self.drop_echoes(new_transaction_hashes, |hash| *hash);
+                if new_transaction_hashes.is_empty() {
+                    return;
+                }
+                let blockchain = self.blockchain.lock().expect("idk why this should succeed");
+                let unknown_hashes: Vec<H256> = new_transaction_hashes
+                    .into_iter()
+                    .filter(|new_hash| {
+                        blockchain.get_transaction(new_hash).is_none()
+                            && !blockchain.is_recently_confirmed(new_hash)
+                    })
+                    .collect();
+                drop(blockchain);
+                if !unknown_hashes.is_empty() {
+                    self.send(peer, Message::GetTransactions(unknown_hashes));
+                }
+            }
+            Message::GetTransactions(requested_hashes) => {
+                debug!("GetTransactions: {:?}", requested_hashes);
+                let blockchain = self.blockchain.lock().expect("idk why this should succeed");
+                let requested_transactions: Vec<Transaction> = requested_hashes
+                    .into_iter()
+                    .filter_map(|hash| blockchain.find_transaction(&hash))
+                    .cloned()
+                    .collect();
+                drop(blockchain);
+                if !requested_transactions.is_empty() {
+                    self.send(peer, Message::Transactions(requested_transactions));
+                }
+            }
+            Message::Transactions(transactions) => {
+                debug!("Transactions: {:?}", transactions.iter().map(Transaction::hash).collect::<Vec<_>>());
+                let mut blockchain = self.blockchain.lock().expect("idk why this should succeed");
+                let mut all_added_transactions = vec![];
+                let valid_signatures = verify_batch(&transactions);
+                for (transaction, valid_signature) in transactions.into_iter().zip(valid_signatures) {
+                    if !valid_signature {
+                        continue;
                     }
-                    if !all_added_blocks.is_empty() {
-                        self.server.broadcast(Message::NewBlockHashes(all_added_blocks));
+                    let hash = transaction.hash();
+                    if blockchain.insert_verified_transaction(transaction).is_ok() {
+                        all_added_transactions.push(hash);
                     }
                 }
-                Message::NewTransactionHashes(new_transaction_hashes) => {
-                    debug!("NewTransactionHashes: {:?}", new_transaction_hashes);
-                    let blockchain = self.blockchain.lock().expect("idk why this should succeed");
-                    let unknown_hashes: Vec<H256> = new_transaction_hashes
-                        .into_iter()
-                        .filter(|new_hash| blockchain.get_transaction(new_hash).is_none())
-                        .collect();
-                    drop(blockchain);
-                    if !unknown_hashes.is_empty() {
-                        peer.write(Message::GetTransactions(unknown_hashes));
-                    }
+                drop(blockchain);
+                self.update_stats(peer, |stats| {
+                    stats.transactions_contributed += all_added_transactions.len() as u64;
+                });
+                if !all_added_transactions.is_empty() {
+                    self.server.broadcast(Message::NewTransactionHashes(all_added_transactions));
                 }
-                Message::GetTransactions(requested_hashes) => {
-                    debug!("GetTransactions: {:?}", requested_hashes);
-                    let blockchain = self.blockchain.lock().expect("idk why this should succeed");
-                    let requested_transactions: Vec<Transaction> = requested_hashes
-                        .into_iter()
-                        .filter_map(|hash| blockchain.get_transaction(&hash))
-                        .map(|transaction| transaction.clone())
-                        .collect();
-                    drop(blockchain);
-                    if !requested_transactions.is_empty() {
-                        peer.write(Message::Transactions(requested_transactions));
-                    }
+            }
+            Message::Inv(items) => {
+                debug!("Inv: {:?}", items);
+                let items = self.drop_echoes(items, |item| match item {
+                    InvItem::Block(hash) | InvItem::Transaction(hash) => *hash,
+                });
+                if items.is_empty() {
+                    return;
                 }
-                Message::Transactions(transactions) => {
-                    debug!("Transactions: {:?}", transactions.iter().map(Transaction::hash).collect::<Vec<_>>());
-                    let mut blockchain = self.blockchain.lock().expect("idk why this should succeed");
-                    let mut all_added_transactions = vec![];
-                    for transaction in transactions {
-                        let hash = transaction.hash();
-                        if blockchain.insert_transaction_with_validation(transaction) {
-                            all_added_transactions.push(hash);
+                let blockchain = self.blockchain.lock().expect("idk why this should succeed");
+                let unknown_items: Vec<InvItem> = items
+                    .into_iter()
+                    .filter(|item| match item {
+                        InvItem::Block(hash) => blockchain.look_up_block(hash).is_none(),
+                        InvItem::Transaction(hash) => {
+                            blockchain.get_transaction(hash).is_none()
+                                && !blockchain.is_recently_confirmed(hash)
+                        }
+                    })
+                    .collect();
+                drop(blockchain);
+                if !unknown_items.is_empty() {
+                    self.requested_blocks.lock().unwrap().record(
+                        unknown_items.iter().filter_map(|item| match item {
+                            InvItem::Block(hash) => Some(*hash),
+                            InvItem::Transaction(_) => None,
+                        }),
+                    );
+                    self.send(peer, Message::GetData(unknown_items));
+                }
+            }
+            Message::GetData(items) => {
+                debug!("GetData: {:?}", items);
+                let blockchain = self.blockchain.lock().expect("idk why this should succeed");
+                let mut requested_blocks: Vec<Block> = vec![];
+                let mut requested_transactions: Vec<Transaction> = vec![];
+                for item in items {
+                    match item {
+                        InvItem::Block(hash) => {
+                            if let Some((block, _, _, _)) = blockchain.look_up_block(&hash) {
+                                requested_blocks.push(block.clone());
+                            }
+                        }
+                        InvItem::Transaction(hash) => {
+                            if let Some(transaction) = blockchain.find_transaction(&hash) {
+                                requested_transactions.push(transaction.clone());
+                            }
                         }
                     }
-                    if !all_added_transactions.is_empty() {
-                        self.server.broadcast(Message::NewTransactionHashes(all_added_transactions));
-                    }
+                }
+                drop(blockchain);
+                if !requested_blocks.is_empty() {
+                    self.send(peer, Message::Blocks(requested_blocks));
+                }
+                if !requested_transactions.is_empty() {
+                    self.send(peer, Message::Transactions(requested_transactions));
+                }
+            }
+            Message::GetHeaders(locator) => {
+                debug!("GetHeaders: {:?}", locator);
+                let blockchain = self.blockchain.lock().expect("idk why this should succeed");
+                let headers = blockchain.headers_after(&locator, MAX_HEADERS_PER_MESSAGE);
+                drop(blockchain);
+                if !headers.is_empty() {
+                    self.send(peer, Message::Headers(headers));
+                }
+            }
+            Message::Headers(headers) => {
+                debug!("Headers: {:?}", headers.iter().map(Header::hash).collect::<Vec<_>>());
+                self.process_headers(headers, peer);
+            }
+            Message::GetTip => {
+                debug!("GetTip");
+                let tip_hash = self.blockchain.lock().expect("idk why this should succeed").tip_hash();
+                self.send(peer, Message::Tip(tip_hash));
+            }
+            Message::Tip(tip_hash) => {
+                debug!("Tip: {}", tip_hash);
+                // Every peer that reports a tip we don't have is synced with
+                // on its own. When peers disagree, each one's headers are
+                // only followed by block downloads if they lead to more work
+                // than our chain at the time, and the chain with the most
+                // work among everything downloaded becomes our tip, as with
+                // blocks that are announced
+                let known = self.blockchain.lock().expect("idk why this should succeed").look_up_block(&tip_hash).is_some();
+                if !known {
+                    self.sync_headers(peer);
                 }
             }
         }
     }
+
+    /// Drop announced items that we created ourselves, which peers are only
+    /// echoing back, without looking at the blockchain
+    fn drop_echoes<T>(&self, items: Vec<T>, hash_of: impl Fn(&T) -> H256) -> Vec<T> {
+        let num_announced = items.len();
+        let items: Vec<T> = items.into_iter().filter(|item| !self.server.is_originated(&hash_of(item))).collect();
+        if items.len() < num_announced {
+            debug!("Ignoring {} echoed announcements", num_announced - items.len());
+        }
+        items
+    }
+
+    /// Check headers received from a peer, and if they lead to a chain with
+    /// more work than ours, ask the peer for the blocks we don't have
+    fn process_headers(&self, headers: Vec<Header>, peer: &peer::Handle) {
+        let Some(first) = headers.first() else {
+            return;
+        };
+        let blockchain = self.blockchain.lock().expect("idk why this should succeed");
+        let Some(work) = blockchain.header_segment_work(&headers, &first.parent) else {
+            warn!("Peer {} sent headers that don't extend a valid chain", peer.addr());
+            return;
+        };
+        let tip_work = blockchain.cumulative_work(&blockchain.tip_hash()).expect("the tip is in the chain");
+        if work <= tip_work {
+            debug!("Headers from peer {} don't lead to more work than our chain", peer.addr());
+            return;
+        }
+        let unknown_hashes: Vec<H256> = headers
+            .iter()
+            .map(Header::hash)
+            .filter(|hash| blockchain.look_up_block(hash).is_none())
+            .collect();
+        drop(blockchain);
+        if headers.len() >= MAX_HEADERS_PER_MESSAGE {
+            let last_hash = headers[headers.len() - 1].hash();
+            self.header_sync.lock().unwrap().insert(peer.addr(), last_hash);
+        }
+        if !unknown_hashes.is_empty() {
+            self.requested_blocks
+                .lock()
+                .unwrap()
+                .record(unknown_hashes.iter().copied());
+            self.send(peer, Message::GetBlocks(unknown_hashes));
+        }
+    }
+
+    /// Count something about a peer's messages
+    fn update_stats(&self, peer: &peer::Handle, update: impl FnOnce(&mut PeerStats)) {
+        let mut peer_stats = self.peer_stats.lock().unwrap();
+        update(peer_stats.entry(peer.addr()).or_default());
+    }
+
+    /// Insert blocks received from a peer into the blockchain, subject to the
+    /// block policy, and ask the peer for the parents of any orphans. Returns
+    /// all blocks that were added
+    fn process_blocks(&self, blocks: Vec<Block>, peer: &peer::Handle) -> Vec<H256> {
+        let now: u128 = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("system time should always be after Unix epoch")
+            .as_millis();
+        let mut requested_blocks = self.requested_blocks.lock().unwrap();
+        let mut blockchain = self.blockchain.lock().expect("idk why this should succeed");
+        let mut all_added_blocks = vec![];
+        let mut missing_parents = vec![];
+        let mut num_rejected = 0;
+        for block in blocks {
+            let hash = block.hash();
+            let was_requested = requested_blocks.remove(&hash);
+            if self.block_policy == BlockPolicy::Strict && !was_requested {
+                debug!("ignoring unrequested block {}", hash);
+                continue;
+            }
+            let timestamp = block.header.timestamp;
+            let mut outcome = blockchain.insert_block_with_validation(block);
+            if outcome.rejected.is_none() {
+                self.block_latency.lock().unwrap().record_at(timestamp, now);
+            }
+            all_added_blocks.append(&mut outcome.added);
+            if let Some(parent) = outcome.orphaned {
+                if blockchain.look_up_block(&parent).is_none() && !missing_parents.contains(&parent) {
+                    missing_parents.push(parent);
+                }
+            }
+            match outcome.rejected {
+                None | Some(RejectReason::AlreadyKnown) => {}
+                // the block may become valid later, and it may be our clock
+                // that is behind, so the peer isn't at fault
+                Some(RejectReason::TimestampTooLate) => {
+                    debug!("Peer {} sent block {} from the future", peer.addr(), hash);
+                }
+                Some(reason) => {
+                    warn!("Peer {} sent invalid block {}: {}", peer.addr(), hash, reason);
+                    num_rejected += 1;
+                }
+            }
+        }
+        // connect some of the orphans that are waiting, if the blockchain
+        // connects them lazily
+        let mut added_orphans = blockchain.process_orphans(ORPHANS_PER_MESSAGE);
+        all_added_blocks.append(&mut added_orphans);
+        // once the last block of a full batch of headers arrives, carry on
+        // with the headers after it
+        let mut header_sync = self.header_sync.lock().unwrap();
+        let next_locator = match header_sync.get(&peer.addr()) {
+            Some(last_hash) if blockchain.look_up_block(last_hash).is_some() => {
+                header_sync.remove(&peer.addr());
+                Some(blockchain.block_locator())
+            }
+            _ => None,
+        };
+        drop(header_sync);
+        drop(blockchain);
+
+        requested_blocks.record(missing_parents.iter().copied());
+        drop(requested_blocks);
+        if !missing_parents.is_empty() {
+            self.send(peer, Message::GetBlocks(missing_parents));
+        }
+        if let Some(locator) = next_locator {
+            self.send(peer, Message::GetHeaders(locator));
+        }
+        self.update_stats(peer, |stats| stats.rejected_blocks += num_rejected);
+        self.penalize(peer, num_rejected);
+        all_added_blocks
+    }
+
+    /// Add to a peer's misbehavior score, banning and disconnecting it once
+    /// the score reaches `BAN_SCORE`
+    fn penalize(&self, peer: &peer::Handle, score: u64) {
+        if score == 0 {
+            return;
+        }
+        let mut misbehavior = self.misbehavior.lock().unwrap();
+        let total = misbehavior.entry(peer.addr().ip()).or_default();
+        *total += score;
+        if *total < BAN_SCORE {
+            return;
+        }
+        drop(misbehavior);
+        if self.banned.lock().unwrap().insert(peer.addr().ip()) {
+            warn!("Banning {} for misbehaving", peer.addr().ip());
+            self.server.disconnect(peer.addr());
+        }
+    }
+}
+
+/// Try `write` up to `attempts` times, waiting `delay` after each failure,
+/// until it succeeds. Returns the result of the last attempt
+fn retry_write(
+    attempts: usize,
+    delay: Duration,
+    mut write: impl FnMut() -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    let mut result = write();
+    for _ in 1..attempts {
+        if result.is_ok() {
+            break;
+        }
+        thread::sleep(delay);
+        result = write();
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::{mine_valid_block, mine_valid_block_on};
+    use crate::crypto::address::H160;
+    use crate::crypto::key_pair::get_deterministic_keypair;
+    use crate::network::server;
+    use crate::transaction::RawTransaction;
+    use ring::signature::KeyPair;
+
+    /// Start a node with a P2P server and workers on a free local port
+    fn start_node() -> (std::net::SocketAddr, ServerHandle) {
+        let (addr, server, _) = start_node_with(Default::default());
+        (addr, server)
+    }
+
+    /// Start a node whose P2P server has the given config, also returning its
+    /// blockchain
+    fn start_node_with(config: server::Config) -> (std::net::SocketAddr, ServerHandle, Arc<Mutex<Blockchain>>) {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let (msg_tx, msg_rx) = channel::unbounded();
+        let (server_ctx, server) = server::new(addr, msg_tx, config).unwrap();
+        server_ctx.start().unwrap();
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        new(1, msg_rx, &server, Arc::clone(&blockchain), BlockPolicy::default()).start();
+        (addr, server, blockchain)
+    }
+
+    /// A worker for `blockchain` behind a P2P server that is never started,
+    /// with the sender that feeds the worker messages
+    fn idle_worker(
+        blockchain: &Arc<Mutex<Blockchain>>,
+        block_policy: BlockPolicy,
+    ) -> (server::Context, Context, channel::Sender<(Vec<u8>, peer::Handle)>) {
+        let (msg_tx, msg_rx) = channel::unbounded();
+        let (server_msg_tx, _server_msg_rx) = channel::unbounded();
+        let (server_ctx, server) = server::new("127.0.0.1:0".parse().unwrap(), server_msg_tx, Default::default()).unwrap();
+        let ctx = new(1, msg_rx, &server, Arc::clone(blockchain), block_policy);
+        (server_ctx, ctx, msg_tx)
+    }
+
+    /// Connect `server` to the node listening at `addr`, which might not be up
+    /// yet
+    fn connect(server: &ServerHandle, addr: std::net::SocketAddr) {
+        let mut attempts = 0;
+        while server.connect(addr).is_err() {
+            attempts += 1;
+            assert!(attempts < 100, "couldn't connect to {}", addr);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn ping_round_trip() {
+        let (_, server_a) = start_node();
+        let (addr_b, _server_b) = start_node();
+        connect(&server_a, addr_b);
+
+        let peers = server_a.peers();
+        assert_eq!(peers.len(), 1);
+        let (peer_id, peer_addr) = peers[0];
+        assert_eq!(peer_addr, addr_b);
+        let rtt = server_a.ping(peer_id, Duration::from_secs(5)).unwrap();
+        assert!(rtt < Duration::from_secs(5));
+
+        assert!(server_a.ping(peer_id + 1, Duration::from_secs(5)).is_err());
+    }
+
+    #[test]
+    fn announcements_converge_with_fanout() {
+        let fanout_one = server::Config { broadcast_fanout: Some(1), ..Default::default() };
+        let (addr_a, server_a, blockchain_a) = start_node_with(fanout_one);
+        let others: Vec<_> = (0..3).map(|_| start_node_with(fanout_one)).collect();
+        for (_, server, _) in &others {
+            connect(server, addr_a);
+        }
+        while server_a.peers().len() < others.len() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let block = mine_valid_block(&blockchain_a.lock().unwrap(), vec![]);
+        blockchain_a.lock().unwrap().insert_block_with_validation(block.clone());
+
+        // each announcement reaches one random peer, so it takes a few
+        for _ in 0..200 {
+            let converged = others
+                .iter()
+                .all(|(_, _, blockchain)| blockchain.lock().unwrap().look_up_block(&block.hash()).is_some());
+            if converged {
+                return;
+            }
+            server_a.broadcast(Message::NewBlockHashes(vec![block.hash()]));
+            thread::sleep(Duration::from_millis(50));
+        }
+        panic!("the block didn't reach every node");
+    }
+
+    #[test]
+    fn strict_block_policy() {
+
+        // a valid block on top of genesis
+        let sender = get_deterministic_keypair(0);
+        let from_addr = H160::from_pubkey(sender.public_key().as_ref());
+        let to_addr = H160::from_pubkey(get_deterministic_keypair(1).public_key().as_ref());
+        let transactions = vec![Transaction::from_raw(
+            RawTransaction { from_addr, to_addr, value: 1, fee: 0, nonce: 0, memo: vec![], valid_since: None },
+            &sender,
+        )];
+        let block = mine_valid_block(&Blockchain::new(), transactions);
+
+        let (peer, _written) = peer::test_handle("10.0.0.1:6000".parse().unwrap());
+        let (_strict_server, strict, _) = idle_worker(&Arc::new(Mutex::new(Blockchain::new())), BlockPolicy::Strict);
+        assert!(strict.process_blocks(vec![block.clone()], &peer).is_empty());
+        strict.requested_blocks.lock().unwrap().record([block.hash()]);
+        assert_eq!(strict.process_blocks(vec![block.clone()], &peer), vec![block.hash()]);
+
+        let (_promiscuous_server, promiscuous, _) = idle_worker(&Arc::new(Mutex::new(Blockchain::new())), BlockPolicy::default());
+        assert_eq!(promiscuous.process_blocks(vec![block.clone()], &peer), vec![block.hash()]);
+    }
+
+    #[test]
+    fn expire_block_requests() {
+        let mut requested = RequestedBlocks::default();
+        let (stale, fresh, other) = (H256::from([1; 32]), H256::from([2; 32]), H256::from([3; 32]));
+        requested.0.insert(stale, Instant::now() - BLOCK_REQUEST_TIMEOUT);
+        requested.record([fresh]);
+        // the stale request is forgotten, so its block counts as unrequested
+        assert!(!requested.0.contains_key(&stale));
+        assert!(!requested.remove(&stale));
+        assert!(requested.remove(&fresh));
+        assert!(!requested.remove(&fresh));
+        assert!(!requested.remove(&other));
+    }
+
+    #[test]
+    fn request_parents_of_orphans() {
+        let (_server, ctx, _) = idle_worker(&Arc::new(Mutex::new(Blockchain::new())), BlockPolicy::default());
+        let addr: SocketAddr = "10.0.0.1:6000".parse().unwrap();
+        let (peer, written) = peer::test_handle(addr);
+
+        let parent = mine_valid_block(&Blockchain::new(), vec![]);
+        let orphan = mine_valid_block_on(&parent, vec![]);
+        assert!(ctx.process_blocks(vec![orphan.clone()], &peer).is_empty());
+        let request: Message = bincode::deserialize(&written.try_recv().unwrap()).unwrap();
+        assert!(matches!(request, Message::GetBlocks(hashes) if hashes == vec![parent.hash()]));
+        assert!(ctx.requested_blocks.lock().unwrap().0.contains_key(&parent.hash()));
+
+        // an invalid block counts against the peer
+        let mut invalid = mine_valid_block(&Blockchain::new(), vec![]);
+        invalid.header.timestamp = 0;
+        assert!(ctx.process_blocks(vec![invalid], &peer).is_empty());
+        assert_eq!(ctx.peer_stats().lock().unwrap()[&addr].rejected_blocks, 1);
+        assert!(written.try_recv().is_err());
+
+        // the parent connects the orphan
+        assert_eq!(
+            ctx.process_blocks(vec![parent.clone()], &peer),
+            vec![parent.hash(), orphan.hash()],
+        );
+    }
+
+    #[test]
+    fn ban_peer_sending_invalid_blocks() {
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let (_server, ctx, _) = idle_worker(&blockchain, BlockPolicy::default());
+        let (peer, written) = peer::test_handle("10.0.0.1:6000".parse().unwrap());
+        let (honest, _) = peer::test_handle("10.0.0.2:6000".parse().unwrap());
+
+        // blocks we already have don't count against a peer
+        let block = mine_valid_block(&blockchain.lock().unwrap(), vec![]);
+        for _ in 0..BAN_SCORE {
+            ctx.process_blocks(vec![block.clone()], &peer);
+        }
+        assert!(ctx.banned_peers().is_empty());
+
+        // nor do ones too far in the future, which may just be early
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis();
+        let mut early = mine_valid_block_on(&block, vec![]);
+        early.header.timestamp = now + 3 * 60 * 60 * 1000;
+        while early.hash() > early.header.difficulty {
+            early.header.nonce += 1;
+        }
+        for _ in 0..BAN_SCORE {
+            ctx.process_blocks(vec![early.clone()], &peer);
+        }
+        assert!(ctx.banned_peers().is_empty());
+        assert!(blockchain.lock().unwrap().look_up_block(&early.hash()).is_none());
+
+        // invalid ones do
+        let mut invalid = mine_valid_block_on(&block, vec![]);
+        invalid.header.timestamp = 0;
+        for i in 0..BAN_SCORE {
+            assert!(ctx.banned_peers().is_empty(), "banned after {} invalid blocks", i);
+            ctx.process_blocks(vec![invalid.clone()], &peer);
+        }
+        assert_eq!(ctx.banned_peers(), vec![peer.addr().ip()]);
+
+        // and once banned, nothing it says is heard, even from another port
+        let ping = bincode::serialize(&Message::Ping(String::from("hello"))).unwrap();
+        ctx.handle_message(&ping, &peer);
+        assert!(written.try_recv().is_err());
+        assert!(!ctx.peer_stats().lock().unwrap()[&peer.addr()].messages_received.contains_key("ping"));
+        let (reconnected, written) = peer::test_handle("10.0.0.1:6001".parse().unwrap());
+        ctx.handle_message(&ping, &reconnected);
+        assert!(written.try_recv().is_err());
+        ctx.handle_message(&ping, &honest);
+        assert_eq!(ctx.peer_stats().lock().unwrap()[&honest.addr()].messages_received["ping"], 1);
+    }
+
+    #[test]
+    fn survive_malformed_messages() {
+        let (_server, ctx, msg_tx) = idle_worker(&Arc::new(Mutex::new(Blockchain::new())), BlockPolicy::default());
+        let addr: SocketAddr = "10.0.0.1:6000".parse().unwrap();
+        let (peer, written) = peer::test_handle(addr);
+        ctx.clone().start();
+
+        // garbage, and a message with too many transactions to bother with
+        msg_tx.send((vec![0xff; 7], peer.clone())).unwrap();
+        let flood = Message::Transactions(vec![Transaction::generate_random(); MAX_ITEMS_PER_MESSAGE + 1]);
+        msg_tx.send((bincode::serialize(&flood).unwrap(), peer.clone())).unwrap();
+
+        // the worker is still there to answer
+        msg_tx.send((bincode::serialize(&Message::Ping(String::from("hello"))).unwrap(), peer)).unwrap();
+        let start = std::time::Instant::now();
+        let pong = loop {
+            if let Ok(raw) = written.try_recv() {
+                break bincode::deserialize::<Message>(&raw).unwrap();
+            }
+            assert!(start.elapsed() < Duration::from_secs(5), "the worker stopped answering");
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert!(matches!(pong, Message::Pong(nonce) if nonce == "hello"));
+        let stats = ctx.peer_stats().lock().unwrap()[&addr].clone();
+        assert_eq!(stats.invalid_messages, 2);
+        assert!(!stats.messages_received.contains_key("transactions"));
+    }
+
+    #[test]
+    fn peer_stats() {
+        let (_server, ctx, _) = idle_worker(&Arc::new(Mutex::new(Blockchain::new())), BlockPolicy::default());
+        let addr: SocketAddr = "10.0.0.1:6000".parse().unwrap();
+        let (peer, _written) = peer::test_handle(addr);
+
+        let sender = get_deterministic_keypair(0);
+        let from_addr = H160::from_pubkey(sender.public_key().as_ref());
+        let to_addr = H160::from_pubkey(get_deterministic_keypair(1).public_key().as_ref());
+        let transaction = |nonce| {
+            Transaction::from_raw(RawTransaction { from_addr, to_addr, value: 1, fee: 0, nonce, memo: vec![], valid_since: None }, &sender)
+        };
+        // one transaction is accepted, the other has the wrong nonce
+        let messages = [
+            Message::Ping(String::from("hello")),
+            Message::Ping(String::from("again")),
+            Message::Transactions(vec![transaction(0), transaction(5)]),
+        ];
+        let mut total_bytes = 0;
+        for message in &messages {
+            let raw = bincode::serialize(message).unwrap();
+            total_bytes += raw.len() as u64;
+            ctx.handle_message(&raw, &peer);
+        }
+        ctx.handle_message(&[0xff; 3], &peer);
+        total_bytes += 3;
+
+        let peer_stats = ctx.peer_stats();
+        let peer_stats = peer_stats.lock().unwrap();
+        assert_eq!(peer_stats.len(), 1);
+        let stats = &peer_stats[&addr];
+        assert_eq!(stats.messages_received.get("ping"), Some(&2));
+        assert_eq!(stats.messages_received.get("transactions"), Some(&1));
+        assert_eq!(stats.messages_received.len(), 2);
+        assert_eq!(stats.bytes_received, total_bytes);
+        assert_eq!(stats.invalid_messages, 1);
+        assert_eq!(stats.blocks_contributed, 0);
+        assert_eq!(stats.transactions_contributed, 1);
+    }
+
+    #[test]
+    fn inv_requests_unknown_items() {
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let (_server, ctx, _) = idle_worker(&blockchain, BlockPolicy::default());
+        let (peer, written) = peer::test_handle("10.0.0.1:6000".parse().unwrap());
+
+        let sender = get_deterministic_keypair(0);
+        let from_addr = H160::from_pubkey(sender.public_key().as_ref());
+        let to_addr = H160::from_pubkey(get_deterministic_keypair(1).public_key().as_ref());
+        let known_transaction = Transaction::from_raw(
+            RawTransaction { from_addr, to_addr, value: 1, fee: 0, nonce: 0, memo: vec![], valid_since: None },
+            &sender,
+        );
+        blockchain.lock().unwrap().insert_transaction_with_validation(known_transaction.clone()).unwrap();
+        let known_block = blockchain.lock().unwrap().tip_hash();
+        let unknown_block = H256::from([1; 32]);
+        let unknown_transaction = H256::from([2; 32]);
+
+        let inv = Message::Inv(vec![
+            InvItem::Block(known_block),
+            InvItem::Block(unknown_block),
+            InvItem::Transaction(known_transaction.hash()),
+            InvItem::Transaction(unknown_transaction),
+        ]);
+        ctx.handle_message(&bincode::serialize(&inv).unwrap(), &peer);
+        let reply: Message = bincode::deserialize(&written.try_recv().unwrap()).unwrap();
+        let Message::GetData(items) = reply else {
+            panic!("expected GetData, got {:?}", reply);
+        };
+        assert_eq!(items, vec![InvItem::Block(unknown_block), InvItem::Transaction(unknown_transaction)]);
+        assert!(written.try_recv().is_err());
+
+        // asking for the known ones gets both kinds back
+        let get_data = Message::GetData(vec![
+            InvItem::Block(known_block),
+            InvItem::Transaction(known_transaction.hash()),
+        ]);
+        ctx.handle_message(&bincode::serialize(&get_data).unwrap(), &peer);
+        let replies: Vec<Message> = (0..2)
+            .map(|_| bincode::deserialize(&written.try_recv().unwrap()).unwrap())
+            .collect();
+        assert!(matches!(&replies[0], Message::Blocks(blocks) if blocks[0].hash() == known_block));
+        assert!(matches!(
+            &replies[1],
+            Message::Transactions(transactions) if transactions[0].hash() == known_transaction.hash()
+        ));
+    }
+
+    #[test]
+    fn retry_failed_write() {
+        let mut attempts = 0;
+        let result = retry_write(WRITE_ATTEMPTS, Duration::ZERO, || {
+            attempts += 1;
+            if attempts == 1 {
+                Err(std::io::ErrorKind::WouldBlock.into())
+            } else {
+                Ok(())
+            }
+        });
+        assert!(result.is_ok());
+        assert_eq!(attempts, 2);
+
+        let mut attempts = 0;
+        let result = retry_write(WRITE_ATTEMPTS, Duration::ZERO, || {
+            attempts += 1;
+            Err(std::io::ErrorKind::WouldBlock.into())
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, WRITE_ATTEMPTS);
+    }
+
+    #[test]
+    fn disconnect_peer_after_failed_writes() {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let (msg_tx, msg_rx) = channel::unbounded();
+        let (server_ctx, server) = server::new(addr, msg_tx, Default::default()).unwrap();
+        server_ctx.start().unwrap();
+        let ctx = new(1, msg_rx, &server, Arc::new(Mutex::new(Blockchain::new())), BlockPolicy::default());
+
+        let _stream = loop {
+            match std::net::TcpStream::connect(addr) {
+                Ok(stream) => break stream,
+                Err(_) => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+        while server.peers().is_empty() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let (_, peer_addr) = server.peers()[0];
+
+        // a handle to the peer whose writes always fail
+        let (peer, written) = peer::test_handle(peer_addr);
+        drop(written);
+        assert!(!ctx.send(&peer, Message::Ping(String::from("hello"))));
+        assert!(server.peers().is_empty());
+    }
+
+    #[test]
+    fn pause_and_resume() {
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let (_server, ctx, _) = idle_worker(&blockchain, BlockPolicy::default());
+        let (peer, written) = peer::test_handle("10.0.0.1:6000".parse().unwrap());
+
+        let mut blocks = vec![mine_valid_block(&blockchain.lock().unwrap(), vec![])];
+        for _ in 0..2 {
+            blocks.push(mine_valid_block_on(blocks.last().unwrap(), vec![]));
+        }
+
+        ctx.pause();
+        for block in &blocks {
+            ctx.handle_message(&bincode::serialize(&Message::Blocks(vec![block.clone()])).unwrap(), &peer);
+        }
+        // other messages are still handled
+        ctx.handle_message(&bincode::serialize(&Message::Ping(String::from("hello"))).unwrap(), &peer);
+        let pong: Message = bincode::deserialize(&written.try_recv().unwrap()).unwrap();
+        assert!(matches!(pong, Message::Pong(_)));
+        assert_eq!(blockchain.lock().unwrap().tip_hash(), Block::genesis().hash());
+
+        // in order, so none of them were orphans whose parents we'd ask for
+        assert_eq!(ctx.resume(), 3);
+        assert_eq!(blockchain.lock().unwrap().tip_hash(), blocks[2].hash());
+        assert!(written.try_recv().is_err());
+        assert_eq!(ctx.resume(), 0);
+
+        // the queue is bounded
+        ctx.pause();
+        let empty = bincode::serialize(&Message::Transactions(vec![])).unwrap();
+        for _ in 0..PAUSED_QUEUE_CAPACITY + 10 {
+            ctx.handle_message(&empty, &peer);
+        }
+        assert_eq!(ctx.resume(), PAUSED_QUEUE_CAPACITY);
+    }
+
+    #[test]
+    fn headers_first_sync() {
+        let (peer_a, written_by_b) = peer::test_handle("10.0.0.1:6000".parse().unwrap());
+        let (peer_b, written_by_a) = peer::test_handle("10.0.0.2:6000".parse().unwrap());
+
+        // a is ahead of b by a few blocks
+        let blockchain_a = Arc::new(Mutex::new(Blockchain::new()));
+        let mut blocks = vec![mine_valid_block(&blockchain_a.lock().unwrap(), vec![])];
+        for _ in 0..2 {
+            blocks.push(mine_valid_block_on(blocks.last().unwrap(), vec![]));
+        }
+        for block in &blocks {
+            blockchain_a.lock().unwrap().insert_block_with_validation(block.clone());
+        }
+        let (_server_a, ctx_a, _) = idle_worker(&blockchain_a, BlockPolicy::Strict);
+        let blockchain_b = Arc::new(Mutex::new(Blockchain::new()));
+        let (_server_b, ctx_b, _) = idle_worker(&blockchain_b, BlockPolicy::Strict);
+        let receive = |written: &mio_extras::channel::Receiver<Vec<u8>>| -> Message {
+            bincode::deserialize(&written.try_recv().unwrap()).unwrap()
+        };
+
+        // b asks for headers, and a sends them
+        ctx_b.sync_headers(&peer_a);
+        let get_headers = receive(&written_by_b);
+        assert!(matches!(&get_headers, Message::GetHeaders(_)));
+        ctx_a.process_message(get_headers, &peer_b);
+        let headers = receive(&written_by_a);
+        let Message::Headers(header_list) = &headers else {
+            panic!("expected headers, got {:?}", headers);
+        };
+        let hashes: Vec<H256> = blocks.iter().map(Block::hash).collect();
+        assert_eq!(header_list.iter().map(Header::hash).collect::<Vec<_>>(), hashes);
+
+        // forged headers don't get as far as asking for blocks
+        let mut forged = header_list.clone();
+        forged[1].timestamp = 0;
+        ctx_b.process_message(Message::Headers(forged), &peer_a);
+        assert!(written_by_b.try_recv().is_err());
+
+        // good ones do, and b takes the blocks even though it is strict
+        ctx_b.process_message(headers, &peer_a);
+        let get_blocks = receive(&written_by_b);
+        assert!(matches!(&get_blocks, Message::GetBlocks(requested) if *requested == hashes));
+        ctx_a.process_message(get_blocks, &peer_b);
+        ctx_b.process_message(receive(&written_by_a), &peer_a);
+        assert_eq!(blockchain_b.lock().unwrap().tip_hash(), blockchain_a.lock().unwrap().tip_hash());
+
+        // once in sync, headers lead nowhere new
+        ctx_b.sync_headers(&peer_a);
+        ctx_a.process_message(receive(&written_by_b), &peer_b);
+        assert!(written_by_a.try_recv().is_err());
+    }
+
+    #[test]
+    fn catch_up_from_tip() {
+        let (peer_a, written_by_b) = peer::test_handle("10.0.0.1:6000".parse().unwrap());
+        let (peer_b, written_by_a) = peer::test_handle("10.0.0.2:6000".parse().unwrap());
+
+        // a has mined a couple of blocks that b, fresh, has never heard of
+        let blockchain_a = Arc::new(Mutex::new(Blockchain::new()));
+        let block = mine_valid_block(&blockchain_a.lock().unwrap(), vec![]);
+        let next_block = mine_valid_block_on(&block, vec![]);
+        blockchain_a.lock().unwrap().insert_block_with_validation(block);
+        blockchain_a.lock().unwrap().insert_block_with_validation(next_block.clone());
+        let (_server_a, ctx_a, _) = idle_worker(&blockchain_a, BlockPolicy::Strict);
+        let blockchain_b = Arc::new(Mutex::new(Blockchain::new()));
+        let (_server_b, ctx_b, _) = idle_worker(&blockchain_b, BlockPolicy::Strict);
+        let receive = |written: &mio_extras::channel::Receiver<Vec<u8>>| -> Message {
+            bincode::deserialize(&written.try_recv().unwrap()).unwrap()
+        };
+
+        // b asks a for its tip and starts syncing once it hears of it
+        ctx_a.process_message(Message::GetTip, &peer_b);
+        let tip = receive(&written_by_a);
+        assert!(matches!(tip, Message::Tip(hash) if hash == next_block.hash()));
+        ctx_b.process_message(tip, &peer_a);
+        let get_headers = receive(&written_by_b);
+        assert!(matches!(get_headers, Message::GetHeaders(_)));
+        ctx_a.process_message(get_headers, &peer_b);
+        ctx_b.process_message(receive(&written_by_a), &peer_a);
+        ctx_a.process_message(receive(&written_by_b), &peer_b);
+        ctx_b.process_message(receive(&written_by_a), &peer_a);
+        assert_eq!(blockchain_b.lock().unwrap().tip_hash(), next_block.hash());
+
+        // a tip we already have needs nothing more
+        ctx_b.process_message(Message::Tip(next_block.hash()), &peer_a);
+        assert!(written_by_b.try_recv().is_err());
+    }
+
+    #[test]
+    fn ignore_echoed_announcements() {
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let (_server, ctx, _) = idle_worker(&blockchain, BlockPolicy::default());
+        let (peer, written) = peer::test_handle("10.0.0.1:6000".parse().unwrap());
+        let ours = H256::from([1; 32]);
+        let theirs = H256::from([2; 32]);
+        ctx.server.mark_originated(&[ours]);
+
+        // our own transaction comes back while the blockchain is busy, and is
+        // dropped without waiting for it
+        let guard = blockchain.lock().unwrap();
+        let (done_tx, done_rx) = channel::bounded(1);
+        let echo_ctx = ctx.clone();
+        let echo_peer = peer.clone();
+        thread::spawn(move || {
+            echo_ctx.process_message(Message::NewTransactionHashes(vec![ours]), &echo_peer);
+            echo_ctx.process_message(Message::Inv(vec![InvItem::Block(ours)]), &echo_peer);
+            done_tx.send(()).unwrap();
+        });
+        assert!(done_rx.recv_timeout(Duration::from_secs(5)).is_ok(), "the echo waited for the blockchain");
+        drop(guard);
+        assert!(written.try_recv().is_err());
+
+        // other hashes in the same announcement are still requested
+        ctx.process_message(Message::NewTransactionHashes(vec![ours, theirs]), &peer);
+        let request: Message = bincode::deserialize(&written.try_recv().unwrap()).unwrap();
+        assert!(matches!(request, Message::GetTransactions(hashes) if hashes == vec![theirs]));
+    }
 }
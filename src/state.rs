@@ -1,10 +1,11 @@
 use core::fmt;
-use std::collections::HashMap;
-use log::{debug, warn};
-use ring::signature::{Ed25519KeyPair, KeyPair};
+use im::HashMap as SharedMap;
+use std::collections::{BTreeMap, HashMap};
+use ring::signature::KeyPair;
+use serde::{Serialize, Serializer};
 use crate::{crypto::{address::H160, key_pair::get_deterministic_keypair}, transaction::RawTransaction};
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
 pub struct AccountInfo {
     /// represents the nonce of the next valid transaction
 	pub nonce: u32,
@@ -20,28 +21,157 @@ impl AccountInfo {
     }
 }
 
-#[derive(Clone, Debug)]
+/// Consensus rules on transactions, beyond the nonce and balance checks. All
+/// nodes must use the same rules, or they'll disagree on which blocks are
+/// valid
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ValidationRules {
+    /// The most a single transaction may transfer, if limited
+    pub max_tx_value: Option<u64>,
+    /// Whether transactions that transfer nothing are invalid. By default they
+    /// are allowed, and like any other transaction they use up a nonce
+    pub reject_zero_value: bool,
+}
+
+impl ValidationRules {
+    /// Whether the transaction transfers more than the limit, if any
+    pub fn exceeds_max_value(&self, transaction: &RawTransaction) -> bool {
+        self.max_tx_value.is_some_and(|max_tx_value| transaction.value > max_tx_value)
+    }
+
+    /// Whether the transaction transfers nothing when that isn't allowed
+    pub fn forbids_zero_value(&self, transaction: &RawTransaction) -> bool {
+        self.reject_zero_value && transaction.value == 0
+    }
+}
+
+/// Cloning a state is cheap: the account map is persistent, so a clone shares
+/// it with the original and only copies the parts that either one later
+/// changes. A block's state therefore costs memory in proportion to the
+/// accounts its transactions touched, not to the whole ledger.
+///
+/// Serializes as the ledger alone: `{"accounts": {<hex address>: {"nonce",
+/// "balance"}}}`, in address order
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
 pub struct State {
-	pub_key_to_acc_info: HashMap<H160, AccountInfo>,
+    #[serde(rename = "accounts", serialize_with = "serialize_accounts")]
+	pub_key_to_acc_info: SharedMap<H160, AccountInfo>,
+    /// While a speculative update is in progress (see `begin`), records the
+    /// previous info of every account touched, in order, so that the update
+    /// can be rolled back. `None` for accounts that didn't exist.
+    #[serde(skip)]
+    undo_log: Option<Vec<(H160, Option<AccountInfo>)>>,
+    /// The rules every transaction applied to this state must follow
+    #[serde(skip)]
+    rules: ValidationRules,
+    /// The timestamp of the block this is the state after. Transactions
+    /// that are only valid since a later time can't be applied yet
+    #[serde(skip)]
+    block_timestamp: u128,
+}
+
+/// Serialize accounts keyed by their address in hex, rather than as byte
+/// arrays, which JSON can't use as keys
+fn serialize_accounts<S: Serializer>(accounts: &SharedMap<H160, AccountInfo>, serializer: S) -> Result<S::Ok, S::Error> {
+    let accounts: BTreeMap<String, &AccountInfo> =
+        accounts.iter().map(|(addr, acc_info)| (addr.to_string(), acc_info)).collect();
+    accounts.serialize(serializer)
 }
 
 impl State {
     /// Initial coin offering; generate an initial state.
     pub fn ico() -> Self {
-        let mut pub_key_to_acc_info = HashMap::new();
         // give the i-th account 1000 * (10 - i) coins, i = 0, 1, 2, ..., 9
-        for i in 0..10 {
-            let pair = get_deterministic_keypair(i);
-            let address = H160::from_pubkey(pair.public_key().as_ref());
-            let balance: u64 = 1000 * ((10 - i) as u64);
-            let nonce: u32 = 0;
-            pub_key_to_acc_info.insert(address, AccountInfo { nonce, balance });
+        let balances: Vec<(H160, u64)> = (0..10)
+            .map(|i| {
+                let pair = get_deterministic_keypair(i);
+                let address = H160::from_pubkey(pair.public_key().as_ref());
+                (address, 1000 * ((10 - i) as u64))
+            })
+            .collect();
+        Self::with_balances(&balances)
+    }
+
+    /// An initial state where each of the specified accounts has the
+    /// specified balance. Repeated accounts get the sum of their balances
+    pub fn with_balances(balances: &[(H160, u64)]) -> Self {
+        let mut state = State {
+            pub_key_to_acc_info: SharedMap::new(),
+            undo_log: None,
+            rules: ValidationRules::default(),
+            block_timestamp: 0,
+        };
+        for (address, balance) in balances {
+            state.mint(address, *balance);
+        }
+        state
+    }
+
+    /// Set the rules transactions must follow. States derived from this one
+    /// keep the rules
+    pub fn set_rules(&mut self, rules: ValidationRules) {
+        self.rules = rules;
+    }
+
+    pub fn rules(&self) -> &ValidationRules {
+        &self.rules
+    }
+
+    /// Set the timestamp of the block this is the state after
+    pub fn set_block_timestamp(&mut self, block_timestamp: u128) {
+        self.block_timestamp = block_timestamp;
+    }
+
+    pub fn block_timestamp(&self) -> u128 {
+        self.block_timestamp
+    }
+
+    /// Whether the transaction isn't valid until a time after this state's
+    /// block
+    pub fn is_timelocked(&self, transaction: &RawTransaction) -> bool {
+        transaction.valid_since.is_some_and(|valid_since| self.block_timestamp < valid_since)
+    }
+
+    /// Start a speculative update. Changes made by `update_in_place` from now
+    /// on can be undone with `rollback` or kept with `commit`, without cloning
+    /// the whole state.
+    pub fn begin(&mut self) {
+        assert!(self.undo_log.is_none(), "speculative updates can't be nested");
+        self.undo_log = Some(Vec::new());
+    }
+
+    /// Keep the changes made since `begin`
+    pub fn commit(&mut self) {
+        self.undo_log.take().expect("no speculative update in progress");
+    }
+
+    /// Undo the changes made since `begin`
+    pub fn rollback(&mut self) {
+        let undo_log = self.undo_log.take().expect("no speculative update in progress");
+        for (addr, previous) in undo_log.into_iter().rev() {
+            match previous {
+                Some(acc_info) => self.pub_key_to_acc_info.insert(addr, acc_info),
+                None => self.pub_key_to_acc_info.remove(&addr),
+            };
+        }
+    }
+
+    /// If a speculative update is in progress, remember the current info of
+    /// the account so it can be restored
+    fn record_undo(&mut self, addr: &H160) {
+        if let Some(undo_log) = &mut self.undo_log {
+            undo_log.push((*addr, self.pub_key_to_acc_info.get(addr).cloned()));
         }
-        State { pub_key_to_acc_info }
     }
 
     pub fn check_transaction_validity(&self, transaction: &RawTransaction) -> bool {
-        let RawTransaction { from_addr, to_addr: _, nonce, value } = transaction;
+        self.check_transaction_validity_eventually(transaction) && !self.is_timelocked(transaction)
+    }
+
+    /// Like `check_transaction_validity`, but ignoring `valid_since`, so
+    /// that a transaction that is only timelocked can wait in the mempool
+    pub fn check_transaction_validity_eventually(&self, transaction: &RawTransaction) -> bool {
+        let RawTransaction { from_addr, to_addr: _, nonce, value, fee, .. } = transaction;
 
         let Some(spender_info) = self.pub_key_to_acc_info.get(from_addr) else {
             // if account doesn't exist, it has no money to spend
@@ -50,7 +180,14 @@ impl State {
         if spender_info.nonce != *nonce {
             return false;
         }
-        if spender_info.balance < *value {
+        // the sender pays the fee on top of what it sends
+        if value.checked_add(*fee).is_none_or(|cost| spender_info.balance < cost) {
+            return false;
+        }
+        if self.rules.exceeds_max_value(transaction) || self.rules.forbids_zero_value(transaction) {
+            return false;
+        }
+        if transaction.memo_too_long() {
             return false;
         }
         true
@@ -58,33 +195,59 @@ impl State {
 
     #[must_use]
 	pub fn update_in_place(&mut self, transaction: &RawTransaction) -> bool {
-        let RawTransaction { from_addr, to_addr, nonce, value } = transaction;
+        let RawTransaction { from_addr, to_addr, nonce: _, value, fee, .. } = transaction;
 
         // check for double spending
-
-        let Some(spender_info) = self.pub_key_to_acc_info.get_mut(from_addr) else {
-            // if account doesn't exist, it has no money to spend
-            return false;
-        };
-
-        if spender_info.nonce != *nonce {
-            return false;
-        }
-        if spender_info.balance < *value {
+        if !self.check_transaction_validity(transaction) {
             return false;
         }
 
         // the transaction is valid, go through with it
+        self.record_undo(from_addr);
+        self.record_undo(to_addr);
+        let spender_info = self
+            .pub_key_to_acc_info
+            .get_mut(from_addr)
+            .expect("a valid transaction's spender exists");
         spender_info.nonce += 1;
-        spender_info.balance -= value;
+        // the fee is taken out of circulation until the miner's coinbase
+        // claims it
+        spender_info.balance -= value + fee;
         let receiver_info = self
             .pub_key_to_acc_info
-            .entry(to_addr.clone())
-            .or_insert_with(AccountInfo::new);
+            .entry(*to_addr)
+            .or_default();
         receiver_info.balance += value;
         true
 	}
 
+    /// Create `value` new coins for the account, as a coinbase does
+    pub fn mint(&mut self, to_addr: &H160, value: u64) {
+        self.record_undo(to_addr);
+        let receiver_info = self.pub_key_to_acc_info.entry(*to_addr).or_default();
+        receiver_info.balance += value;
+    }
+
+    /// Checks that, for each sender, the given transactions use contiguous
+    /// nonces starting from the sender's current nonce, in the order given
+    pub fn check_nonce_contiguity<'a>(
+        &self,
+        transactions: impl Iterator<Item = &'a RawTransaction>,
+    ) -> bool {
+        let mut next_nonces: HashMap<H160, u32> = HashMap::new();
+        for transaction in transactions {
+            let next_nonce = next_nonces.entry(transaction.from_addr).or_insert_with(|| {
+                self.get_acc_info(&transaction.from_addr)
+                    .map_or(0, |acc_info| acc_info.nonce)
+            });
+            if transaction.nonce != *next_nonce {
+                return false;
+            }
+            *next_nonce += 1;
+        }
+        true
+    }
+
     /// Returns a new State representing what would happen if the given
     /// transactions acted on this State. Returns None if the transactions
     /// are invalid.
@@ -104,6 +267,16 @@ impl State {
     pub fn get_acc_info(&self, addr: &H160) -> Option<&AccountInfo> {
         self.pub_key_to_acc_info.get(addr)
     }
+
+    /// Every account with its info, in no particular order
+    pub fn accounts(&self) -> impl Iterator<Item = (&H160, &AccountInfo)> {
+        self.pub_key_to_acc_info.iter()
+    }
+
+    /// The ledger as JSON, for API responses
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).expect("a state always serializes")
+    }
 }
 
 impl fmt::Display for State {
@@ -114,3 +287,62 @@ impl fmt::Display for State {
         write!(f, "{:#?}", ledger)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rollback_restores_state() {
+        let mut state = State::ico();
+        let original = state.clone();
+        let addr = |i| H160::from_pubkey(get_deterministic_keypair(i).public_key().as_ref());
+        let new_addr: H160 = [7; 20].into();
+
+        state.begin();
+        assert!(state.update_in_place(&RawTransaction { from_addr: addr(0), to_addr: addr(1), value: 10, fee: 0, nonce: 0, memo: vec![], valid_since: None }));
+        assert!(state.update_in_place(&RawTransaction { from_addr: addr(1), to_addr: new_addr, value: 20, fee: 0, nonce: 0, memo: vec![], valid_since: None }));
+        assert!(state.update_in_place(&RawTransaction { from_addr: addr(0), to_addr: new_addr, value: 30, fee: 0, nonce: 1, memo: vec![], valid_since: None }));
+        assert_ne!(state, original);
+        state.rollback();
+        assert_eq!(state, original);
+        assert!(state.get_acc_info(&new_addr).is_none());
+
+        state.begin();
+        assert!(state.update_in_place(&RawTransaction { from_addr: addr(0), to_addr: new_addr, value: 10, fee: 0, nonce: 0, memo: vec![], valid_since: None }));
+        state.commit();
+        assert_eq!(state.get_acc_info(&new_addr).unwrap().balance, 10);
+        assert_eq!(state.get_acc_info(&addr(0)).unwrap().nonce, 1);
+    }
+
+    #[test]
+    fn derived_states_are_independent() {
+        let parent = State::ico();
+        let addr = |i| H160::from_pubkey(get_deterministic_keypair(i).public_key().as_ref());
+        let transaction = RawTransaction { from_addr: addr(0), to_addr: addr(1), value: 10, fee: 0, nonce: 0, memo: vec![], valid_since: None };
+        let child = parent.update_with_transactions(std::iter::once(&transaction)).unwrap();
+
+        // the child shares the accounts it didn't touch, and the parent sees
+        // none of its changes
+        assert_eq!(child.get_acc_info(&addr(0)).unwrap().balance, 10_000 - 10);
+        assert_eq!(parent.get_acc_info(&addr(0)).unwrap().balance, 10_000);
+        assert_eq!(parent.get_acc_info(&addr(1)).unwrap().balance, 9_000);
+        assert_eq!(child.get_acc_info(&addr(2)), parent.get_acc_info(&addr(2)));
+    }
+
+    #[test]
+    fn serialize_ledger() {
+        let mut state = State::ico();
+        state.pub_key_to_acc_info.clear();
+        state.mint(&[0xab; 20].into(), 5);
+        state.mint(&[0x01; 20].into(), 7);
+        let expected = serde_json::json!({
+            "accounts": {
+                "0101010101010101010101010101010101010101": { "nonce": 0, "balance": 7 },
+                "abababababababababababababababababababab": { "nonce": 0, "balance": 5 },
+            }
+        });
+        assert_eq!(state.to_json(), expected);
+        assert_eq!(serde_json::to_value(state.get_acc_info(&[0xab; 20].into())).unwrap(), expected["accounts"]["abababababababababababababababababababab"]);
+    }
+}
@@ -9,9 +9,27 @@ pub struct RawTransaction {
     pub from_addr: H160,
     pub to_addr: H160,
     pub value: u64,
+    /// Paid by the sender, on top of `value`, to the miner of the block that
+    /// includes the transaction
+    pub fee: u64,
     pub nonce: u32,
+    /// Arbitrary data for tagging the transaction, at most `MAX_MEMO_LEN`
+    /// bytes. It is signed like the rest of the transaction but doesn't
+    /// affect balances
+    pub memo: Vec<u8>,
+    /// If set, the transaction may only be included in a block whose parent
+    /// has a timestamp of at least this many milliseconds since the Unix
+    /// epoch. Until then it waits in the mempool
+    pub valid_since: Option<u128>,
 }
 
+/// The longest memo a valid transaction may carry, in bytes
+pub const MAX_MEMO_LEN: usize = 64;
+
+/// The most coins the coinbase of a block may create, besides the fees of the
+/// block's transactions
+pub const BLOCK_REWARD: u64 = 50;
+
 /// Create digital signature of a transaction
 pub fn sign(transaction: &RawTransaction, key: &Ed25519KeyPair) -> Signature {
     let transaction_bytes = bincode::serialize(transaction).expect("shouldn't fail");
@@ -44,9 +62,24 @@ impl RawTransaction {
             from_addr: from_addr.into(),
             to_addr: to_addr.into(),
             value,
+            fee: 0,
             nonce,
+            memo: vec![],
+            valid_since: None,
         }
     }
+
+    /// Whether the memo is longer than `MAX_MEMO_LEN`
+    pub fn memo_too_long(&self) -> bool {
+        self.memo.len() > MAX_MEMO_LEN
+    }
+
+    /// Whether this is a coinbase, which creates coins instead of
+    /// transferring them. Coinbases are sent from the all-zero address, which
+    /// no key pair has
+    pub fn is_coinbase(&self) -> bool {
+        self.from_addr == H160::default()
+    }
 }
 
 impl Hashable for RawTransaction {
@@ -56,10 +89,15 @@ impl Hashable for RawTransaction {
     }
 }
 
+/// The id of the Ed25519 signature scheme. It is the only scheme supported
+pub const SCHEME_ED25519: u8 = 0;
+
 /// A signed transaction
 #[derive(Serialize, Deserialize, Clone)]
 pub struct SignedTransaction {
     pub raw_transaction: RawTransaction,
+    /// Which signature scheme `pub_key` and `signature` belong to
+    pub scheme: u8,
     pub pub_key: Vec<u8>,
     pub signature: Vec<u8>,
 }
@@ -69,7 +107,27 @@ impl SignedTransaction {
     pub fn from_raw(raw_transaction: RawTransaction, key: &Ed25519KeyPair) -> SignedTransaction {
         let pub_key = key.public_key().as_ref().to_vec();
         let signature = sign(&raw_transaction, key).as_ref().to_vec();
-        SignedTransaction { raw_transaction, pub_key, signature }
+        SignedTransaction { raw_transaction, scheme: SCHEME_ED25519, pub_key, signature }
+    }
+
+    /// Create the coinbase of a block at the given height, which creates
+    /// `value` coins for `to_addr`. The height makes coinbases of different
+    /// blocks different. Coinbases have no sender, so they aren't signed
+    pub fn coinbase(to_addr: H160, value: u64, height: u64) -> SignedTransaction {
+        let raw_transaction = RawTransaction {
+            from_addr: H160::default(),
+            to_addr,
+            value,
+            fee: 0,
+            nonce: height as u32,
+            memo: vec![],
+            valid_since: None,
+        };
+        SignedTransaction { raw_transaction, scheme: SCHEME_ED25519, pub_key: vec![], signature: vec![] }
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.raw_transaction.is_coinbase()
     }
 
     pub fn generate_random() -> Self {
@@ -78,8 +136,12 @@ impl SignedTransaction {
         SignedTransaction::from_raw(raw_transaction, &key)
     }
 
-    /// Verify the signature of this transaction
+    /// Verify the signature of this transaction. Transactions signed with an
+    /// unknown scheme are never valid
     pub fn verify_signature(&self) -> bool {
+        if self.scheme != SCHEME_ED25519 {
+            return false;
+        }
         let serialized_raw = bincode::serialize(&self.raw_transaction).unwrap();
         let public_key = ring::signature::UnparsedPublicKey::new(
             &ring::signature::ED25519, &self.pub_key[..]
@@ -91,6 +153,72 @@ impl SignedTransaction {
     }
 }
 
+/// Verify the signatures of many transactions at once. The result at each
+/// index is the same as calling `verify_signature` on the transaction at that
+/// index. With the `batch-verify` feature, the signatures are checked with a
+/// single Ed25519 batch verification, falling back to one-at-a-time checks
+/// only if the batch fails.
+pub fn verify_batch(transactions: &[SignedTransaction]) -> Vec<bool> {
+    #[cfg(feature = "batch-verify")]
+    {
+        batch::verify_batch(transactions)
+    }
+    #[cfg(not(feature = "batch-verify"))]
+    {
+        transactions.iter().map(SignedTransaction::verify_signature).collect()
+    }
+}
+
+#[cfg(feature = "batch-verify")]
+mod batch {
+    use super::SignedTransaction;
+    use crate::crypto::address::H160;
+    use ed25519_dalek::{PublicKey, Signature};
+    use std::convert::TryFrom;
+
+    pub fn verify_batch(transactions: &[SignedTransaction]) -> Vec<bool> {
+        let mut results = vec![false; transactions.len()];
+
+        // anything that is malformed or not signed by the sender is invalid
+        // outright; everything else goes into the batch
+        let mut indices = Vec::new();
+        let mut messages = Vec::new();
+        let mut signatures = Vec::new();
+        let mut public_keys = Vec::new();
+        for (i, transaction) in transactions.iter().enumerate() {
+            if transaction.scheme != super::SCHEME_ED25519 {
+                continue;
+            }
+            if H160::from_pubkey(&transaction.pub_key[..]) != transaction.raw_transaction.from_addr {
+                continue;
+            }
+            let (Ok(public_key), Ok(signature)) = (
+                PublicKey::from_bytes(&transaction.pub_key[..]),
+                Signature::try_from(&transaction.signature[..]),
+            ) else {
+                continue;
+            };
+            indices.push(i);
+            messages.push(bincode::serialize(&transaction.raw_transaction).unwrap());
+            signatures.push(signature);
+            public_keys.push(public_key);
+        }
+
+        let messages: Vec<&[u8]> = messages.iter().map(Vec::as_slice).collect();
+        if ed25519_dalek::verify_batch(&messages, &signatures, &public_keys).is_ok() {
+            for i in indices {
+                results[i] = true;
+            }
+        } else {
+            // at least one signature is bad; find out which ones
+            for i in indices {
+                results[i] = transactions[i].verify_signature();
+            }
+        }
+        results
+    }
+}
+
 impl std::fmt::Debug for SignedTransaction {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{:?}", self.raw_transaction)
@@ -104,7 +232,7 @@ impl Hashable for SignedTransaction {
     }
 }
 
-#[cfg(any(test, test_utilities))]
+#[cfg(test)]
 mod tests {
     use super::*;
     use crate::crypto::key_pair;
@@ -114,7 +242,42 @@ mod tests {
         let t = RawTransaction::generate_random();
         let key = key_pair::random();
         let signature = sign(&t, &key);
-        assert!(verify(&t, &(key.public_key()), &signature));
+        assert!(verify(&t, key.public_key(), &signature));
+    }
+
+    #[test]
+    fn verify_batch_matches_individual() {
+        let mut transactions: Vec<_> = (0..8).map(|i| {
+            let key = key_pair::get_deterministic_keypair(i);
+            let mut raw_transaction = RawTransaction::generate_random();
+            raw_transaction.from_addr = H160::from_pubkey(key.public_key().as_ref());
+            SignedTransaction::from_raw(raw_transaction, &key)
+        }).collect();
+        // tamper with the signature of one
+        transactions[2].signature[0] ^= 0xff;
+        // sign one with a key that doesn't own the sending account
+        transactions[5] = SignedTransaction::generate_random();
+        // give one a malformed public key
+        transactions[7].pub_key.truncate(5);
+
+        let batch_results = verify_batch(&transactions);
+        let individual_results: Vec<_> = transactions.iter().map(SignedTransaction::verify_signature).collect();
+        assert_eq!(batch_results, individual_results);
+        assert_eq!(batch_results, vec![true, true, false, true, true, false, true, false]);
+    }
+
+    #[test]
+    fn signature_schemes() {
+        let key = key_pair::get_deterministic_keypair(0);
+        let mut raw_transaction = RawTransaction::generate_random();
+        raw_transaction.from_addr = H160::from_pubkey(key.public_key().as_ref());
+        let mut transaction = SignedTransaction::from_raw(raw_transaction, &key);
+        assert_eq!(transaction.scheme, SCHEME_ED25519);
+        assert!(transaction.verify_signature());
+
+        transaction.scheme = 1;
+        assert!(!transaction.verify_signature());
+        assert_eq!(verify_batch(&[transaction]), vec![false]);
     }
 
     #[cfg(feature = "my-tests")]
@@ -127,7 +290,7 @@ mod tests {
                 let t = RawTransaction::generate_random();
                 let key = key_pair::random();
                 let signature = sign(&t, &key);
-                assert!(verify(&t, &(key.public_key()), &signature));
+                assert!(verify(&t, key.public_key(), &signature));
             }
         }
     }
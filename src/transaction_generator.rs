@@ -1,98 +1,383 @@
 use log::debug;
-use serde::{Serialize,Deserialize};
-use ring::signature::{Ed25519KeyPair, Signature, KeyPair, VerificationAlgorithm, EdDSAParameters};
-use crate::crypto;
+use ring::signature::KeyPair;
 use crate::crypto::address::H160;
-use crate::crypto::hash::{H256, Hashable};
+use crate::crypto::hash::{Hashable, H256};
 
 use crate::crypto::key_pair::get_deterministic_keypair;
 use crate::network::server::Handle as ServerHandle;
 use crate::transaction::{RawTransaction, SignedTransaction};
-use std::sync::mpsc::Receiver;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
 use std::thread;
-use std::time;
+use std::time::Duration;
 use std::sync::{Arc, Mutex};
 use crate::network::message::Message;
 use crate::blockchain::{Blockchain};
+use crate::state::State;
+
+/// What kind of transactions to generate
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GenerationMode {
+    /// Random transfers between the ICO accounts, some with bad signatures
+    Normal,
+    /// Pairs of conflicting transactions from the same sender with the same
+    /// nonce but different receivers
+    DoubleSpend,
+}
+
+impl std::str::FromStr for GenerationMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "normal" => Ok(GenerationMode::Normal),
+            "doublespend" => Ok(GenerationMode::DoubleSpend),
+            _ => Err(format!("unknown mode {}", s)),
+        }
+    }
+}
+
+/// How the generator picks the account that sends each round's transactions
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SenderStrategy {
+    /// Cycle through the ICO accounts in order
+    #[default]
+    RoundRobin,
+    /// Pick any ICO account at random
+    Random,
+    /// Pick the ICO account with the highest balance at the tip
+    RichestFirst,
+}
+
+impl std::str::FromStr for SenderStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "roundrobin" => Ok(SenderStrategy::RoundRobin),
+            "random" => Ok(SenderStrategy::Random),
+            "richest" => Ok(SenderStrategy::RichestFirst),
+            _ => Err(format!("unknown sender strategy {}", s)),
+        }
+    }
+}
+
+/// A request for one round of generation
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GenerationRequest {
+    pub mode: GenerationMode,
+    /// The sender strategy to use from now on, or `None` to keep the current
+    /// one
+    pub sender_strategy: Option<SenderStrategy>,
+}
+
+/// A command to the transaction generator
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TxGenCommand {
+    /// Generate one round right away
+    Once(GenerationRequest),
+    /// Generate one round every `theta` milliseconds until stopped
+    Start { request: GenerationRequest, theta: u64 },
+    /// Stop generating rounds on a timer
+    Stop,
+}
+
+/// The number of accounts given coins in the ICO
+const NUM_ICO_ACCOUNTS: u8 = 10;
 
 pub struct TransactionGenerator {
     server: ServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
-    rx: Receiver<()>,
+    rx: Receiver<TxGenCommand>,
+    sender_strategy: SenderStrategy,
+    next_sender_acc: u8,
 }
 
 impl TransactionGenerator {
     pub fn new(
         server: &ServerHandle,
         blockchain: &Arc<Mutex<Blockchain>>,
-        rx: Receiver<()>,
+        rx: Receiver<TxGenCommand>,
     ) -> TransactionGenerator {
         TransactionGenerator {
             server: server.clone(),
             blockchain: Arc::clone(blockchain),
             rx,
+            sender_strategy: SenderStrategy::default(),
+            next_sender_acc: 0,
         }
     }
 
-    pub fn start(self) {
+    pub fn start(mut self) {
         thread::spawn(move || {
-            if self.rx.recv().is_ok() {
-                self.generation_loop();
-            }
+            self.generation_loop();
             log::warn!("Transaction Generator exited");
         });
     }
 
-    /// Generate random transactions and send them to the server
-    fn generation_loop(&self) {
-        const INTERVAL_MILLISECONDS: u64 = 700; // how quickly to generate transactions
-
-        let mut next_sender_acc = 0;
+    /// Generate transactions and send them to the server, one round for each
+    /// `Once` command, and one round every interval after a `Start`, until
+    /// the channel is closed
+    fn generation_loop(&mut self) {
+        // the mode and interval of the rounds on a timer, if started
+        let mut running: Option<(GenerationMode, Duration)> = None;
         loop {
+            let command = match running {
+                Some((mode, interval)) => match self.rx.recv_timeout(interval) {
+                    Ok(command) => command,
+                    Err(RecvTimeoutError::Timeout) => {
+                        self.generate(mode);
+                        continue;
+                    }
+                    Err(RecvTimeoutError::Disconnected) => return,
+                },
+                None => match self.rx.recv() {
+                    Ok(command) => command,
+                    Err(_) => return,
+                },
+            };
+            match command {
+                TxGenCommand::Once(request) => self.handle_request(request),
+                TxGenCommand::Start { request, theta } => {
+                    self.handle_request(request);
+                    running = Some((request.mode, Duration::from_millis(theta)));
+                }
+                TxGenCommand::Stop => running = None,
+            }
+        }
+    }
+
+    /// Switch to the requested sender strategy, if any, and do one round of
+    /// generation
+    fn handle_request(&mut self, request: GenerationRequest) {
+        if let Some(sender_strategy) = request.sender_strategy {
+            self.sender_strategy = sender_strategy;
+        }
+        self.generate(request.mode);
+    }
 
-            // sleep for some time:
-            let _ = self.rx.recv();
-            // let interval = time::Duration::from_millis(INTERVAL_MILLISECONDS);
-            // thread::sleep(interval);
+    /// Pick the account to send from, according to the sender strategy
+    fn next_sender(&mut self, state: &State) -> u8 {
+        match self.sender_strategy {
+            SenderStrategy::RoundRobin => {
+                let sender_acc = self.next_sender_acc;
+                self.next_sender_acc = (self.next_sender_acc + 1) % NUM_ICO_ACCOUNTS;
+                sender_acc
+            }
+            SenderStrategy::Random => rand::random::<u8>() % NUM_ICO_ACCOUNTS,
+            SenderStrategy::RichestFirst => (0..NUM_ICO_ACCOUNTS)
+                .rev()
+                .max_by_key(|&acc_num| {
+                    let key_pair = get_deterministic_keypair(acc_num);
+                    let addr = H160::from_pubkey(key_pair.public_key().as_ref());
+                    state.get_acc_info(&addr).map_or(0, |acc_info| acc_info.balance)
+                })
+                .expect("there are ICO accounts"),
+        }
+    }
 
-            let mut blockchain = self.blockchain.lock().expect("idk why this should work");
+    /// Do one round of generation: generate some transactions, add them to
+    /// the mempool, and broadcast them
+    fn generate(&mut self, mode: GenerationMode) {
+        let blockchain = Arc::clone(&self.blockchain);
+        let mut blockchain = blockchain.lock().expect("idk why this should work");
 
-            // 1. generate some random transactions:
-            let num_transactions = 1;
-            let transactions: Vec<_> = std::iter::from_fn(|| {
+        // 1. generate some random transactions:
+        let (_, _, latest_state) = blockchain.tip_data();
+        let sender_acc = self.next_sender(latest_state);
+        let sender_key_pair = get_deterministic_keypair(sender_acc);
+        let from_addr = H160::from_pubkey(sender_key_pair.public_key().as_ref());
+        let nonce = latest_state
+            .get_acc_info(&from_addr)
+            .expect("this account should have been in the ICO")
+            .nonce;
+        let transactions: Vec<_> = match mode {
+            GenerationMode::Normal => {
                 let receiver_acc_num = rand::random::<u8>() % 10;
-                let sender_key_pair = get_deterministic_keypair(next_sender_acc);
                 let receiver_key_pair = get_deterministic_keypair(receiver_acc_num);
-                let from_addr = H160::from_pubkey(sender_key_pair.public_key().as_ref());
                 let to_addr = H160::from_pubkey(receiver_key_pair.public_key().as_ref());
-                let (_, _, latest_state) = blockchain.tip_data();
-                let nonce = latest_state
-                    .get_acc_info(&from_addr)
-                    .expect("this account should have been in the ICO")
-                    .nonce;
-                let valid = rand::random::<u8>() % 8 != 0;
-                Some(SignedTransaction::from_raw(
+                let valid = !rand::random::<u8>().is_multiple_of(8);
+                vec![SignedTransaction::from_raw(
                     RawTransaction {
                         from_addr,
                         to_addr,
                         value: 1,
+                        fee: 0,
                         nonce,
+                        memo: vec![],
+                        valid_since: None,
                     },
                     if valid { &sender_key_pair } else { &receiver_key_pair },
-                ))
-            }).take(num_transactions).collect();
+                )]
+            }
+            GenerationMode::DoubleSpend => {
+                // two validly signed transactions that spend the same nonce
+                let first_receiver = (sender_acc + 1) % NUM_ICO_ACCOUNTS;
+                let second_receiver = (sender_acc + 2) % NUM_ICO_ACCOUNTS;
+                [first_receiver, second_receiver]
+                    .iter()
+                    .map(|&receiver_acc_num| {
+                        let receiver_key_pair = get_deterministic_keypair(receiver_acc_num);
+                        let to_addr = H160::from_pubkey(receiver_key_pair.public_key().as_ref());
+                        SignedTransaction::from_raw(
+                            RawTransaction {
+                                from_addr,
+                                to_addr,
+                                value: 1,
+                                fee: 0,
+                                nonce,
+                                memo: vec![],
+                                valid_since: None,
+                            },
+                            &sender_key_pair,
+                        )
+                    })
+                    .collect()
+            }
+        };
 
-            debug!("generated transactions {:?}", transactions);
+        debug!("generated transactions {:?}", transactions);
 
-            // 2. add these transactions to the mempool:
-            for transaction in &transactions {
-                let _ = blockchain.insert_transaction_with_validation(transaction.clone());
-            }
-            // 3. broadcast them using `self.server.broadcast(Message::NewTransactionHashes(...))`:
-            self.server.broadcast(Message::NewTransactionHashes(transactions.into_iter().map(|tx| tx.hash()).collect()));
+        // 2. add these transactions to the mempool:
+        for transaction in &transactions {
+            let _ = blockchain.insert_transaction_with_validation(transaction.clone());
+        }
+        // 3. broadcast them using `self.server.broadcast(Message::NewTransactionHashes(...))`:
+        let hashes: Vec<H256> = transactions.into_iter().map(|tx| tx.hash()).collect();
+        self.server.mark_originated(&hashes);
+        self.server.broadcast(Message::NewTransactionHashes(hashes));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::mine_valid_block;
+    use crate::network::server;
+    use crossbeam::channel;
+    use std::sync::mpsc;
+
+    #[test]
+    fn double_spend_mode() {
+        let (msg_tx, _msg_rx) = channel::unbounded();
+        let (_server_ctx, server) = server::new("127.0.0.1:0".parse().unwrap(), msg_tx, Default::default()).unwrap();
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let (_tx_gen_tx, tx_gen_rx) = mpsc::channel();
+        let mut generator = TransactionGenerator::new(&server, &blockchain, tx_gen_rx);
+
+        for _ in 0..3 {
+            generator.generate(GenerationMode::DoubleSpend);
+        }
+
+        // exactly one transaction of each conflicting pair made it in
+        let mut blockchain = blockchain.lock().unwrap();
+        let pending: Vec<SignedTransaction> = blockchain
+            .mempool_transactions()
+            .map(|(_, transaction)| transaction.clone())
+            .collect();
+        assert_eq!(pending.len(), 3);
+        let mut senders: Vec<_> = pending.iter().map(|tx| tx.raw_transaction.from_addr).collect();
+        senders.sort();
+        senders.dedup();
+        assert_eq!(senders.len(), 3);
+
+        // a block never contains both sides of a conflicting pair
+        let sender_key_pair = get_deterministic_keypair(0);
+        let from_addr = H160::from_pubkey(sender_key_pair.public_key().as_ref());
+        let conflicting_pair: Vec<_> = [1, 2]
+            .iter()
+            .map(|&receiver_acc_num| {
+                let receiver_key_pair = get_deterministic_keypair(receiver_acc_num);
+                let to_addr = H160::from_pubkey(receiver_key_pair.public_key().as_ref());
+                SignedTransaction::from_raw(
+                    RawTransaction { from_addr, to_addr, value: 1, fee: 0, nonce: 0, memo: vec![], valid_since: None },
+                    &sender_key_pair,
+                )
+            })
+            .collect();
+        assert!(conflicting_pair.iter().any(|tx| blockchain.get_transaction(&tx.hash()).is_some()));
+        let block = mine_valid_block(&blockchain, conflicting_pair);
+        assert!(blockchain.insert_block_with_validation(block).added.is_empty());
+    }
 
-            next_sender_acc += 1;
-            next_sender_acc %= 10;
+    #[test]
+    fn generate_on_a_timer() {
+        let (msg_tx, _msg_rx) = channel::unbounded();
+        let (_server_ctx, server) = server::new("127.0.0.1:0".parse().unwrap(), msg_tx, Default::default()).unwrap();
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let (tx_gen_tx, tx_gen_rx) = mpsc::channel();
+        TransactionGenerator::new(&server, &blockchain, tx_gen_rx).start();
+        let num_pending = || blockchain.lock().unwrap().mempool_transactions().count();
+
+        // each round adds one transaction from the next sender
+        let request = GenerationRequest { mode: GenerationMode::DoubleSpend, sender_strategy: None };
+        tx_gen_tx.send(TxGenCommand::Start { request, theta: 20 }).unwrap();
+        let start = std::time::Instant::now();
+        while num_pending() < 3 {
+            assert!(start.elapsed() < Duration::from_secs(5), "no transactions were generated");
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        tx_gen_tx.send(TxGenCommand::Stop).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        let stopped_at = num_pending();
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(num_pending(), stopped_at);
+        // short of every ICO account having sent one, after which rounds
+        // would add nothing anyway
+        assert!(stopped_at < NUM_ICO_ACCOUNTS as usize);
+
+        tx_gen_tx.send(TxGenCommand::Once(request)).unwrap();
+        let start = std::time::Instant::now();
+        while num_pending() == stopped_at {
+            assert!(start.elapsed() < Duration::from_secs(5), "no transaction was generated");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn new_generator(sender_strategy: SenderStrategy) -> TransactionGenerator {
+        let (msg_tx, _msg_rx) = channel::unbounded();
+        let (_server_ctx, server) = server::new("127.0.0.1:0".parse().unwrap(), msg_tx, Default::default()).unwrap();
+        let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+        let (_tx_gen_tx, tx_gen_rx) = mpsc::channel();
+        let mut generator = TransactionGenerator::new(&server, &blockchain, tx_gen_rx);
+        generator.sender_strategy = sender_strategy;
+        generator
+    }
+
+    #[test]
+    fn round_robin_senders() {
+        let mut generator = new_generator(SenderStrategy::RoundRobin);
+        let state = State::ico();
+        let senders: Vec<u8> = (0..12).map(|_| generator.next_sender(&state)).collect();
+        assert_eq!(senders, vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1]);
+    }
+
+    #[test]
+    fn random_senders() {
+        let mut generator = new_generator(SenderStrategy::Random);
+        let state = State::ico();
+        let senders: Vec<u8> = (0..100).map(|_| generator.next_sender(&state)).collect();
+        assert!(senders.iter().all(|&sender| sender < NUM_ICO_ACCOUNTS));
+        let mut distinct = senders.clone();
+        distinct.sort();
+        distinct.dedup();
+        assert!(distinct.len() > 1);
+        assert_ne!(senders[..10], [0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn richest_first_senders() {
+        let mut generator = new_generator(SenderStrategy::RichestFirst);
+        let addr = |i| H160::from_pubkey(get_deterministic_keypair(i).public_key().as_ref());
+        // account 0 has the most coins in the ICO
+        let mut state = State::ico();
+        for _ in 0..3 {
+            assert_eq!(generator.next_sender(&state), 0);
+        }
+        // until it pays enough of them to account 1
+        assert!(state.update_in_place(&RawTransaction { from_addr: addr(0), to_addr: addr(1), value: 1000, fee: 0, nonce: 0, memo: vec![], valid_since: None }));
+        for _ in 0..3 {
+            assert_eq!(generator.next_sender(&state), 1);
         }
     }
 }